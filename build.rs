@@ -13,21 +13,40 @@
 use std::env;
 use std::fs;
 use std::io::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use clap::CommandFactory;
+use clap::{Command, CommandFactory};
 use clap_complete::{generate_to, Shell};
 
+/// Prints the hosts `-H` accepts (see `commands::complete`).
+const HOSTS_COMMAND: &str = "BONK_COMPLETE=hosts bonk 2>/dev/null";
+
 // Create a cli module structure that mirrors src/cli/.
 // This allows root.rs's `super::submodule::Type` imports to resolve correctly.
+// Helper methods on the arg structs are only used by the binary.
+#[allow(dead_code)]
 #[path = "src/cli"]
 mod cli {
     #[path = "build.rs"]
     pub mod build;
+    #[path = "config.rs"]
+    pub mod config;
+    #[path = "doctor.rs"]
+    pub mod doctor;
+    #[path = "generations.rs"]
+    pub mod generations;
+    #[path = "history.rs"]
+    pub mod history;
+    #[path = "home.rs"]
+    pub mod home;
     #[path = "os.rs"]
     pub mod os;
+    #[path = "rollback.rs"]
+    pub mod rollback;
     #[path = "root.rs"]
     mod root;
+    #[path = "specialisations.rs"]
+    pub mod specialisations;
     #[path = "store.rs"]
     pub mod store;
     #[path = "try_pkg.rs"]
@@ -61,6 +80,7 @@ fn main() -> Result<(), Error> {
     for shell in shells {
         generate_to(shell, &mut cmd, "bonk", &completions_dir)?;
     }
+    add_host_completions(&cmd, &completions_dir)?;
 
     // Tell cargo to rerun this script if CLI definitions change
     println!("cargo:rerun-if-changed=src/cli/");
@@ -76,3 +96,72 @@ fn main() -> Result<(), Error> {
 
     Ok(())
 }
+
+/// Make `-H`/`--host` complete the flake's hosts by calling bonk, which the
+/// generated scripts can't do on their own.
+///
+/// Only arguments with the `host` id are touched, so e.g. `bonk build -H`
+/// (a build host) keeps completing normally.
+fn add_host_completions(cmd: &Command, dir: &Path) -> Result<(), Error> {
+    // Fish: extra `complete` lines add candidates to the existing options.
+    let mut fish = fs::read_to_string(dir.join("bonk.fish"))?;
+    for sub in cmd
+        .get_subcommands()
+        .filter(|sub| sub.get_arguments().any(|arg| arg.get_id() == "host"))
+    {
+        let names: Vec<&str> = std::iter::once(sub.get_name())
+            .chain(sub.get_all_aliases())
+            .collect();
+        fish.push_str(&format!(
+            "complete -c bonk -n \"__fish_bonk_using_subcommand {}\" -s H -l host -f -a \"({})\"\n",
+            names.join(" "),
+            HOSTS_COMMAND
+        ));
+    }
+    fs::write(dir.join("bonk.fish"), fish)?;
+
+    // Zsh: the host arguments are the only ones whose value is named HOST.
+    let zsh = fs::read_to_string(dir.join("_bonk"))?
+        .replace("]:HOST:_default'", "]:HOST:_bonk_hosts'")
+        .replacen(
+            "autoload -U is-at-least\n",
+            &format!(
+                "autoload -U is-at-least\n\n\
+                 _bonk_hosts() {{\n    \
+                 local -a hosts\n    \
+                 hosts=(${{(f)\"$({})\"}})\n    \
+                 _describe -t hosts 'host' hosts\n\
+                 }}\n",
+                HOSTS_COMMAND
+            ),
+            1,
+        );
+    fs::write(dir.join("_bonk"), zsh)?;
+
+    // Bash: replace the file completion under each `--host)` case, and under
+    // the `-H)` case that follows it.
+    let bash = fs::read_to_string(dir.join("bonk.bash"))?;
+    let mut patched = Vec::new();
+    let mut last_case = "";
+    let mut pending = false;
+    for line in bash.lines() {
+        let trimmed = line.trim();
+        if let Some(case) = trimmed.strip_suffix(')').filter(|c| c.starts_with('-')) {
+            pending = case == "--host" || (case == "-H" && last_case == "--host");
+            last_case = case;
+        }
+        if pending && trimmed.starts_with("COMPREPLY=") {
+            let indent = &line[..line.len() - line.trim_start().len()];
+            patched.push(format!(
+                "{}COMPREPLY=($(compgen -W \"$({})\" -- \"${{cur}}\"))",
+                indent, HOSTS_COMMAND
+            ));
+            pending = false;
+        } else {
+            patched.push(line.to_string());
+        }
+    }
+    fs::write(dir.join("bonk.bash"), patched.join("\n") + "\n")?;
+
+    Ok(())
+}
@@ -17,9 +17,13 @@ pub struct BuildArgs {
     pub out_link: Option<String>,
 
     /// Enable --show-trace for debugging.
-    #[arg(short, long)]
+    #[arg(short, long, overrides_with = "no_trace")]
     pub trace: bool,
 
+    /// Don't pass --show-trace, even if the config enables it.
+    #[arg(long, overrides_with = "trace")]
+    pub no_trace: bool,
+
     /// Show what would be built without building.
     #[arg(short = 'n', long)]
     pub dry_run: bool,
@@ -31,6 +35,22 @@ pub struct BuildArgs {
     /// Force local build, ignoring BONK_BUILD_HOST.
     #[arg(short, long)]
     pub local: bool,
+
+    /// Refuse to build if the flake's git checkout has untracked `.nix`
+    /// files or uncommitted changes.
+    #[arg(long)]
+    pub require_clean: bool,
+
+    /// `git add` untracked `.nix` files in the flake so they are built.
+    #[arg(long)]
+    pub add_untracked: bool,
+}
+
+impl BuildArgs {
+    /// `--trace` or `--no-trace`, whichever came last, if either was given.
+    pub fn trace(&self) -> Option<bool> {
+        (self.trace || self.no_trace).then_some(self.trace)
+    }
 }
 
 #[cfg(test)]
@@ -82,6 +102,8 @@ mod tests {
     #[test]
     fn test_trace() {
         assert!(parse(&["-t"]).trace);
+        assert_eq!(parse(&["-t", "--no-trace"]).trace(), Some(false));
+        assert_eq!(parse(&[]).trace(), None);
     }
 
     #[test]
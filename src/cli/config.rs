@@ -0,0 +1,102 @@
+//! Config command arguments.
+
+use clap::{Parser, Subcommand};
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommands {
+    /// Show effective settings and where each came from.
+    #[command(name = "show")]
+    Show(ShowArgs),
+
+    /// Print the effective value of a single setting.
+    #[command(name = "get")]
+    Get(GetArgs),
+
+    /// Write a setting to the user config file.
+    #[command(name = "set")]
+    Set(SetArgs),
+}
+
+#[derive(Parser, Debug)]
+pub struct ShowArgs {
+    /// Output as JSON.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct GetArgs {
+    /// Dotted setting name (e.g. `gc.keep`).
+    #[arg()]
+    pub key: String,
+
+    /// Output as JSON, including the source.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct SetArgs {
+    /// Dotted setting name (e.g. `gc.keep`).
+    #[arg()]
+    pub key: String,
+
+    /// Value to set. List settings take one or more values.
+    #[arg(required = true, num_args = 1.., allow_hyphen_values = true)]
+    pub values: Vec<String>,
+
+    /// Write to the project `bonk.toml` at the flake root instead.
+    #[arg(long)]
+    pub project: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    }
+
+    fn parse(args: &[&str]) -> ConfigCommands {
+        let mut full = vec!["test"];
+        full.extend(args);
+        Cli::try_parse_from(full).unwrap().command
+    }
+
+    #[test]
+    fn test_show_json() {
+        match parse(&["show", "--json"]) {
+            ConfigCommands::Show(args) => assert!(args.json),
+            _ => panic!("expected show"),
+        }
+    }
+
+    #[test]
+    fn test_get_key() {
+        match parse(&["get", "gc.keep"]) {
+            ConfigCommands::Get(args) => assert_eq!(args.key, "gc.keep"),
+            _ => panic!("expected get"),
+        }
+    }
+
+    #[test]
+    fn test_set_list_with_hyphen_values() {
+        match parse(&["set", "extra_args", "--impure", "--verbose"]) {
+            ConfigCommands::Set(args) => {
+                assert_eq!(args.key, "extra_args");
+                assert_eq!(args.values, vec!["--impure", "--verbose"]);
+                assert!(!args.project);
+            }
+            _ => panic!("expected set"),
+        }
+    }
+
+    #[test]
+    fn test_set_requires_value() {
+        assert!(Cli::try_parse_from(["test", "set", "gc.keep"]).is_err());
+    }
+}
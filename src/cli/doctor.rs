@@ -0,0 +1,51 @@
+//! Doctor command arguments.
+
+use clap::Parser;
+
+#[derive(Parser, Debug, Default)]
+pub struct DoctorArgs {
+    /// Also check that the current user may use this extra binary cache.
+    #[arg(short, long, value_name = "URL")]
+    pub substituter: Option<String>,
+
+    /// Skip slow checks (SSH reachability and flake evaluation).
+    #[arg(short, long)]
+    pub quick: bool,
+
+    /// Output as JSON.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> DoctorArgs {
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            doctor: DoctorArgs,
+        }
+        let mut full = vec!["test"];
+        full.extend(args);
+        Cli::try_parse_from(full).unwrap().doctor
+    }
+
+    #[test]
+    fn test_defaults() {
+        let args = parse(&[]);
+        assert!(args.substituter.is_none());
+        assert!(!args.quick);
+    }
+
+    #[test]
+    fn test_substituter_and_quick() {
+        let args = parse(&["-q", "-s", "https://cache.example.com"]);
+        assert!(args.quick);
+        assert_eq!(
+            args.substituter.as_deref(),
+            Some("https://cache.example.com")
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! Generations command arguments.
+
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+pub struct GenerationsArgs {
+    /// Profile to list: `system`, `home` (Home Manager), `user` (your
+    /// `nix profile`) or a path to a profile.
+    #[arg(short = 'P', long, value_name = "PROFILE", default_value = "system")]
+    pub profile: String,
+
+    /// Output as JSON.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> GenerationsArgs {
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            generations: GenerationsArgs,
+        }
+        let mut full = vec!["test"];
+        full.extend(args);
+        Cli::try_parse_from(full).unwrap().generations
+    }
+
+    #[test]
+    fn test_defaults() {
+        let args = parse(&[]);
+        assert_eq!(args.profile, "system");
+        assert!(!args.json);
+    }
+
+    #[test]
+    fn test_profile_and_json() {
+        let args = parse(&["-P", "home", "--json"]);
+        assert_eq!(args.profile, "home");
+        assert!(args.json);
+    }
+}
@@ -0,0 +1,103 @@
+//! History command arguments.
+
+use clap::{Args, Parser, Subcommand};
+
+#[derive(Parser, Debug)]
+pub struct HistoryArgs {
+    #[command(subcommand)]
+    pub command: Option<HistoryCommands>,
+
+    /// Filters for the default `list` subcommand.
+    #[command(flatten)]
+    pub list: ListArgs,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum HistoryCommands {
+    /// List past runs, newest first (the default).
+    #[command(name = "list", alias = "ls")]
+    List(ListArgs),
+
+    /// Show everything recorded for one run.
+    #[command(name = "show")]
+    Show(ShowArgs),
+}
+
+#[derive(Args, Debug, Default, Clone)]
+pub struct ListArgs {
+    /// Only runs of this subcommand (e.g. `switch`).
+    #[arg(short, long = "command", value_name = "NAME")]
+    pub command: Option<String>,
+
+    /// Only runs that deployed to this host.
+    #[arg(short = 'H', long)]
+    pub host: Option<String>,
+
+    /// Only runs that failed.
+    #[arg(long)]
+    pub failed: bool,
+
+    /// Number of runs to show.
+    #[arg(short = 'n', long, default_value_t = 20)]
+    pub limit: usize,
+
+    /// Output as JSON.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct ShowArgs {
+    /// Run id, as listed by `bonk history`.
+    #[arg()]
+    pub id: u64,
+
+    /// Output as JSON.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        history: HistoryArgs,
+    }
+
+    fn parse(args: &[&str]) -> HistoryArgs {
+        let mut full = vec!["test"];
+        full.extend(args);
+        Cli::try_parse_from(full).unwrap().history
+    }
+
+    #[test]
+    fn test_bare_history_lists() {
+        let args = parse(&["--failed", "-H", "zebes"]);
+        assert!(args.command.is_none());
+        assert!(args.list.failed);
+        assert_eq!(args.list.host.as_deref(), Some("zebes"));
+        assert_eq!(args.list.limit, 20);
+    }
+
+    #[test]
+    fn test_show_id() {
+        match parse(&["show", "42"]).command {
+            Some(HistoryCommands::Show(args)) => assert_eq!(args.id, 42),
+            _ => panic!("expected show"),
+        }
+    }
+
+    #[test]
+    fn test_list_subcommand_filters() {
+        match parse(&["list", "-c", "switch", "-n", "5"]).command {
+            Some(HistoryCommands::List(args)) => {
+                assert_eq!(args.command.as_deref(), Some("switch"));
+                assert_eq!(args.limit, 5);
+            }
+            _ => panic!("expected list"),
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Home Manager command arguments.
+
+use clap::{Parser, Subcommand};
+
+use super::os::BuildOptions;
+
+#[derive(Subcommand, Debug)]
+pub enum HomeCommands {
+    /// Build and activate your Home Manager configuration.
+    #[command(name = "switch", alias = "s")]
+    Switch(HomeArgs),
+
+    /// Build your Home Manager configuration without activating it.
+    #[command(name = "build", alias = "b")]
+    Build(HomeArgs),
+
+    /// List Home Manager generations.
+    #[command(name = "generations", alias = "gens")]
+    Generations(HomeGenerationsArgs),
+
+    /// Activate the previous (or a given) Home Manager generation.
+    #[command(name = "rollback")]
+    Rollback(HomeRollbackArgs),
+}
+
+#[derive(Parser, Debug, Default, Clone)]
+pub struct HomeArgs {
+    /// Flake reference to build, overriding `-p` (e.g. `.#alice@rune`). A
+    /// `#name` suffix selects the configuration like `-c`.
+    #[arg(value_name = "FLAKE")]
+    pub flake: Option<String>,
+
+    /// `homeConfigurations` attribute to build. Defaults to `<user>@<host>`,
+    /// or `<user>` if the flake has no configuration for this host.
+    #[arg(short, long, value_name = "NAME")]
+    pub configuration: Option<String>,
+
+    #[command(flatten)]
+    pub build: BuildOptions,
+
+    /// Move files Home Manager would overwrite aside with this extension.
+    #[arg(short = 'b', long, value_name = "EXT")]
+    pub backup_extension: Option<String>,
+}
+
+#[derive(Parser, Debug, Default)]
+pub struct HomeGenerationsArgs {
+    /// Output as JSON.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Parser, Debug, Default)]
+pub struct HomeRollbackArgs {
+    /// Generation to activate. Defaults to the one before the current
+    /// generation.
+    #[arg(value_name = "GENERATION", conflicts_with = "to")]
+    pub generation: Option<u64>,
+
+    /// Activate the newest generation created at or before this time (UTC),
+    /// e.g. `2024-06-01` (end of that day) or `"2024-06-01 14:30"`.
+    #[arg(long, value_name = "DATE")]
+    pub to: Option<String>,
+
+    /// Skip confirmation prompt.
+    #[arg(short, long)]
+    pub yes: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(subcommand)]
+        home: HomeCommands,
+    }
+
+    fn parse(args: &[&str]) -> HomeCommands {
+        let mut full = vec!["test"];
+        full.extend(args);
+        Cli::try_parse_from(full).unwrap().home
+    }
+
+    #[test]
+    fn test_switch_args() {
+        let HomeCommands::Switch(args) = parse(&["s", "-c", "alice@rune", "-B", "builder", "-t"])
+        else {
+            panic!("expected switch");
+        };
+        assert_eq!(args.configuration.as_deref(), Some("alice@rune"));
+        assert_eq!(args.build.build_host.as_deref(), Some("builder"));
+        assert!(args.build.trace);
+    }
+
+    #[test]
+    fn test_build_with_flake_ref() {
+        let HomeCommands::Build(args) = parse(&["build", ".#alice", "-b", "backup"]) else {
+            panic!("expected build");
+        };
+        assert_eq!(args.flake.as_deref(), Some(".#alice"));
+        assert_eq!(args.backup_extension.as_deref(), Some("backup"));
+    }
+
+    #[test]
+    fn test_rollback_args() {
+        let HomeCommands::Rollback(args) = parse(&["rollback", "12", "-y"]) else {
+            panic!("expected rollback");
+        };
+        assert_eq!(args.generation, Some(12));
+        assert!(args.yes);
+        assert!(Cli::try_parse_from(["test", "rollback", "12", "--to", "2024-06-01"]).is_err());
+    }
+}
@@ -6,15 +6,29 @@
 
 // All modules are public so lib.rs consumers can access types for codegen
 pub mod build;
+pub mod config;
+pub mod doctor;
+pub mod generations;
+pub mod history;
+pub mod home;
 pub mod os;
+pub mod rollback;
 pub mod root;
+pub mod specialisations;
 pub mod store;
 pub mod try_pkg;
 pub mod update;
 
 pub use build::BuildArgs;
+pub use config::ConfigCommands;
+pub use doctor::DoctorArgs;
+pub use generations::GenerationsArgs;
+pub use history::HistoryArgs;
+pub use home::{HomeArgs, HomeCommands, HomeRollbackArgs};
 pub use os::OsArgs;
-pub use root::{Cli, Commands};
+pub use rollback::RollbackArgs;
+pub use root::{Cli, Commands, Elevation, PlanFormat};
+pub use specialisations::SpecialisationsArgs;
 pub use store::StoreCommands;
 pub use try_pkg::TryArgs;
 pub use update::UpdateArgs;
@@ -1,15 +1,28 @@
-//! Shared arguments for `switch` and `boot` commands.
+//! Shared arguments for the OS commands (`switch`, `boot`, `test`,
+//! `build-system`, `dry-activate` and `build-vm`).
 
 use clap::Parser;
 
-#[derive(Parser, Debug, Default)]
+#[derive(Parser, Debug, Default, Clone)]
 pub struct OsArgs {
+    /// Flake reference to build, overriding `-p` (e.g. `.#zebes` or
+    /// `github:org/cfg/release#zebes`). A `#host` suffix selects the host
+    /// like `-H`.
+    #[arg(value_name = "FLAKE")]
+    pub flake: Option<String>,
+
     /// NixOS flake configuration to build (e.g. `zebes` selects
     /// `nixosConfigurations.zebes`). Defaults to the current hostname.
-    /// Does NOT control where the result is deployed -- use `-T` for that.
+    /// Does NOT control where the result is deployed -- use `-T` for that,
+    /// unless a `[hosts.<name>]` profile sets a `target`.
+    /// Comma-separate several hosts (`-H a,b,c`) to deploy to each of them.
     #[arg(short = 'H', long)]
     pub host: Option<String>,
 
+    /// Deploy to every host in a `[groups]` entry from config.
+    #[arg(short = 'g', long)]
+    pub group: Option<String>,
+
     /// Also deploy the built configuration to the `-H` host via SSH.
     /// Combine as `-TH <host>` to select and deploy in one shot.
     #[arg(short = 'T', long)]
@@ -20,6 +33,79 @@ pub struct OsArgs {
     #[arg(long)]
     pub target_host: Option<String>,
 
+    #[command(flatten)]
+    pub build: BuildOptions,
+
+    /// Build first, show the package and closure size changes against the
+    /// running system, and ask before activating.
+    #[arg(long, visible_alias = "ask", conflicts_with = "dry_run")]
+    pub review: bool,
+
+    /// After activating, check for failed systemd units, SSH reachability
+    /// and the `[checks]` from config; roll back if any fail (switch and
+    /// test only).
+    #[arg(long, overrides_with = "no_check")]
+    pub check: bool,
+
+    /// Skip health checks even if `checks.enabled` is set.
+    #[arg(long, overrides_with = "check")]
+    pub no_check: bool,
+
+    /// On a remote target, roll back unless the new system is confirmed
+    /// within this long (e.g. 90s, 5m). The target rolls itself back if bonk
+    /// can no longer reach it.
+    #[arg(long, value_name = "DURATION", conflicts_with = "dry_run")]
+    pub confirm_timeout: Option<String>,
+
+    /// Activate this `specialisation` of the configuration (switch, boot
+    /// and test). A local switch keeps the running specialisation by default.
+    #[arg(long, visible_alias = "specialization", value_name = "NAME")]
+    pub specialisation: Option<String>,
+
+    /// Activate the base configuration, even if a specialisation is running.
+    #[arg(
+        long,
+        visible_alias = "no-specialization",
+        conflicts_with = "specialisation"
+    )]
+    pub no_specialisation: bool,
+
+    /// Maximum hosts to deploy in parallel (multi-host only) [default: 4].
+    #[arg(short = 'j', long)]
+    pub jobs: Option<usize>,
+
+    /// Stop starting new hosts after the first failure (multi-host only).
+    /// By default every host is attempted.
+    #[arg(long)]
+    pub fail_fast: bool,
+
+    /// Deploy the first N hosts one at a time before the rest; abort if
+    /// any of them fails (multi-host only).
+    #[arg(long, value_name = "N")]
+    pub canary: Option<usize>,
+
+    /// Deploy in waves of N hosts; a wave must fully succeed before the
+    /// next one starts (multi-host only).
+    #[arg(long, value_name = "N")]
+    pub stage_size: Option<usize>,
+}
+
+impl OsArgs {
+    /// Hosts named by `-H`, split on commas.
+    pub fn hosts(&self) -> Vec<String> {
+        self.host
+            .iter()
+            .flat_map(|h| h.split(','))
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .map(String::from)
+            .collect()
+    }
+}
+
+/// How to build, shared by the OS commands and `bonk home`.
+#[derive(Parser, Debug, Default, Clone)]
+pub struct BuildOptions {
     /// Build on a remote host instead of locally.
     #[arg(short = 'B', long)]
     pub build_host: Option<String>,
@@ -29,9 +115,13 @@ pub struct OsArgs {
     pub local: bool,
 
     /// Enable --show-trace for debugging.
-    #[arg(short, long)]
+    #[arg(short, long, overrides_with = "no_trace")]
     pub trace: bool,
 
+    /// Don't pass --show-trace, even if the config enables it.
+    #[arg(long, overrides_with = "trace")]
+    pub no_trace: bool,
+
     /// Extra binary cache URL.
     #[arg(short = 's', long)]
     pub substituter: Option<String>,
@@ -43,6 +133,33 @@ pub struct OsArgs {
     /// Show what would be built without building.
     #[arg(short = 'n', long)]
     pub dry_run: bool,
+
+    /// Refuse to build if the flake's git checkout has untracked `.nix`
+    /// files or uncommitted changes.
+    #[arg(long)]
+    pub require_clean: bool,
+
+    /// `git add` untracked `.nix` files in the flake so they are built.
+    #[arg(long)]
+    pub add_untracked: bool,
+}
+
+impl BuildOptions {
+    /// `--trace` or `--no-trace`, whichever came last, if either was given.
+    pub fn trace(&self) -> Option<bool> {
+        (self.trace || self.no_trace).then_some(self.trace)
+    }
+}
+
+/// Arguments for `build-vm`.
+#[derive(Parser, Debug, Default, Clone)]
+pub struct VmArgs {
+    #[command(flatten)]
+    pub os: OsArgs,
+
+    /// Only build the VM; don't start it.
+    #[arg(long)]
+    pub no_run: bool,
 }
 
 #[cfg(test)]
@@ -64,13 +181,17 @@ mod tests {
     #[test]
     fn test_default_args() {
         let args = parse(&[]);
+        assert!(args.flake.is_none());
         assert!(args.host.is_none());
         assert!(!args.target);
         assert!(args.target_host.is_none());
-        assert!(args.build_host.is_none());
-        assert!(!args.local);
-        assert!(!args.trace);
-        assert!(!args.dry_run);
+        assert!(args.build.build_host.is_none());
+        assert!(!args.build.local);
+        assert!(!args.build.trace);
+        assert!(!args.build.dry_run);
+        assert!(args.group.is_none());
+        assert!(args.jobs.is_none());
+        assert!(!args.fail_fast);
     }
 
     #[test]
@@ -104,31 +225,95 @@ mod tests {
     #[test]
     fn test_build_host_flag() {
         let args = parse(&["-B", "buildserver"]);
-        assert_eq!(args.build_host, Some("buildserver".to_string()));
+        assert_eq!(args.build.build_host, Some("buildserver".to_string()));
     }
 
     #[test]
     fn test_local_flag() {
-        assert!(parse(&["--local"]).local);
+        assert!(parse(&["--local"]).build.local);
     }
 
     #[test]
     fn test_trace_flag() {
-        assert!(parse(&["-t"]).trace);
+        assert!(parse(&["-t"]).build.trace);
+    }
+
+    #[test]
+    fn test_trace_flags_last_wins() {
+        assert_eq!(parse(&[]).build.trace(), None);
+        assert_eq!(parse(&["-t", "--no-trace"]).build.trace(), Some(false));
+        assert_eq!(parse(&["--no-trace", "-t"]).build.trace(), Some(true));
     }
 
     #[test]
     fn test_substituter_and_key() {
         let args = parse(&["-s", "https://cache.example.com", "-k", "key:AAAA..."]);
         assert_eq!(
-            args.substituter,
+            args.build.substituter,
             Some("https://cache.example.com".to_string())
         );
-        assert_eq!(args.key, Some("key:AAAA...".to_string()));
+        assert_eq!(args.build.key, Some("key:AAAA...".to_string()));
     }
 
     #[test]
     fn test_dry_run_flag() {
-        assert!(parse(&["-n"]).dry_run);
+        assert!(parse(&["-n"]).build.dry_run);
+    }
+
+    #[test]
+    fn test_worktree_flags() {
+        let args = parse(&["--require-clean", "--add-untracked"]);
+        assert!(args.build.require_clean);
+        assert!(args.build.add_untracked);
+    }
+
+    #[test]
+    fn test_check_flags_last_wins() {
+        let args = parse(&["--check", "--no-check"]);
+        assert!(!args.check && args.no_check);
+        let args = parse(&["--no-check", "--check", "--confirm-timeout", "90s"]);
+        assert!(args.check && !args.no_check);
+        assert_eq!(args.confirm_timeout.as_deref(), Some("90s"));
+    }
+
+    #[test]
+    fn test_specialisation_flags() {
+        let args = parse(&["--specialisation", "gaming"]);
+        assert_eq!(args.specialisation.as_deref(), Some("gaming"));
+        assert_eq!(
+            parse(&["--specialization", "gaming"])
+                .specialisation
+                .as_deref(),
+            Some("gaming")
+        );
+        assert!(parse(&["--no-specialisation"]).no_specialisation);
+    }
+
+    #[test]
+    fn test_multiple_hosts() {
+        let args = parse(&["-H", "zebes, ridley,kraid"]);
+        assert_eq!(args.hosts(), vec!["zebes", "ridley", "kraid"]);
+    }
+
+    #[test]
+    fn test_group_and_jobs() {
+        let args = parse(&["--group", "servers", "-j", "8", "--fail-fast"]);
+        assert_eq!(args.group, Some("servers".to_string()));
+        assert_eq!(args.jobs, Some(8));
+        assert!(args.fail_fast);
+    }
+
+    #[test]
+    fn test_canary_and_stage_size() {
+        let args = parse(&["--canary", "1", "--stage-size", "3"]);
+        assert_eq!(args.canary, Some(1));
+        assert_eq!(args.stage_size, Some(3));
+    }
+
+    #[test]
+    fn test_positional_flake_ref() {
+        let args = parse(&[".#zebes", "-t"]);
+        assert_eq!(args.flake, Some(".#zebes".to_string()));
+        assert!(args.build.trace);
     }
 }
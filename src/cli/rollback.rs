@@ -0,0 +1,74 @@
+//! Rollback command arguments.
+
+use clap::Parser;
+
+#[derive(Parser, Debug, Default)]
+pub struct RollbackArgs {
+    /// Generation to roll back to. Defaults to the one before the current
+    /// generation.
+    #[arg(value_name = "GENERATION", conflicts_with = "to")]
+    pub generation: Option<u64>,
+
+    /// Roll back to the newest generation created at or before this time
+    /// (UTC), e.g. `2024-06-01` (end of that day) or `"2024-06-01 14:30"`.
+    #[arg(long, value_name = "DATE")]
+    pub to: Option<String>,
+
+    /// Make the generation the boot default without activating it now.
+    #[arg(short, long)]
+    pub boot: bool,
+
+    /// Host whose generations to roll back, for its `[hosts.<name>]`
+    /// profile target. Defaults to the current hostname.
+    #[arg(short = 'H', long)]
+    pub host: Option<String>,
+
+    /// Roll back the `-H` host via SSH, like `bonk switch -T`.
+    #[arg(short = 'T', long)]
+    pub target: bool,
+
+    /// Roll back a specific SSH target (e.g. `root@192.168.1.50`).
+    #[arg(long)]
+    pub target_host: Option<String>,
+
+    /// Skip confirmation prompt.
+    #[arg(short, long)]
+    pub yes: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<RollbackArgs, clap::Error> {
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            rollback: RollbackArgs,
+        }
+        let mut full = vec!["test"];
+        full.extend(args);
+        Cli::try_parse_from(full).map(|cli| cli.rollback)
+    }
+
+    #[test]
+    fn test_defaults() {
+        let args = parse(&[]).unwrap();
+        assert!(args.generation.is_none());
+        assert!(args.to.is_none());
+        assert!(!args.boot);
+    }
+
+    #[test]
+    fn test_generation_and_target() {
+        let args = parse(&["41", "--boot", "-TH", "zebes"]).unwrap();
+        assert_eq!(args.generation, Some(41));
+        assert!(args.boot && args.target);
+        assert_eq!(args.host.as_deref(), Some("zebes"));
+    }
+
+    #[test]
+    fn test_generation_conflicts_with_to() {
+        assert!(parse(&["41", "--to", "2024-06-01"]).is_err());
+    }
+}
@@ -2,12 +2,19 @@
 
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
 // Use explicit submodule paths for build.rs compatibility.
 // build.rs mirrors this structure so these paths resolve correctly there too.
 use super::build::BuildArgs;
-use super::os::OsArgs;
+use super::config::ConfigCommands;
+use super::doctor::DoctorArgs;
+use super::generations::GenerationsArgs;
+use super::history::HistoryArgs;
+use super::home::HomeCommands;
+use super::os::{OsArgs, VmArgs};
+use super::rollback::RollbackArgs;
+use super::specialisations::SpecialisationsArgs;
 use super::store::StoreCommands;
 use super::try_pkg::TryArgs;
 use super::update::UpdateArgs;
@@ -30,10 +37,117 @@ pub struct Cli {
     #[arg(short, long, global = true)]
     pub verbose: bool,
 
+    /// Print every external command bonk would run, without running any.
+    /// Use `--plan=json` for machine-readable output.
+    #[arg(
+        long,
+        alias = "explain",
+        global = true,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "text"
+    )]
+    pub plan: Option<PlanFormat>,
+
+    /// Show a progress display for nix builds and downloads instead of nix's
+    /// own output (when stderr is a terminal).
+    #[arg(long, global = true, overrides_with = "no_progress")]
+    pub progress: bool,
+
+    /// Show nix's own output, even if the config enables `progress`.
+    #[arg(long, global = true, overrides_with = "progress")]
+    pub no_progress: bool,
+
+    /// After building, report the N slowest derivations (default 10) and
+    /// the time spent building vs. downloading from caches.
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "10"
+    )]
+    pub timings: Option<usize>,
+
+    /// Retry builds and fetches that fail with a network error up to N times,
+    /// with backoff.
+    #[arg(long, global = true, value_name = "N")]
+    pub retries: Option<u32>,
+
+    /// Give up on a build or fetch after this long (e.g. 90s, 30m, 2h).
+    #[arg(long, global = true, value_name = "DURATION")]
+    pub timeout: Option<String>,
+
+    /// How to get root for privileged steps [default: auto]
+    #[arg(long, global = true, value_enum, value_name = "METHOD")]
+    pub elevation: Option<Elevation>,
+
+    /// Include the environment commands run with (inherited `NIX_*`/`NH_*`
+    /// variables and any bonk sets) in displayed commands.
+    #[arg(long, global = true)]
+    pub show_env: bool,
+
     #[command(subcommand)]
     pub command: Commands,
 }
 
+impl Cli {
+    /// The subcommand's `FLAKE` argument, which overrides `-p`, if it takes
+    /// one and it was given.
+    pub fn flake(&self) -> Option<&str> {
+        let flake = match &self.command {
+            Commands::Switch(args)
+            | Commands::Boot(args)
+            | Commands::Test(args)
+            | Commands::BuildSystem(args)
+            | Commands::DryActivate(args) => &args.flake,
+            Commands::BuildVm(args) => &args.os.flake,
+            Commands::Specialisations(args) => &args.flake,
+            Commands::Home {
+                command: HomeCommands::Switch(args) | HomeCommands::Build(args),
+            } => &args.flake,
+            _ => return None,
+        };
+        flake.as_deref()
+    }
+
+    /// `--progress` or `--no-progress`, whichever came last, if either was
+    /// given.
+    pub fn progress(&self) -> Option<bool> {
+        (self.progress || self.no_progress).then_some(self.progress)
+    }
+
+    /// Where to look for the project config: the flake the command acts on,
+    /// as given by `FLAKE` or `-p`.
+    pub fn config_flake(&self) -> Option<PathBuf> {
+        self.flake()
+            .map(PathBuf::from)
+            .or_else(|| self.flake_path.clone())
+    }
+}
+
+/// Output format for `--plan`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlanFormat {
+    Text,
+    Json,
+}
+
+/// How bonk gets root for privileged steps.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Elevation {
+    /// Use the first of sudo, doas and run0 that is installed.
+    Auto,
+    Sudo,
+    Doas,
+    Run0,
+    /// Run privileged steps without escalating.
+    None,
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     /// Build and activate NixOS configuration now.
@@ -44,6 +158,37 @@ pub enum Commands {
     #[command(name = "boot")]
     Boot(OsArgs),
 
+    /// Build and activate NixOS configuration now, without adding a boot entry.
+    #[command(name = "test")]
+    Test(OsArgs),
+
+    /// Build the NixOS system without activating it.
+    #[command(name = "build-system")]
+    BuildSystem(OsArgs),
+
+    /// Build the NixOS system and show what activating it would change.
+    #[command(name = "dry-activate")]
+    DryActivate(OsArgs),
+
+    /// Build a QEMU VM of the NixOS configuration and start it.
+    #[command(name = "build-vm")]
+    BuildVm(VmArgs),
+
+    /// Roll back to the previous (or a given) system generation.
+    #[command(name = "rollback")]
+    Rollback(RollbackArgs),
+
+    /// List the specialisations a host's configuration defines.
+    #[command(name = "specialisations", aliases = ["specs", "specializations"])]
+    Specialisations(SpecialisationsArgs),
+
+    /// Build, switch and roll back standalone Home Manager configurations.
+    #[command(name = "home")]
+    Home {
+        #[command(subcommand)]
+        command: HomeCommands,
+    },
+
     /// Build packages into the Nix store.
     #[command(name = "build", alias = "b")]
     Build(BuildArgs),
@@ -62,6 +207,25 @@ pub enum Commands {
     /// Create a temporary shell with packages.
     #[command(name = "try")]
     Try(TryArgs),
+
+    /// Show and edit bonk's configuration.
+    #[command(name = "config")]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
+    /// Check that bonk's dependencies and environment are set up.
+    #[command(name = "doctor")]
+    Doctor(DoctorArgs),
+
+    /// Show past runs and the commands they ran.
+    #[command(name = "history")]
+    History(HistoryArgs),
+
+    /// List the generations of the system or another profile.
+    #[command(name = "generations", alias = "gens")]
+    Generations(GenerationsArgs),
 }
 
 #[cfg(test)]
@@ -92,12 +256,54 @@ mod tests {
         assert_eq!(cli.flake_path, Some(PathBuf::from("/path/to/flake")));
     }
 
+    #[test]
+    fn test_config_flake_prefers_flake_argument() {
+        let cli = Cli::try_parse_from(["bonk", "-p", "/etc/nixos", "switch", "/home/me/cfg#zebes"])
+            .unwrap();
+        assert_eq!(
+            cli.config_flake(),
+            Some(PathBuf::from("/home/me/cfg#zebes"))
+        );
+
+        let cli =
+            Cli::try_parse_from(["bonk", "-p", "/etc/nixos", "home", "build", "/home/me/cfg"])
+                .unwrap();
+        assert_eq!(cli.config_flake(), Some(PathBuf::from("/home/me/cfg")));
+
+        let cli = Cli::try_parse_from(["bonk", "-p", "/etc/nixos", "update"]).unwrap();
+        assert_eq!(cli.config_flake(), Some(PathBuf::from("/etc/nixos")));
+    }
+
     #[test]
     fn test_cli_parsing_verbose() {
         let cli = Cli::try_parse_from(["bonk", "-v", "switch"]).unwrap();
         assert!(cli.verbose);
     }
 
+    #[test]
+    fn test_cli_parsing_progress() {
+        let cli = Cli::try_parse_from(["bonk", "switch"]).unwrap();
+        assert_eq!(cli.progress(), None);
+
+        let cli = Cli::try_parse_from(["bonk", "switch", "--progress", "--no-progress"]).unwrap();
+        assert_eq!(cli.progress(), Some(false));
+
+        let cli = Cli::try_parse_from(["bonk", "--no-progress", "--progress", "switch"]).unwrap();
+        assert_eq!(cli.progress(), Some(true));
+    }
+
+    #[test]
+    fn test_cli_parsing_plan() {
+        let cli = Cli::try_parse_from(["bonk", "switch", "--plan"]).unwrap();
+        assert_eq!(cli.plan, Some(PlanFormat::Text));
+
+        let cli = Cli::try_parse_from(["bonk", "--explain=json", "store", "nuke"]).unwrap();
+        assert_eq!(cli.plan, Some(PlanFormat::Json));
+
+        let cli = Cli::try_parse_from(["bonk", "switch"]).unwrap();
+        assert!(cli.plan.is_none());
+    }
+
     #[test]
     fn test_cli_parsing_store_gc() {
         let cli = Cli::try_parse_from(["bonk", "store", "gc"]).unwrap();
@@ -108,4 +314,15 @@ mod tests {
             }
         ));
     }
+
+    #[test]
+    fn test_cli_parsing_config_show() {
+        let cli = Cli::try_parse_from(["bonk", "config", "show"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Config {
+                command: ConfigCommands::Show(_)
+            }
+        ));
+    }
 }
@@ -0,0 +1,51 @@
+//! Specialisations command arguments.
+
+use clap::Parser;
+
+#[derive(Parser, Debug, Default)]
+pub struct SpecialisationsArgs {
+    /// Flake reference, overriding `-p`. A `#host` suffix selects the host
+    /// like `-H`.
+    #[arg(value_name = "FLAKE")]
+    pub flake: Option<String>,
+
+    /// NixOS flake configuration to inspect. Defaults to the current
+    /// hostname.
+    #[arg(short = 'H', long)]
+    pub host: Option<String>,
+
+    /// Output as JSON.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> SpecialisationsArgs {
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            specialisations: SpecialisationsArgs,
+        }
+        let mut full = vec!["test"];
+        full.extend(args);
+        Cli::try_parse_from(full).unwrap().specialisations
+    }
+
+    #[test]
+    fn test_defaults() {
+        let args = parse(&[]);
+        assert!(args.flake.is_none());
+        assert!(args.host.is_none());
+        assert!(!args.json);
+    }
+
+    #[test]
+    fn test_host_and_json() {
+        let args = parse(&["-H", "zebes", "--json"]);
+        assert_eq!(args.host.as_deref(), Some("zebes"));
+        assert!(args.json);
+    }
+}
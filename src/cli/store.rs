@@ -31,9 +31,9 @@ pub struct GcArgs {
     #[arg(short, long)]
     pub older_than: Option<String>,
 
-    /// Keep at least this many generations.
-    #[arg(short, long, default_value = "3")]
-    pub keep: u32,
+    /// Keep at least this many generations [default: 3].
+    #[arg(short, long)]
+    pub keep: Option<u32>,
 
     /// Show what would be deleted without deleting.
     #[arg(short = 'n', long)]
@@ -106,7 +106,7 @@ mod tests {
         match parse(&["gc"]) {
             StoreCommands::Gc(args) => {
                 assert!(args.older_than.is_none());
-                assert_eq!(args.keep, 3);
+                assert!(args.keep.is_none());
                 assert!(!args.dry_run);
             }
             _ => panic!("expected gc"),
@@ -121,6 +121,14 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_gc_with_keep() {
+        match parse(&["gc", "-k", "5"]) {
+            StoreCommands::Gc(args) => assert_eq!(args.keep, Some(5)),
+            _ => panic!("expected gc"),
+        }
+    }
+
     #[test]
     fn test_gc_alias_clean() {
         assert!(matches!(parse(&["clean"]), StoreCommands::Gc(_)));
@@ -11,6 +11,15 @@ pub struct UpdateArgs {
     /// Commit the lock file changes.
     #[arg(short, long)]
     pub commit: bool,
+
+    /// Refuse to build if the flake's git checkout has untracked `.nix`
+    /// files or uncommitted changes.
+    #[arg(long)]
+    pub require_clean: bool,
+
+    /// `git add` untracked `.nix` files in the flake so they are built.
+    #[arg(long)]
+    pub add_untracked: bool,
 }
 
 #[cfg(test)]
@@ -5,25 +5,42 @@ use std::path::Path;
 use anyhow::Result;
 
 use crate::cli::BuildArgs;
-use crate::env;
+use crate::config::Config;
 use crate::exec::CommandRunner;
-use crate::flake::resolve_flake_path;
+use crate::flake::{check_worktree, resolve_flake_path, FlakeRef};
+use crate::history;
 use crate::output;
 
 /// Execute the build command.
-pub fn run(args: &BuildArgs, flake_path: Option<&Path>) -> Result<()> {
+pub fn run(args: &BuildArgs, flake_path: Option<&Path>, config: &Config) -> Result<()> {
     let target = match &args.target {
-        Some(t) => t.clone(),
-        None => resolve_flake_path(flake_path)?,
+        Some(t) => {
+            if let Ok(flake) = FlakeRef::parse(t) {
+                check_worktree(&flake, args.require_clean, args.add_untracked)?;
+            }
+            t.clone()
+        }
+        None => {
+            let flake = resolve_flake_path(flake_path, config)?;
+            history::set_flake(&flake);
+            check_worktree(&flake, args.require_clean, args.add_untracked)?;
+            // A `#host` suffix selects a host, not a package to build.
+            flake.url
+        }
     };
 
-    // Resolve build host: --local disables, --build-host overrides, else env fallback
+    // Resolve build host: --local disables, --build-host overrides, else env/config fallback
     let build_host = if args.local {
         None
     } else {
-        args.build_host.clone().or_else(env::get_build_host)
+        args.build_host.clone().or_else(|| config.build_host())
     };
 
+    let trace = args
+        .trace()
+        .or(config.settings().build.trace)
+        .unwrap_or(false);
+
     output::info(&format!("Building: {}", target));
 
     if let Some(ref bh) = build_host {
@@ -47,10 +64,10 @@ pub fn run(args: &BuildArgs, flake_path: Option<&Path>) -> Result<()> {
         runner = runner.args(["-o", out]);
     }
 
-    runner = runner.arg_if(args.trace, "--show-trace");
+    runner = runner.arg_if(trace, "--show-trace");
     runner = runner.arg_if(args.dry_run, "--dry-run");
 
-    runner.run()?;
+    runner.nix_log().retryable().run()?;
 
     if args.dry_run {
         output::success("Dry run complete");
@@ -60,3 +77,27 @@ pub fn run(args: &BuildArgs, flake_path: Option<&Path>) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{with_executor, Recorder};
+    use clap::Parser;
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    #[test]
+    #[serial]
+    fn test_build_ignores_host_in_flake_path() {
+        std::env::remove_var("BONK_BUILD_HOST");
+        let recorder = Arc::new(Recorder::default());
+        let args = BuildArgs::parse_from(["build"]);
+
+        with_executor(recorder.clone(), || {
+            run(&args, Some(Path::new(".#zebes")), &Config::default())
+        })
+        .unwrap();
+
+        assert_eq!(recorder.command_lines(), ["nix build ."]);
+    }
+}
@@ -0,0 +1,344 @@
+//! Health checks after `switch` and `test`, and the confirm-or-roll-back
+//! deadline for remote deploys.
+//!
+//! Checks run on the activated host, over SSH for remote targets: first
+//! whether SSH still works at all, then failed systemd units, then the
+//! commands and HTTP probes from `[checks]` and the host profile. The os
+//! command rolls back to the previous generation when any of them fail.
+//!
+//! A [`Deadline`] is a rollback scheduled on the target itself with
+//! `systemd-run`, so it still happens when the new system cut bonk off.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use owo_colors::OwoColorize;
+
+use crate::config::{Config, HostProfile};
+use crate::exec::CommandRunner;
+use crate::generations::SYSTEM_PROFILE;
+use crate::output;
+
+/// How long a single check may take.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long an HTTP probe waits for a response.
+const HTTP_TIMEOUT_SECS: &str = "10";
+
+/// The checks to run on one host.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Checks {
+    failed_units: bool,
+    commands: Vec<String>,
+    http: Vec<String>,
+}
+
+impl Checks {
+    /// The `[checks]` from config, plus those of the host's profile.
+    pub fn resolve(config: &Config, profile: &HostProfile) -> Self {
+        let checks = &config.settings().checks;
+        let combine = |global: &Option<Vec<String>>, host: &Option<Vec<String>>| {
+            global.iter().chain(host).flatten().cloned().collect()
+        };
+        Self {
+            failed_units: checks.failed_units.unwrap_or(true),
+            commands: combine(&checks.commands, &profile.checks),
+            http: combine(&checks.http, &profile.http_checks),
+        }
+    }
+
+    /// The units that have failed on `target` (or this machine) before
+    /// activating, when the checks look for failed units.
+    pub fn failed_before(&self, target: Option<&str>) -> Vec<String> {
+        if !self.failed_units {
+            return Vec::new();
+        }
+        failed_units(target).unwrap_or_else(|e| {
+            tracing::info!("Could not list failed units: {:#}", e);
+            Vec::new()
+        })
+    }
+}
+
+/// Run `checks` on `target` (or this machine), printing each result, and
+/// return a description of each one that failed.
+///
+/// Units in `failed_before`, which had already failed before activating,
+/// aren't held against the new system.
+pub fn run(checks: &Checks, target: Option<&str>, failed_before: &[String]) -> Vec<String> {
+    output::info("Running health checks...");
+    let mut results = Vec::new();
+
+    if let Some(target) = target {
+        let reachable = capture(CommandRunner::ssh(target).arg("true"));
+        let unreachable = reachable.is_err();
+        results.push((format!("ssh {}", target), reachable.map(drop)));
+        if unreachable {
+            // Nothing else can be checked.
+            return report(results);
+        }
+    }
+
+    if checks.failed_units {
+        let failed = failed_units(target).and_then(|units| {
+            let units: Vec<String> = units
+                .into_iter()
+                .filter(|unit| !failed_before.contains(unit))
+                .collect();
+            if units.is_empty() {
+                Ok(())
+            } else {
+                anyhow::bail!("{}", units.join(", "))
+            }
+        });
+        results.push(("failed systemd units".to_string(), failed));
+    }
+
+    for command in &checks.commands {
+        let runner = match target {
+            None => CommandRunner::new("sh").args(["-c", command]),
+            // The remote shell runs it.
+            Some(target) => CommandRunner::ssh(target).arg(command),
+        };
+        results.push((format!("`{}`", command), capture(runner).map(drop)));
+    }
+
+    for probe in &checks.http {
+        let url = http_url(probe);
+        let curl = [
+            "curl",
+            "-fsS",
+            "-o",
+            "/dev/null",
+            "--max-time",
+            HTTP_TIMEOUT_SECS,
+            &url,
+        ];
+        results.push((url.clone(), capture(on(target, &curl)).map(drop)));
+    }
+
+    report(results)
+}
+
+/// Print check results and return the failures.
+fn report(results: Vec<(String, Result<()>)>) -> Vec<String> {
+    let mut failures = Vec::new();
+    for (name, result) in results {
+        match result {
+            Ok(()) => output::line(format_args!("  {} {}", "✓".green(), name)),
+            Err(e) => {
+                let failure = format!("{}: {:#}", name, e);
+                output::line(format_args!("  {} {}", "✗".red(), failure));
+                failures.push(failure);
+            }
+        }
+    }
+    failures
+}
+
+/// The URL probed for an `http` check: `port[/path]` on localhost, or a full
+/// URL as-is.
+fn http_url(probe: &str) -> String {
+    if probe.starts_with("http://") || probe.starts_with("https://") {
+        probe.to_string()
+    } else {
+        format!("http://localhost:{}", probe)
+    }
+}
+
+/// The systemd units that have failed on `target` (or this machine).
+fn failed_units(target: Option<&str>) -> Result<Vec<String>> {
+    let listing = capture(on(
+        target,
+        &["systemctl", "--failed", "--no-legend", "--plain"],
+    ))?;
+    Ok(listing
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_string)
+        .collect())
+}
+
+/// `command` on `target`, or on this machine.
+fn on(target: Option<&str>, command: &[&str]) -> CommandRunner {
+    match target {
+        None => CommandRunner::new(command[0]).args(command[1..].iter().copied()),
+        Some(target) => CommandRunner::ssh(target).args(command.iter().copied()),
+    }
+}
+
+fn capture(runner: CommandRunner) -> Result<String> {
+    runner
+        .show_command(false)
+        .inherit_stdio(false)
+        .timeout(CHECK_TIMEOUT)
+        .run_output()
+        .map(|(stdout, _)| stdout)
+}
+
+/// A rollback scheduled on a remote target, which happens unless cancelled
+/// in time.
+pub struct Deadline {
+    target: String,
+    unit: String,
+    armed: Instant,
+    after: Duration,
+}
+
+impl Deadline {
+    /// Schedule `target` to roll back in `after`: to generation `previous`
+    /// after a switch, or to the system profile after a test.
+    pub fn arm(target: &str, previous: Option<u64>, action: &str, after: Duration) -> Result<Self> {
+        let activate = format!("{}/bin/switch-to-configuration", SYSTEM_PROFILE);
+        let rollback = match previous {
+            Some(number) => format!(
+                "/run/current-system/sw/bin/nix-env -p {} --switch-generation {} && {} {}",
+                SYSTEM_PROFILE, number, activate, action
+            ),
+            None => format!("{} {}", activate, action),
+        };
+        let unit = format!("bonk-rollback-{}", std::process::id());
+
+        output::status(&format!(
+            "Scheduling a rollback on {} in {}s unless confirmed",
+            target,
+            after.as_secs()
+        ));
+        CommandRunner::ssh_sudo(target)
+            // ssh hands the remote shell one string, so the script is quoted
+            // for it.
+            .arg(format!(
+                "systemd-run --unit={} --on-active={} /bin/sh -c \"{}\"",
+                unit,
+                after.as_secs(),
+                rollback
+            ))
+            .run()
+            .with_context(|| format!("failed to schedule a rollback on {}", target))?;
+
+        Ok(Self {
+            target: target.to_string(),
+            unit,
+            armed: Instant::now(),
+            after,
+        })
+    }
+
+    /// Time left before the target rolls back.
+    pub fn remaining(&self) -> Duration {
+        self.after.saturating_sub(self.armed.elapsed())
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Call off the scheduled rollback.
+    pub fn cancel(self) -> Result<()> {
+        CommandRunner::ssh_sudo(&self.target)
+            .args(["systemctl", "stop", &format!("{}.timer", self.unit)])
+            .run()
+            .with_context(|| {
+                format!(
+                    "failed to cancel the scheduled rollback on {}; it will still roll back",
+                    self.target
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{with_executor, Recorder};
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_resolve_adds_host_checks() {
+        let config = Config::parse_for_test(
+            r#"
+            [checks]
+            commands = ["true"]
+            http = ["8080/health"]
+
+            [hosts.zebes]
+            checks = ["test -e /run/app"]
+            "#,
+        );
+        let checks = Checks::resolve(&config, config.host_profile("zebes").unwrap());
+        assert!(checks.failed_units);
+        assert_eq!(checks.commands, vec!["true", "test -e /run/app"]);
+        assert_eq!(checks.http, vec!["8080/health"]);
+    }
+
+    #[test]
+    fn test_http_url() {
+        assert_eq!(http_url("8080/health"), "http://localhost:8080/health");
+        assert_eq!(http_url("443"), "http://localhost:443");
+        assert_eq!(http_url("https://example.com"), "https://example.com");
+    }
+
+    #[test]
+    #[serial]
+    fn test_run_reports_failures() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.respond(
+            "systemctl --failed",
+            0,
+            "nginx.service loaded failed failed nginx\n",
+        );
+        recorder.fail("sh -c 'test -e /run/app'");
+        let checks = Checks {
+            failed_units: true,
+            commands: vec!["true".to_string(), "test -e /run/app".to_string()],
+            http: vec!["8080".to_string()],
+        };
+
+        let failures = with_executor(recorder.clone(), || run(&checks, None, &[]));
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0], "failed systemd units: nginx.service");
+        assert!(failures[1].starts_with("`test -e /run/app`"));
+        assert_eq!(
+            recorder.command_lines().last().unwrap(),
+            "curl -fsS -o /dev/null --max-time 10 http://localhost:8080"
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_unreachable_target_skips_other_checks() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.fail("ssh -o BatchMode=yes -o ConnectTimeout=10 zebes true");
+        let checks = Checks {
+            failed_units: true,
+            ..Checks::default()
+        };
+
+        let failures = with_executor(recorder.clone(), || run(&checks, Some("zebes"), &[]));
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].starts_with("ssh zebes"));
+        assert_eq!(recorder.command_lines().len(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_units_failed_before_activating_are_ignored() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.respond(
+            "systemctl --failed",
+            0,
+            "nginx.service loaded failed failed nginx\n\
+             backup.service loaded failed failed backup\n",
+        );
+        let checks = Checks {
+            failed_units: true,
+            ..Checks::default()
+        };
+
+        let failures = with_executor(recorder, || {
+            run(&checks, None, &["backup.service".to_string()])
+        });
+        assert_eq!(failures, vec!["failed systemd units: nginx.service"]);
+    }
+}
@@ -0,0 +1,68 @@
+//! Dynamic shell completion - prints the values `-H` accepts.
+//!
+//! The completion scripts generated by `build.rs` run
+//! `BONK_COMPLETE=hosts bonk`, so this must stay quiet and fast: errors
+//! print nothing, and the flake's configurations only ever come from the
+//! cache, never from evaluating the flake.
+
+use crate::config::Config;
+use crate::configurations;
+use crate::flake::resolve_flake_path;
+
+/// Print the flake's `nixosConfigurations` and the configured host profile
+/// names, one per line.
+pub fn hosts(config: &Config) {
+    let mut names: Vec<String> = resolve_flake_path(None, config)
+        .map(|flake| configurations::cached(&flake, configurations::NIXOS))
+        .unwrap_or_default();
+    names.extend(config.settings().hosts.keys().cloned());
+    names.sort();
+    names.dedup();
+
+    for name in names {
+        println!("{}", name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    /// What the scripts run to complete hosts (`HOSTS_COMMAND` in build.rs).
+    const HOSTS_COMMAND: &str = "BONK_COMPLETE=hosts bonk";
+
+    /// A completion script as generated and patched by build.rs.
+    fn script(name: &str) -> String {
+        let path = Path::new(env!("OUT_DIR")).join("completions").join(name);
+        fs::read_to_string(path).unwrap()
+    }
+
+    // build.rs patches clap's output by matching its text, so this fails if
+    // a clap_complete update changes the format and a patch stops applying.
+    #[test]
+    fn test_scripts_complete_hosts() {
+        let fish = script("bonk.fish");
+        assert!(fish.lines().any(|line| {
+            line.contains("__fish_bonk_using_subcommand switch s\"") && line.contains(HOSTS_COMMAND)
+        }));
+
+        let zsh = script("_bonk");
+        assert!(zsh.contains(&format!("hosts=(${{(f)\"$({}", HOSTS_COMMAND)));
+        assert!(zsh.contains("]:HOST:_bonk_hosts'"));
+        assert!(!zsh.contains("]:HOST:_default'"));
+
+        // Each `--host)` case, and the `-H)` case after it, completes hosts.
+        let bash = script("bonk.bash");
+        let lines: Vec<&str> = bash.lines().map(str::trim).collect();
+        let cases: Vec<usize> = (0..lines.len())
+            .filter(|&i| lines[i] == "--host)")
+            .collect();
+        assert!(!cases.is_empty());
+        for i in cases {
+            assert_eq!(lines[i + 4], "-H)");
+            assert!(lines[i + 1].contains(HOSTS_COMMAND));
+            assert!(lines[i + 5].contains(HOSTS_COMMAND));
+        }
+    }
+}
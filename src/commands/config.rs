@@ -0,0 +1,249 @@
+//! Config command - shows and edits layered settings.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use owo_colors::OwoColorize;
+use serde::Serialize;
+
+use crate::cli::config::{GetArgs, SetArgs, ShowArgs};
+use crate::cli::ConfigCommands;
+use crate::config::{self, Config, Source, KEYS, PROJECT_CONFIG_FILE};
+use crate::env;
+use crate::exec;
+use crate::flake::{locate_flake, FlakeOrigin};
+use crate::host::get_hostname;
+use crate::output;
+
+/// A resolved setting and where it came from.
+#[derive(Debug, Serialize)]
+struct Entry {
+    key: &'static str,
+    value: Option<serde_json::Value>,
+    source: Source,
+}
+
+/// Execute a config subcommand.
+///
+/// `flake_source` says whether an explicit flake path came from the `-p`
+/// flag or the `BONK_FLAKE_PATH` environment variable.
+pub fn run(
+    command: &ConfigCommands,
+    flake_path: Option<&Path>,
+    flake_source: Source,
+    config: &Config,
+) -> Result<()> {
+    for error in config.errors() {
+        output::warn(&format!("Ignoring {}", error));
+    }
+
+    let resolver = Resolver {
+        flake_path,
+        flake_source,
+        config,
+    };
+
+    match command {
+        ConfigCommands::Show(args) => show(args, &resolver),
+        ConfigCommands::Get(args) => get(args, &resolver),
+        ConfigCommands::Set(args) => set(args, &resolver),
+    }
+}
+
+fn show(args: &ShowArgs, resolver: &Resolver) -> Result<()> {
+    let entries = resolver.entries();
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+
+    output::header("Effective Configuration");
+    let width = entries.iter().map(|e| e.key.len()).max().unwrap_or(0);
+    for entry in &entries {
+        let value = entry
+            .value
+            .as_ref()
+            .map_or_else(|| "(unset)".to_string(), display_value);
+        println!(
+            "  {:<width$}  {}  {}",
+            entry.key,
+            value,
+            format!("({})", entry.source).dimmed(),
+        );
+    }
+
+    if resolver.config.layers().is_empty() {
+        println!();
+        output::status("No config files found");
+    }
+
+    Ok(())
+}
+
+fn get(args: &GetArgs, resolver: &Resolver) -> Result<()> {
+    let entry = resolver.entries().into_iter().find(|e| e.key == args.key);
+    let entry = match entry {
+        Some(entry) => entry,
+        // Not a known key: let find_key produce the error with valid names.
+        None => return config::find_key(&args.key).map(|_| ()),
+    };
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&entry)?);
+        return Ok(());
+    }
+
+    match entry.value {
+        Some(ref value) => println!("{}", display_value(value)),
+        None => anyhow::bail!("'{}' is not set", entry.key),
+    }
+
+    Ok(())
+}
+
+fn set(args: &SetArgs, resolver: &Resolver) -> Result<()> {
+    let key = config::find_key(&args.key)?;
+    let value = config::parse_value(key, &args.values)?;
+
+    let path = if args.project {
+        if key.name == "checks.commands" {
+            anyhow::bail!(
+                "checks.commands runs shell commands, so it is only read from the user config"
+            );
+        }
+        let (flake, _) = locate_flake(resolver.flake_path, resolver.config)?;
+        match flake.local_path().filter(|p| p.is_dir()) {
+            Some(root) => root.join(PROJECT_CONFIG_FILE),
+            None => anyhow::bail!("flake '{}' is not a local directory", flake),
+        }
+    } else {
+        config::user_config_path()
+            .context("could not determine user config path (set HOME or BONK_CONFIG)")?
+    };
+
+    if exec::planning() {
+        output::status(&format!(
+            "Would set {} = {} in {}",
+            key.name,
+            args.values.join(" "),
+            path.display()
+        ));
+        return Ok(());
+    }
+
+    config::write_value(&path, key, value)?;
+
+    output::success(&format!(
+        "Set {} = {} in {}",
+        key.name,
+        args.values.join(" "),
+        path.display()
+    ));
+
+    if let Some(var) = key.env.filter(|var| std::env::var_os(var).is_some()) {
+        output::warn(&format!("{} is set and overrides this value", var));
+    }
+
+    Ok(())
+}
+
+/// Resolves each setting to its effective value and source.
+struct Resolver<'a> {
+    flake_path: Option<&'a Path>,
+    flake_source: Source,
+    config: &'a Config,
+}
+
+impl Resolver<'_> {
+    fn entries(&self) -> Vec<Entry> {
+        let mut entries = vec![self.flake_path_entry(), self.host_entry()];
+        entries.extend(
+            KEYS.iter()
+                .filter(|k| k.name != "flake_path")
+                .map(|k| self.key_entry(k.name)),
+        );
+        entries
+    }
+
+    fn flake_path_entry(&self) -> Entry {
+        let key = "flake_path";
+        let Ok((path, origin)) = locate_flake(self.flake_path, self.config) else {
+            return Entry {
+                key,
+                value: None,
+                source: Source::Default,
+            };
+        };
+
+        let source = match origin {
+            FlakeOrigin::Explicit => self.flake_source.clone(),
+            FlakeOrigin::Cwd => Source::Cwd,
+            FlakeOrigin::Parent => Source::ParentDir,
+            FlakeOrigin::Env => Source::Env {
+                name: if std::env::var_os("BONK_FLAKE_PATH").is_some() {
+                    "BONK_FLAKE_PATH"
+                } else {
+                    "FLAKE"
+                },
+            },
+            FlakeOrigin::Config => self
+                .config
+                .lookup(key)
+                .map_or(Source::Default, |(_, source)| source),
+        };
+
+        Entry {
+            key,
+            value: Some(serde_json::Value::String(path.to_string())),
+            source,
+        }
+    }
+
+    fn host_entry(&self) -> Entry {
+        Entry {
+            key: "host",
+            value: get_hostname().ok().map(serde_json::Value::String),
+            source: Source::Hostname,
+        }
+    }
+
+    fn key_entry(&self, key: &'static str) -> Entry {
+        let from_env = match key {
+            "build_host" => {
+                env::get_build_host().map(|h| (toml::Value::String(h), "BONK_BUILD_HOST"))
+            }
+            "extra_args" => Some(env::get_extra_args())
+                .filter(|args| !args.is_empty())
+                .map(|args| (toml::Value::from(args), "BONK_EXTRA_ARGS")),
+            _ => None,
+        };
+
+        let (value, source) = match from_env {
+            Some((value, name)) => (Some(value), Source::Env { name }),
+            None => match self.config.lookup(key) {
+                Some((value, source)) => (Some(value), source),
+                None => (config::default_value(key), Source::Default),
+            },
+        };
+
+        Entry {
+            key,
+            value: value.and_then(|v| serde_json::to_value(v).ok()),
+            source,
+        }
+    }
+}
+
+/// Render a value for human-readable output.
+fn display_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Array(items) => items
+            .iter()
+            .map(display_value)
+            .collect::<Vec<_>>()
+            .join(", "),
+        other => other.to_string(),
+    }
+}
@@ -0,0 +1,653 @@
+//! Doctor command - checks bonk's dependencies and environment.
+//!
+//! Each check reports ok, warn, fail or skip, and failed checks come with a
+//! suggested fix. Bonk exits non-zero if any check failed.
+
+use std::ffi::CString;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Result;
+use owo_colors::OwoColorize;
+use serde::Serialize;
+
+use crate::cli::DoctorArgs;
+use crate::config::Config;
+use crate::elevate::{self, Privilege};
+use crate::exec::{self, CommandRunner};
+use crate::flake::resolve_flake_path;
+use crate::host::get_hostname;
+use crate::output;
+use crate::progress::format_bytes;
+
+const MIB: u64 = 1 << 20;
+const GIB: u64 = 1 << 30;
+
+/// Longest a single check may take (a flake evaluation can be slow, but a
+/// hung ssh connection or daemon should not stall the report).
+const CHECK_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Programs bonk runs, with how to install each.
+const PROGRAMS: [(&str, &str); 3] = [
+    (
+        "nh",
+        "Install nh, e.g. add `pkgs.nh` to environment.systemPackages",
+    ),
+    ("nix", "Install Nix: https://nixos.org/download"),
+    (
+        "nix-store",
+        "nix-store ships with Nix; make sure Nix's bin directory is on PATH",
+    ),
+];
+
+/// Experimental features bonk relies on.
+const FEATURES: [&str; 2] = ["nix-command", "flakes"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+    Skip,
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    name: String,
+    status: Status,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fix: Option<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn ok(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self::new(name, Status::Ok, detail)
+    }
+
+    fn skip(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self::new(name, Status::Skip, detail)
+    }
+
+    fn warn(name: impl Into<String>, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self::new(name, Status::Warn, detail).with_fix(fix)
+    }
+
+    fn fail(name: impl Into<String>, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self::new(name, Status::Fail, detail).with_fix(fix)
+    }
+
+    fn with_fix(mut self, fix: impl Into<String>) -> Self {
+        self.fix = Some(fix.into());
+        self
+    }
+}
+
+/// Execute the doctor command.
+///
+/// # Errors
+///
+/// Returns an error if any check failed.
+pub fn run(args: &DoctorArgs, flake_path: Option<&Path>, config: &Config) -> Result<()> {
+    let mut checks: Vec<Check> = PROGRAMS
+        .iter()
+        .map(|(program, fix)| check_program(program, fix))
+        .collect();
+    checks.push(check_elevation());
+    checks.extend(check_config(config));
+
+    let has_nix = checks
+        .iter()
+        .any(|c| c.name == "nix" && c.status == Status::Ok);
+    if has_nix {
+        checks.push(check_features());
+        checks.push(check_trusted_user(args, config));
+    }
+
+    checks.push(check_free_space(
+        "/nix",
+        10 * GIB,
+        2 * GIB,
+        "Free space with `bonk store gc` (or `bonk store nuke` as a last resort)",
+    ));
+    checks.push(check_free_space(
+        "/boot",
+        200 * MIB,
+        50 * MIB,
+        "Drop old generations with `bonk store gc -k 2`, then run `bonk boot` \
+         to rewrite the boot entries",
+    ));
+
+    if args.quick {
+        checks.push(Check::skip("ssh", "skipped (--quick)"));
+    } else {
+        checks.extend(check_ssh(config));
+    }
+
+    checks.extend(check_flake(flake_path, config, has_nix && !args.quick));
+
+    report(&checks, args.json)
+}
+
+/// Run a command quietly and return its trimmed stdout.
+fn capture(program: &str, args: &[&str]) -> Result<String> {
+    let (stdout, _) = CommandRunner::new(program)
+        .args(args.iter().copied())
+        .show_command(false)
+        .timeout(CHECK_TIMEOUT)
+        .run_output()?;
+    Ok(stdout.trim().to_string())
+}
+
+/// First line of an error, for one-line check details.
+fn first_line(error: &anyhow::Error) -> String {
+    format!("{:#}", error)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn check_elevation() -> Check {
+    let name = "elevation";
+    match elevate::current() {
+        Privilege::Root => Check::ok(name, "running as root"),
+        Privilege::Off => Check::warn(
+            name,
+            "turned off (elevation = none)",
+            "Privileged steps such as `bonk store nuke` will fail unless bonk runs as root",
+        ),
+        Privilege::Program(program) if exec::program_exists(program) => Check::ok(name, program),
+        Privilege::Program(program) => Check::fail(
+            name,
+            format!("{} not found on PATH", program),
+            "Install sudo, doas or run0, or pick one with `bonk config set elevation <method>`",
+        ),
+    }
+}
+
+/// Config files that failed to load, or the ones that did.
+fn check_config(config: &Config) -> Vec<Check> {
+    let name = "config";
+    if !config.errors().is_empty() {
+        return config
+            .errors()
+            .iter()
+            .map(|error| {
+                Check::fail(
+                    name,
+                    error.to_string(),
+                    format!(
+                        "Fix or remove the setting in {} (other commands refuse to run until then)",
+                        error.path.display()
+                    ),
+                )
+            })
+            .collect();
+    }
+
+    let files: Vec<String> = config
+        .layers()
+        .iter()
+        .map(|layer| layer.path.display().to_string())
+        .collect();
+    if files.is_empty() {
+        vec![Check::ok(name, "no config files (using defaults)")]
+    } else {
+        vec![Check::ok(name, files.join(", "))]
+    }
+}
+
+fn check_program(program: &str, fix: &str) -> Check {
+    if !exec::program_exists(program) {
+        return Check::fail(program, "not found on PATH", fix);
+    }
+
+    match capture(program, &["--version"]) {
+        Ok(version) => Check::ok(program, version.lines().next().unwrap_or_default()),
+        Err(e) => Check::warn(
+            program,
+            format!(
+                "found, but `{} --version` failed: {}",
+                program,
+                first_line(&e)
+            ),
+            fix,
+        ),
+    }
+}
+
+/// Read a nix setting, falling back to `nix show-config` on Nix older than 2.20.
+fn nix_setting(key: &str) -> Result<String> {
+    capture("nix", &["config", "show", key]).or_else(|_| {
+        let all = capture("nix", &["show-config"])?;
+        Ok(setting_value(&all, key).unwrap_or_default().to_string())
+    })
+}
+
+/// Find `key = value` in `nix show-config` output.
+fn setting_value<'a>(config: &'a str, key: &str) -> Option<&'a str> {
+    config.lines().find_map(|line| {
+        let (k, v) = line.split_once('=')?;
+        (k.trim() == key).then(|| v.trim())
+    })
+}
+
+fn check_features() -> Check {
+    let name = "experimental features";
+    let fix = "Add `experimental-features = nix-command flakes` to /etc/nix/nix.conf \
+               (on NixOS: nix.settings.experimental-features = [ \"nix-command\" \"flakes\" ];)";
+
+    match nix_setting("experimental-features") {
+        Ok(enabled) => {
+            let missing = missing_features(&enabled);
+            if missing.is_empty() {
+                Check::ok(name, enabled)
+            } else {
+                Check::fail(name, format!("not enabled: {}", missing.join(", ")), fix)
+            }
+        }
+        Err(e) => Check::fail(
+            name,
+            format!("could not read the nix configuration: {}", first_line(&e)),
+            fix,
+        ),
+    }
+}
+
+/// Required features missing from a space-separated list of enabled ones.
+fn missing_features(enabled: &str) -> Vec<&'static str> {
+    FEATURES
+        .into_iter()
+        .filter(|f| !enabled.split_whitespace().any(|e| e == *f))
+        .collect()
+}
+
+/// Extra substituters are ignored by the daemon unless the user is trusted.
+fn check_trusted_user(args: &DoctorArgs, config: &Config) -> Check {
+    let name = "trusted user";
+    let settings = config.settings();
+    let caches: Vec<&String> = args
+        .substituter
+        .iter()
+        .chain(settings.os.substituters.iter().flatten())
+        .chain(
+            settings
+                .hosts
+                .values()
+                .flat_map(|p| p.substituters.iter().flatten()),
+        )
+        .collect();
+
+    let Some(cache) = caches.first() else {
+        return Check::skip(name, "no extra substituters in use");
+    };
+
+    let user = match std::env::var("USER")
+        .ok()
+        .filter(|u| !u.is_empty())
+        .map_or_else(|| capture("id", &["-un"]), Ok)
+    {
+        Ok(user) => user,
+        Err(e) => {
+            return Check::warn(
+                name,
+                format!("could not determine the current user: {}", first_line(&e)),
+                "Set USER",
+            )
+        }
+    };
+    if user == "root" {
+        return Check::ok(name, "running as root");
+    }
+
+    let trusted = match nix_setting("trusted-users") {
+        Ok(trusted) => trusted,
+        Err(e) => {
+            return Check::warn(
+                name,
+                format!("could not read trusted-users: {}", first_line(&e)),
+                "Check that `nix config show trusted-users` works",
+            )
+        }
+    };
+    let groups = capture("id", &["-Gn"]).unwrap_or_default();
+    let groups: Vec<&str> = groups.split_whitespace().collect();
+
+    if is_trusted(&user, &groups, &trusted) {
+        Check::ok(name, format!("{} is in trusted-users", user))
+    } else {
+        Check::fail(
+            name,
+            format!(
+                "{} is not in trusted-users ({}), so extra substituters such as {} are ignored",
+                user, trusted, cache
+            ),
+            format!(
+                "Add `trusted-users = root {0}` to /etc/nix/nix.conf \
+                 (on NixOS: nix.settings.trusted-users = [ \"root\" \"{0}\" ];)",
+                user
+            ),
+        )
+    }
+}
+
+/// Whether `user` (a member of `groups`) matches a `trusted-users` entry.
+fn is_trusted(user: &str, groups: &[&str], trusted: &str) -> bool {
+    trusted.split_whitespace().any(|entry| {
+        entry == "*"
+            || entry == user
+            || entry
+                .strip_prefix('@')
+                .is_some_and(|group| groups.contains(&group))
+    })
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+fn free_space(path: &Path) -> Option<u64> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    // SAFETY: `path` is NUL-terminated and `stat` is only read on success.
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stat = unsafe { stat.assume_init() };
+
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+fn space_status(free: u64, warn_below: u64, fail_below: u64) -> Status {
+    if free < fail_below {
+        Status::Fail
+    } else if free < warn_below {
+        Status::Warn
+    } else {
+        Status::Ok
+    }
+}
+
+fn check_free_space(path: &str, warn_below: u64, fail_below: u64, fix: &str) -> Check {
+    let name = format!("free space on {}", path);
+
+    if !Path::new(path).exists() {
+        return Check::skip(name, "does not exist");
+    }
+    let Some(free) = free_space(Path::new(path)) else {
+        return Check::skip(name, "could not read filesystem usage");
+    };
+
+    let detail = format!("{} free", format_bytes(free as f64));
+    match space_status(free, warn_below, fail_below) {
+        Status::Ok => Check::ok(name, detail),
+        status => Check::new(name, status, detail).with_fix(fix),
+    }
+}
+
+/// Build and target hosts bonk may connect to, with their role.
+fn ssh_hosts(config: &Config) -> Vec<(String, &'static str)> {
+    let mut hosts: Vec<(String, &'static str)> = Vec::new();
+    let mut add = |host: &Option<String>, role| {
+        if let Some(host) = host {
+            if !hosts.iter().any(|(h, _)| h == host) {
+                hosts.push((host.clone(), role));
+            }
+        }
+    };
+
+    add(&config.build_host(), "build host");
+    for profile in config.settings().hosts.values() {
+        add(&profile.build_host, "build host");
+        add(&profile.target, "target host");
+    }
+
+    hosts
+}
+
+fn check_ssh(config: &Config) -> Vec<Check> {
+    let hosts = ssh_hosts(config);
+    if hosts.is_empty() {
+        return vec![Check::skip("ssh", "no build or target hosts configured")];
+    }
+
+    hosts
+        .into_iter()
+        .map(|(host, role)| {
+            let name = format!("ssh {}", host);
+            match capture(
+                "ssh",
+                &[
+                    "-o",
+                    "BatchMode=yes",
+                    "-o",
+                    "ConnectTimeout=5",
+                    &host,
+                    "true",
+                ],
+            ) {
+                Ok(_) => Check::ok(name, format!("reachable ({})", role)),
+                Err(e) => Check::fail(
+                    name,
+                    format!("unreachable ({}): {}", role, first_line(&e)),
+                    format!(
+                        "Check that `ssh {0}` connects without a password prompt \
+                         (e.g. add your key with `ssh-copy-id {0}`)",
+                        host
+                    ),
+                ),
+            }
+        })
+        .collect()
+}
+
+fn check_flake(flake_path: Option<&Path>, config: &Config, evaluate: bool) -> Vec<Check> {
+    let flake = match resolve_flake_path(flake_path, config) {
+        Ok(flake) => flake,
+        Err(e) => {
+            return vec![Check::fail(
+                "flake",
+                first_line(&e),
+                "Run bonk inside your config repository, pass -p <path>, \
+                 or set it with `bonk config set flake_path <path>`",
+            )]
+        }
+    };
+
+    let mut checks = vec![Check::ok("flake", flake.to_string())];
+    if !evaluate {
+        return checks;
+    }
+
+    let name = "flake evaluation";
+    let attr = format!("{}#nixosConfigurations", flake.url);
+    let names = capture(
+        "nix",
+        &["eval", "--json", &attr, "--apply", "builtins.attrNames"],
+    )
+    .and_then(|json| Ok(serde_json::from_str::<Vec<String>>(&json)?));
+
+    checks.push(match names {
+        Ok(names) => check_configurations(&names, config),
+        Err(e) => Check::fail(
+            name,
+            first_line(&e),
+            format!("Run `nix flake check {}` to see the full error", flake.url),
+        ),
+    });
+    checks
+}
+
+/// Check that the flake has a configuration for this machine.
+fn check_configurations(names: &[String], config: &Config) -> Check {
+    let name = "flake evaluation";
+    let detail = format!("{} nixosConfigurations: {}", names.len(), names.join(", "));
+
+    let Ok(hostname) = get_hostname() else {
+        return Check::ok(name, detail);
+    };
+    let wanted = config.configuration(&hostname);
+
+    if names.contains(&wanted) {
+        Check::ok(name, detail)
+    } else {
+        Check::warn(
+            name,
+            format!("{}; none named '{}'", detail, wanted),
+            format!(
+                "Pass -H <name> when rebuilding, or map this machine with \
+                 `configuration = \"<name>\"` under [hosts.{}]",
+                hostname
+            ),
+        )
+    }
+}
+
+fn report(checks: &[Check], json: bool) -> Result<()> {
+    let failed = checks.iter().filter(|c| c.status == Status::Fail).count();
+    let warned = checks.iter().filter(|c| c.status == Status::Warn).count();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(checks)?);
+    } else {
+        output::header("Bonk Doctor");
+        let width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+        for check in checks {
+            let symbol = match check.status {
+                Status::Ok => "✓".green().to_string(),
+                Status::Warn => "!".yellow().to_string(),
+                Status::Fail => "✗".red().to_string(),
+                Status::Skip => "-".dimmed().to_string(),
+            };
+            println!("  {} {:<width$}  {}", symbol, check.name, check.detail);
+            if let Some(ref fix) = check.fix {
+                println!("    {:<width$}  {} {}", "", "fix:".cyan(), fix);
+            }
+        }
+        println!();
+
+        if failed == 0 && warned == 0 {
+            output::success("Everything looks good");
+        } else if failed == 0 {
+            output::warn(&format!("{} warning(s)", warned));
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} of {} checks failed", failed, checks.len());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{with_executor, Recorder};
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    #[test]
+    #[serial]
+    fn test_config_errors_fail() {
+        assert_eq!(check_config(&Config::default())[0].status, Status::Ok);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "bulid_host = \"x\"\n").unwrap();
+        std::env::set_var("BONK_CONFIG", &path);
+        let config = Config::load_lenient(Some(dir.path()));
+        std::env::remove_var("BONK_CONFIG");
+
+        let checks = check_config(&config);
+        assert_eq!(checks[0].status, Status::Fail);
+        assert!(checks[0].detail.contains("unknown field `bulid_host`"));
+    }
+
+    #[test]
+    fn test_missing_features() {
+        assert!(missing_features("flakes nix-command ca-derivations").is_empty());
+        assert_eq!(missing_features("nix-command"), vec!["flakes"]);
+        assert_eq!(missing_features(""), vec!["nix-command", "flakes"]);
+    }
+
+    #[test]
+    fn test_setting_value() {
+        let config = "sandbox = true\ntrusted-users = root @wheel\n";
+        assert_eq!(setting_value(config, "trusted-users"), Some("root @wheel"));
+        assert_eq!(setting_value(config, "substituters"), None);
+    }
+
+    #[test]
+    fn test_is_trusted() {
+        assert!(is_trusted("toph", &[], "root toph"));
+        assert!(is_trusted("toph", &["users", "wheel"], "root @wheel"));
+        assert!(is_trusted("toph", &[], "*"));
+        assert!(!is_trusted("toph", &["users"], "root @wheel"));
+    }
+
+    #[test]
+    fn test_space_status() {
+        assert_eq!(space_status(20 * GIB, 10 * GIB, 2 * GIB), Status::Ok);
+        assert_eq!(space_status(5 * GIB, 10 * GIB, 2 * GIB), Status::Warn);
+        assert_eq!(space_status(GIB, 10 * GIB, 2 * GIB), Status::Fail);
+    }
+
+    #[test]
+    #[serial]
+    fn test_features_disabled() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.respond("nix config show experimental-features", 0, "nix-command\n");
+
+        let check = with_executor(recorder, check_features);
+
+        assert_eq!(check.status, Status::Fail);
+        assert_eq!(check.detail, "not enabled: flakes");
+        assert!(check.fix.is_some());
+    }
+
+    #[test]
+    #[serial]
+    fn test_features_fall_back_to_show_config() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.fail("nix config show").respond(
+            "nix show-config",
+            0,
+            "experimental-features = flakes nix-command\n",
+        );
+
+        let check = with_executor(recorder, check_features);
+
+        assert_eq!(check.status, Status::Ok);
+    }
+
+    #[test]
+    #[serial]
+    fn test_flake_evaluation_failure_suggests_check() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.fail("nix eval");
+
+        let checks = with_executor(recorder, || {
+            check_flake(Some(Path::new("/etc/nixos")), &Config::default(), true)
+        });
+
+        assert_eq!(checks[0].status, Status::Ok);
+        assert_eq!(checks[1].status, Status::Fail);
+        assert_eq!(
+            checks[1].fix.as_deref(),
+            Some("Run `nix flake check /etc/nixos` to see the full error")
+        );
+    }
+}
@@ -0,0 +1,348 @@
+//! Multi-host deploys - runs an os rebuild against many hosts in parallel.
+//!
+//! Selected with `-H a,b,c` or `--group <name>`. Every host is deployed over
+//! SSH (as if `-T` were given), at most `--jobs` at a time, with each line of
+//! output tagged with the host name. `--canary` and `--stage-size` split the
+//! rollout into stages; a stage with a failure stops the stages after it.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use owo_colors::OwoColorize;
+
+use crate::cli::OsArgs;
+use crate::commands::os::{self, OsAction};
+use crate::config::Config;
+use crate::output;
+use crate::progress::format_ms;
+
+/// Hosts deployed in parallel when `--jobs` is not given.
+const DEFAULT_JOBS: usize = 4;
+
+/// A group of hosts deployed together.
+#[derive(Debug, PartialEq)]
+struct Stage {
+    hosts: Vec<String>,
+    /// Deploy one host at a time (canary stage).
+    sequential: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Outcome {
+    Succeeded,
+    Failed(String),
+    Skipped,
+}
+
+#[derive(Debug)]
+struct HostResult {
+    host: String,
+    outcome: Outcome,
+    duration: Option<Duration>,
+}
+
+impl HostResult {
+    fn skipped(host: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            outcome: Outcome::Skipped,
+            duration: None,
+        }
+    }
+}
+
+/// Deploy `action` to every host in `hosts`.
+///
+/// # Errors
+///
+/// Returns an error if the arguments can't be applied to several hosts, or
+/// if any host failed to deploy.
+pub fn run(
+    action: OsAction,
+    args: &OsArgs,
+    hosts: &[String],
+    flake: &str,
+    config: &Config,
+) -> Result<()> {
+    if hosts.is_empty() {
+        anyhow::bail!("no hosts to deploy to");
+    }
+    if args.review {
+        anyhow::bail!("--review works with one host at a time");
+    }
+    if args.confirm_timeout.is_some() {
+        anyhow::bail!("--confirm-timeout works with one host at a time");
+    }
+    if args.target_host.is_some() {
+        anyhow::bail!(
+            "--target-host cannot be used with multiple hosts; \
+             set `target` in each [hosts.<name>] profile instead"
+        );
+    }
+
+    let jobs = args.jobs.unwrap_or(DEFAULT_JOBS).max(1);
+    let stages = plan_stages(hosts, args.canary, args.stage_size);
+
+    output::info(&format!(
+        "Deploying to {} hosts ({}, {} at a time): {}",
+        hosts.len(),
+        action.as_str(),
+        jobs,
+        hosts.join(", ")
+    ));
+
+    // Every host is deployed remotely, so -T is implied (for actions that
+    // deploy at all).
+    let host_args = OsArgs {
+        host: None,
+        group: None,
+        target: action.deploys(),
+        ..args.clone()
+    };
+    let width = hosts.iter().map(String::len).max().unwrap_or(0);
+
+    let mut results = Vec::new();
+    let mut aborted = false;
+
+    for (index, stage) in stages.iter().enumerate() {
+        if aborted {
+            results.extend(stage.hosts.iter().map(|h| HostResult::skipped(h)));
+            continue;
+        }
+
+        if stages.len() > 1 {
+            output::header(&format!("Stage {}/{}", index + 1, stages.len()));
+        }
+
+        let stage_jobs = if stage.sequential { 1 } else { jobs };
+        let stage_results = run_stage(
+            action,
+            &host_args,
+            &stage.hosts,
+            stage_jobs,
+            args.fail_fast,
+            flake,
+            config,
+            width,
+        );
+
+        let failed = stage_results
+            .iter()
+            .any(|r| matches!(r.outcome, Outcome::Failed(_)));
+        results.extend(stage_results);
+
+        if failed && (args.fail_fast || index + 1 < stages.len()) {
+            output::warn("Stopping rollout after failure");
+            aborted = true;
+        }
+    }
+
+    print_summary(&results);
+
+    let failed = results
+        .iter()
+        .filter(|r| matches!(r.outcome, Outcome::Failed(_)))
+        .count();
+    if failed > 0 {
+        anyhow::bail!("{} of {} hosts failed", failed, results.len());
+    }
+
+    let skipped = results
+        .iter()
+        .filter(|r| r.outcome == Outcome::Skipped)
+        .count();
+    if skipped == 0 {
+        output::success(&format!(
+            "All {} hosts deployed ({})",
+            hosts.len(),
+            action.as_str()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Split hosts into stages: an optional sequential canary stage, then the
+/// rest either all at once or in waves of `stage_size`.
+fn plan_stages(hosts: &[String], canary: Option<usize>, stage_size: Option<usize>) -> Vec<Stage> {
+    let mut stages = Vec::new();
+    let canary = canary.unwrap_or(0).min(hosts.len());
+
+    if canary > 0 {
+        stages.push(Stage {
+            hosts: hosts[..canary].to_vec(),
+            sequential: true,
+        });
+    }
+
+    let rest = &hosts[canary..];
+    let chunk = stage_size.filter(|&n| n > 0).unwrap_or(rest.len().max(1));
+    stages.extend(rest.chunks(chunk).map(|c| Stage {
+        hosts: c.to_vec(),
+        sequential: false,
+    }));
+
+    stages
+}
+
+/// Deploy one stage, at most `jobs` hosts at a time.
+///
+/// Results are returned in the same order as `hosts`.
+#[allow(clippy::too_many_arguments)]
+fn run_stage(
+    action: OsAction,
+    args: &OsArgs,
+    hosts: &[String],
+    jobs: usize,
+    fail_fast: bool,
+    flake: &str,
+    config: &Config,
+    width: usize,
+) -> Vec<HostResult> {
+    let queue = Mutex::new(hosts.iter().collect::<VecDeque<_>>());
+    let results = Mutex::new(Vec::with_capacity(hosts.len()));
+    let stop = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(hosts.len()) {
+            scope.spawn(|| loop {
+                let Some(host) = queue.lock().unwrap().pop_front() else {
+                    break;
+                };
+
+                if stop.load(Ordering::SeqCst) {
+                    results.lock().unwrap().push(HostResult::skipped(host));
+                    continue;
+                }
+
+                output::set_prefix(Some(format!("[{:<width$}]", host)));
+                let start = Instant::now();
+                let outcome = match os::rebuild(action, args, Some(host), flake, config) {
+                    Ok(()) => Outcome::Succeeded,
+                    Err(e) => {
+                        output::warn(&format!("Failed: {:#}", e));
+                        if fail_fast {
+                            stop.store(true, Ordering::SeqCst);
+                        }
+                        Outcome::Failed(format!("{:#}", e))
+                    }
+                };
+                output::set_prefix(None);
+
+                results.lock().unwrap().push(HostResult {
+                    host: host.clone(),
+                    outcome,
+                    duration: Some(start.elapsed()),
+                });
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|r| hosts.iter().position(|h| *h == r.host));
+    results
+}
+
+fn print_summary(results: &[HostResult]) {
+    output::header("Deploy Summary");
+
+    let width = results
+        .iter()
+        .map(|r| r.host.len())
+        .chain(std::iter::once("HOST".len()))
+        .max()
+        .unwrap_or(0);
+
+    println!(
+        "  {:<width$}  {:<9}  {:>7}",
+        "HOST".dimmed(),
+        "STATUS".dimmed(),
+        "TIME".dimmed()
+    );
+
+    for result in results {
+        let time = result
+            .duration
+            .map(|d| format_ms(d.as_millis() as u64))
+            .unwrap_or_default();
+        let (status, detail) = match &result.outcome {
+            Outcome::Succeeded => (format!("{:<9}", "ok").green().to_string(), ""),
+            Outcome::Failed(e) => (format!("{:<9}", "failed").red().to_string(), e.as_str()),
+            Outcome::Skipped => (format!("{:<9}", "skipped").yellow().to_string(), ""),
+        };
+        let detail = detail.lines().next().unwrap_or_default();
+        if detail.is_empty() {
+            println!("  {:<width$}  {}  {:>7}", result.host, status, time);
+        } else {
+            println!(
+                "  {:<width$}  {}  {:>7}  {}",
+                result.host,
+                status,
+                time,
+                detail.dimmed()
+            );
+        }
+    }
+
+    let count = |pred: fn(&Outcome) -> bool| results.iter().filter(|r| pred(&r.outcome)).count();
+    println!();
+    output::status(&format!(
+        "{} succeeded, {} failed, {} skipped",
+        count(|o| *o == Outcome::Succeeded),
+        count(|o| matches!(o, Outcome::Failed(_))),
+        count(|o| *o == Outcome::Skipped),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_plan_stages_single_stage() {
+        let stages = plan_stages(&hosts(&["a", "b", "c"]), None, None);
+        assert_eq!(stages.len(), 1);
+        assert_eq!(stages[0].hosts, hosts(&["a", "b", "c"]));
+        assert!(!stages[0].sequential);
+    }
+
+    #[test]
+    fn test_plan_stages_canary() {
+        let stages = plan_stages(&hosts(&["a", "b", "c"]), Some(1), None);
+        assert_eq!(
+            stages,
+            vec![
+                Stage {
+                    hosts: hosts(&["a"]),
+                    sequential: true
+                },
+                Stage {
+                    hosts: hosts(&["b", "c"]),
+                    sequential: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_stages_waves() {
+        let stages = plan_stages(&hosts(&["a", "b", "c", "d", "e"]), Some(1), Some(2));
+        let sizes: Vec<usize> = stages.iter().map(|s| s.hosts.len()).collect();
+        assert_eq!(sizes, vec![1, 2, 2]);
+    }
+
+    #[test]
+    fn test_plan_stages_canary_larger_than_hosts() {
+        let stages = plan_stages(&hosts(&["a", "b"]), Some(5), None);
+        assert_eq!(stages.len(), 1);
+        assert!(stages[0].sequential);
+    }
+}
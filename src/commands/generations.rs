@@ -0,0 +1,144 @@
+//! Generations command - lists a profile's generations with their metadata.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use owo_colors::OwoColorize;
+
+use crate::cli::GenerationsArgs;
+use crate::exec::CommandRunner;
+use crate::generations::{self, Generation};
+use crate::history::format_timestamp;
+use crate::output;
+use crate::progress::format_bytes;
+
+/// Execute the generations command.
+pub fn run(args: &GenerationsArgs) -> Result<()> {
+    let profile = generations::profile_path(&args.profile)?;
+    let mut gens = generations::list(&profile)?;
+    if gens.is_empty() {
+        bail!("{} has no generations", profile.display());
+    }
+
+    // Keep stdout for the document when printing JSON.
+    let redirect = args.json.then(output::stdout_to_stderr).transpose()?;
+    match closure_sizes(&gens) {
+        Ok(sizes) => {
+            for generation in &mut gens {
+                generation.closure_size =
+                    sizes.get(&generation.path.display().to_string()).copied();
+            }
+        }
+        Err(e) => output::warn(&format!("Could not determine closure sizes: {:#}", e)),
+    }
+    drop(redirect);
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&gens)?);
+        return Ok(());
+    }
+
+    output::header(&format!("Generations of {}", profile.display()));
+    println!(
+        "  {:>4}  {:<16}  {:<24}  {:<10}  {:>10}  {}",
+        "GEN".dimmed(),
+        "CREATED (UTC)".dimmed(),
+        "VERSION".dimmed(),
+        "KERNEL".dimmed(),
+        "SIZE".dimmed(),
+        "STATE".dimmed()
+    );
+
+    for generation in &gens {
+        println!(
+            "  {:>4}  {:<16}  {:<24}  {:<10}  {:>10}  {}",
+            generation.number,
+            format_timestamp(generation.created),
+            generation.version.as_deref().unwrap_or("-"),
+            generation.kernel.as_deref().unwrap_or("-"),
+            generation
+                .closure_size
+                .map_or("-".to_string(), |size| format_bytes(size as f64)),
+            markers(generation)
+        );
+    }
+
+    Ok(())
+}
+
+/// Markers for the generation's state, e.g. `current, booted`.
+fn markers(generation: &Generation) -> String {
+    let mut markers = Vec::new();
+    if generation.current {
+        markers.push("current".green().bold().to_string());
+    }
+    if generation.booted {
+        markers.push("booted".cyan().to_string());
+    }
+    if generation.boot_entry {
+        markers.push("boot entry".dimmed().to_string());
+    }
+    markers.join(", ")
+}
+
+/// Closure size of each generation, keyed by store path.
+fn closure_sizes(gens: &[Generation]) -> Result<HashMap<String, u64>> {
+    let (json, _) = CommandRunner::new("nix")
+        .args(["path-info", "--json", "--closure-size"])
+        .args(gens.iter().map(|g| g.path.display().to_string()))
+        .show_command(false)
+        .inherit_stdio(false)
+        .run_output()?;
+    parse_path_info(&json)
+}
+
+/// Parse `nix path-info --json --closure-size`, which is a list of objects
+/// with a `path` field in older nix versions and an object keyed by path in
+/// newer ones.
+fn parse_path_info(json: &str) -> Result<HashMap<String, u64>> {
+    if json.trim().is_empty() {
+        // Plan mode runs nothing.
+        return Ok(HashMap::new());
+    }
+
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    let size = |info: &serde_json::Value| info.get("closureSize")?.as_u64();
+
+    let sizes = match value {
+        serde_json::Value::Array(infos) => infos
+            .iter()
+            .filter_map(|info| Some((info.get("path")?.as_str()?.to_string(), size(info)?)))
+            .collect(),
+        serde_json::Value::Object(infos) => infos
+            .iter()
+            .filter_map(|(path, info)| Some((path.clone(), size(info)?)))
+            .collect(),
+        _ => bail!("unexpected output from nix path-info"),
+    };
+    Ok(sizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_path_info_list() {
+        let json = r#"[{"path":"/nix/store/a-system","closureSize":1024,"narSize":10}]"#;
+        let sizes = parse_path_info(json).unwrap();
+        assert_eq!(sizes.get("/nix/store/a-system"), Some(&1024));
+    }
+
+    #[test]
+    fn test_parse_path_info_object() {
+        let json = r#"{"/nix/store/a-system":{"closureSize":2048},"/nix/store/b-gone":null}"#;
+        let sizes = parse_path_info(json).unwrap();
+        assert_eq!(sizes.len(), 1);
+        assert_eq!(sizes.get("/nix/store/a-system"), Some(&2048));
+    }
+
+    #[test]
+    fn test_parse_path_info_empty() {
+        assert!(parse_path_info("").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+//! History command - lists and shows past runs.
+
+use anyhow::{Context, Result};
+use owo_colors::OwoColorize;
+
+use crate::cli::history::{HistoryCommands, ListArgs, ShowArgs};
+use crate::cli::HistoryArgs;
+use crate::history::{self, format_timestamp, Entry};
+use crate::output;
+use crate::progress::format_ms;
+
+/// Execute the history command.
+pub fn run(args: &HistoryArgs) -> Result<()> {
+    let path = history::history_path()
+        .context("could not determine history path (set HOME or XDG_STATE_HOME)")?;
+    let entries = history::load(&path)?;
+
+    match args.command {
+        Some(HistoryCommands::List(ref list)) => list_entries(list, &entries),
+        Some(HistoryCommands::Show(ref show)) => show_entry(show, &entries),
+        None => list_entries(&args.list, &entries),
+    }
+}
+
+/// Whether `entry` passes the list filters.
+fn matches(args: &ListArgs, entry: &Entry) -> bool {
+    args.command
+        .as_ref()
+        .is_none_or(|name| entry.subcommand == *name)
+        && args
+            .host
+            .as_ref()
+            .is_none_or(|host| entry.hosts.iter().any(|h| h.host == *host))
+        && (!args.failed || !entry.success)
+}
+
+fn list_entries(args: &ListArgs, entries: &[Entry]) -> Result<()> {
+    let selected: Vec<&Entry> = entries
+        .iter()
+        .rev()
+        .filter(|e| matches(args, e))
+        .take(args.limit)
+        .collect();
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&selected)?);
+        return Ok(());
+    }
+
+    if selected.is_empty() {
+        output::info("No matching runs");
+        return Ok(());
+    }
+
+    output::header("Run History");
+    println!(
+        "  {:>4}  {:<16}  {:<10}  {:<7}  {:>7}  {}",
+        "ID".dimmed(),
+        "STARTED (UTC)".dimmed(),
+        "COMMAND".dimmed(),
+        "STATUS".dimmed(),
+        "TIME".dimmed(),
+        "HOSTS".dimmed()
+    );
+
+    for entry in selected {
+        let hosts: Vec<&str> = entry.hosts.iter().map(|h| h.host.as_str()).collect();
+        println!(
+            "  {:>4}  {:<16}  {:<10}  {}  {:>7}  {}",
+            entry.id,
+            format_timestamp(entry.started),
+            entry.subcommand,
+            status(entry.success),
+            format_ms(entry.duration_ms),
+            hosts.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+fn show_entry(args: &ShowArgs, entries: &[Entry]) -> Result<()> {
+    let entry = entries
+        .iter()
+        .find(|e| e.id == args.id)
+        .with_context(|| format!("no run with id {}", args.id))?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(entry)?);
+        return Ok(());
+    }
+
+    output::header(&format!("Run {}", entry.id));
+    output::kv("command", &format!("bonk {}", entry.args.join(" ")));
+    output::kv(
+        "started",
+        &format!("{} UTC", format_timestamp(entry.started)),
+    );
+    output::kv("duration", &format_ms(entry.duration_ms));
+    output::kv("status", &status(entry.success));
+    if let Some(ref error) = entry.error {
+        output::kv("error", error);
+    }
+    if let Some(ref cwd) = entry.cwd {
+        output::kv("cwd", cwd);
+    }
+    if let Some(ref flake) = entry.flake {
+        output::kv("flake", flake);
+    }
+    if let Some(ref rev) = entry.flake_rev {
+        let dirty = if entry.flake_dirty == Some(true) {
+            " (dirty)"
+        } else {
+            ""
+        };
+        output::kv("revision", &format!("{}{}", rev, dirty));
+    }
+    for host in &entry.hosts {
+        let mut detail = host.host.clone();
+        if let Some(ref target) = host.target {
+            detail.push_str(&format!(" -> {}", target));
+        }
+        if let Some(ref build_host) = host.build_host {
+            detail.push_str(&format!(" (built on {})", build_host));
+        }
+        output::kv("host", &detail);
+    }
+
+    if !entry.timings.is_empty() {
+        entry.timings.print(10);
+    }
+
+    if !entry.commands.is_empty() {
+        output::header("Commands");
+        for cmd in &entry.commands {
+            let code = match cmd.exit_code {
+                Some(0) => "0".green().to_string(),
+                Some(code) => code.to_string().red().to_string(),
+                None => "-".red().to_string(),
+            };
+            println!(
+                "  [{}] {:>7}  {}",
+                code,
+                format_ms(cmd.duration_ms),
+                cmd.command
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn status(success: bool) -> String {
+    if success {
+        format!("{:<7}", "ok").green().to_string()
+    } else {
+        format!("{:<7}", "failed").red().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::HostRecord;
+
+    fn entry(subcommand: &str, host: &str, success: bool) -> Entry {
+        Entry {
+            subcommand: subcommand.to_string(),
+            hosts: vec![HostRecord {
+                host: host.to_string(),
+                ..HostRecord::default()
+            }],
+            success,
+            ..Entry::default()
+        }
+    }
+
+    #[test]
+    fn test_matches_filters() {
+        let failed_switch = entry("switch", "zebes", false);
+        let args = ListArgs {
+            command: Some("switch".to_string()),
+            host: Some("zebes".to_string()),
+            failed: true,
+            ..ListArgs::default()
+        };
+        assert!(matches(&args, &failed_switch));
+        assert!(!matches(&args, &entry("switch", "zebes", true)));
+        assert!(!matches(&args, &entry("boot", "zebes", false)));
+        assert!(!matches(&args, &entry("switch", "ridley", false)));
+        assert!(matches(&ListArgs::default(), &failed_switch));
+    }
+}
@@ -0,0 +1,310 @@
+//! Home commands - wraps `nh home switch` and `nh home build` for
+//! standalone Home Manager, and lists and rolls back its generations.
+//!
+//! The configuration defaults to `homeConfigurations.<user>@<host>`, falling
+//! back to `<user>` when the flake has no configuration for this host. The
+//! build host and caches resolve like the os commands', including this
+//! host's `[hosts.<name>]` profile.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::cli::{GenerationsArgs, HomeArgs, HomeCommands, HomeRollbackArgs};
+use crate::commands::os::BuildOptions;
+use crate::commands::{generations, rollback};
+use crate::config::Config;
+use crate::configurations::{self, HOME};
+use crate::exec::{self, CommandRunner};
+use crate::flake::{check_worktree, resolve_flake_path, FlakeRef};
+use crate::generations as profiles;
+use crate::history;
+use crate::host::get_hostname;
+use crate::output;
+
+/// Execute a home subcommand.
+pub fn run(command: &HomeCommands, flake_path: Option<&Path>, config: &Config) -> Result<()> {
+    match command {
+        HomeCommands::Switch(args) => rebuild("switch", args, flake_path, config),
+        HomeCommands::Build(args) => rebuild("build", args, flake_path, config),
+        HomeCommands::Generations(args) => generations::run(&GenerationsArgs {
+            profile: "home".to_string(),
+            json: args.json,
+        }),
+        HomeCommands::Rollback(args) => roll_back(args),
+    }
+}
+
+/// Build, and for `switch` activate, the Home Manager configuration.
+fn rebuild(
+    action: &str,
+    args: &HomeArgs,
+    flake_path: Option<&Path>,
+    config: &Config,
+) -> Result<()> {
+    let flake = match args.flake {
+        Some(ref flake) => FlakeRef::parse(flake)?,
+        None => resolve_flake_path(flake_path, config)?,
+    };
+    history::set_flake(&flake);
+    check_worktree(&flake, args.build.require_clean, args.build.add_untracked)?;
+
+    let hostname = get_hostname().context("could not determine hostname")?;
+    let configuration = configuration(args, &flake, &hostname)?;
+    let profile = config.host_profile(&hostname).cloned().unwrap_or_default();
+    let build = BuildOptions::resolve(
+        args.build.build_host.as_deref(),
+        args.build.local,
+        args.build.substituter.as_deref(),
+        args.build.key.as_deref(),
+        &profile,
+        config,
+    );
+    let trace = args
+        .build
+        .trace()
+        .or(config.settings().os.trace)
+        .unwrap_or(false);
+    let extra_args = config.extra_args();
+
+    output::info(&format!(
+        "Rebuilding home configuration: {} ({})",
+        configuration, action
+    ));
+    if let Some(ref bh) = build.build_host {
+        output::status(&format!("Building on remote host: {}", bh));
+    }
+
+    let nh_runner = |action: &str| {
+        let mut runner = CommandRunner::new("nh")
+            .args(["home", action])
+            .arg(&flake.url)
+            .args(["-c", &configuration]);
+        runner = build.apply(runner);
+        if let Some(ref extension) = args.backup_extension {
+            runner = runner.args(["--backup-extension", extension]);
+        }
+        runner = runner.arg_if(trace, "--show-trace");
+        runner = runner.arg_if(args.build.dry_run, "--dry-run");
+        if !extra_args.is_empty() {
+            runner = runner.arg("--").args(&extra_args);
+        }
+        runner.nix_log()
+    };
+
+    if action == "build" {
+        nh_runner("build").retryable().run()?;
+    } else {
+        // With --retries, build first so that only the build is retried,
+        // never the activation.
+        if exec::retries() > 0 {
+            nh_runner("build").retryable().run()?;
+        }
+        nh_runner(action).run()?;
+    }
+
+    if args.build.dry_run {
+        output::success(&format!("Dry run complete (home {})", action));
+    } else if action == "build" {
+        output::success("Build complete! (home build)");
+    } else {
+        output::success(&format!("Home configuration {} activated", configuration));
+    }
+    Ok(())
+}
+
+/// The `homeConfigurations` attribute to build: the one named with `-c` or
+/// `#name`, else `<user>@<hostname>` or `<user>`, whichever the flake has.
+fn configuration(args: &HomeArgs, flake: &FlakeRef, hostname: &str) -> Result<String> {
+    let attr = flake
+        .attr
+        .as_deref()
+        .map(|attr| attr.strip_prefix("homeConfigurations.").unwrap_or(attr));
+    let explicit = match (args.configuration.as_deref(), attr) {
+        (Some(name), Some(attr)) if name != attr => anyhow::bail!(
+            "flake reference '{}' selects '{}', which conflicts with -c '{}'",
+            flake,
+            attr,
+            name
+        ),
+        (name, attr) => name.or(attr),
+    };
+    if let Some(name) = explicit {
+        configurations::validate(flake, HOME, &[name.to_string()])?;
+        return Ok(name.to_string());
+    }
+
+    let user = std::env::var("USER")
+        .ok()
+        .filter(|user| !user.is_empty())
+        .context("could not determine the user (USER is not set); pass -c <name>")?;
+    let candidates = [format!("{}@{}", user, hostname), user];
+
+    let names = configurations::list(flake, HOME).unwrap_or_else(|e| {
+        tracing::info!("Could not list {}: {:#}", HOME, e);
+        Vec::new()
+    });
+    if names.is_empty() {
+        // Let nh report it if the guess is wrong.
+        return Ok(candidates[0].clone());
+    }
+    match candidates.iter().find(|name| names.contains(name)) {
+        Some(name) => Ok(name.clone()),
+        None => Err(configurations::unknown(
+            &candidates[0],
+            &flake.url,
+            HOME,
+            &names,
+        )),
+    }
+}
+
+/// Activate an earlier Home Manager generation.
+///
+/// Running a generation's `activate` script points the profile at it again
+/// as a new generation, the way `home-manager` itself rolls back.
+fn roll_back(args: &HomeRollbackArgs) -> Result<()> {
+    let profile = profiles::profile_path("home")?;
+    let gens = profiles::list(&profile)?;
+    let to = rollback::select(&gens, args.generation, args.to.as_deref())?;
+    let from = gens.iter().find(|g| g.current);
+
+    output::header("Home Manager rollback");
+    if let Some(from) = from {
+        output::kv("from", &rollback::describe(from));
+    }
+    output::kv("to", &rollback::describe(to));
+
+    if !args.yes && !exec::planning() {
+        println!();
+        if !output::confirm(&format!("Activate generation {}?", to.number))? {
+            output::info("Cancelled.");
+            return Ok(());
+        }
+    }
+
+    CommandRunner::new(to.path.join("activate").display().to_string())
+        .run()
+        .with_context(|| format!("failed to activate generation {}", to.number))?;
+
+    output::success(&format!("Activated Home Manager generation {}", to.number));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::os::BuildOptions;
+    use crate::exec::{with_executor, Recorder};
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    fn resolve(args: &HomeArgs, flake: &str, names: &str) -> Result<String> {
+        let recorder = Arc::new(Recorder::default());
+        recorder.respond("nix eval", 0, names);
+        let flake = FlakeRef::parse(flake).unwrap();
+        with_executor(recorder, || configuration(args, &flake, "rune"))
+    }
+
+    #[test]
+    #[serial]
+    fn test_configuration_prefers_user_at_host() {
+        std::env::set_var("USER", "alice");
+        let names = r#"["alice","alice@rune","bob@rune"]"#;
+        assert_eq!(
+            resolve(&HomeArgs::default(), "github:org/cfg", names).unwrap(),
+            "alice@rune"
+        );
+
+        let names = r#"["alice","bob@rune"]"#;
+        assert_eq!(
+            resolve(&HomeArgs::default(), "github:org/cfg", names).unwrap(),
+            "alice"
+        );
+
+        let err =
+            resolve(&HomeArgs::default(), "github:org/cfg", r#"["alicia@rune"]"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "github:org/cfg has no homeConfigurations.alice@rune; did you mean 'alicia@rune'?"
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_configuration_from_flag_or_flake_ref() {
+        let args = HomeArgs {
+            configuration: Some("bob@rune".to_string()),
+            ..HomeArgs::default()
+        };
+        let names = r#"["alice@rune","bob@rune"]"#;
+        assert_eq!(resolve(&args, "github:org/cfg", names).unwrap(), "bob@rune");
+        assert_eq!(
+            resolve(&HomeArgs::default(), "github:org/cfg#bob@rune", names).unwrap(),
+            "bob@rune"
+        );
+        assert!(resolve(&args, "github:org/cfg#alice@rune", names).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_switch_passes_build_options_to_nh() {
+        std::env::remove_var("BONK_BUILD_HOST");
+        std::env::remove_var("BONK_EXTRA_ARGS");
+        let recorder = Arc::new(Recorder::default());
+        let args = HomeArgs {
+            flake: Some("github:org/cfg#alice".to_string()),
+            build: BuildOptions {
+                build_host: Some("builder".to_string()),
+                substituter: Some("https://cache.example.com".to_string()),
+                trace: true,
+                ..BuildOptions::default()
+            },
+            backup_extension: Some("bak".to_string()),
+            ..HomeArgs::default()
+        };
+
+        with_executor(recorder.clone(), || {
+            rebuild("switch", &args, None, &Config::default())
+        })
+        .unwrap();
+
+        assert_eq!(
+            recorder.command_lines().last().unwrap(),
+            "nh home switch github:org/cfg -c alice --build-host builder \
+             --extra-substituters https://cache.example.com --backup-extension bak --show-trace"
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_switch_with_retries_only_retries_the_build() {
+        std::env::remove_var("BONK_BUILD_HOST");
+        std::env::remove_var("BONK_EXTRA_ARGS");
+        let recorder = Arc::new(Recorder::default());
+        recorder
+            .fail_once("nh home build", "error: unable to download 'https://x'")
+            .fail_once("nh home switch", "error: unable to download 'https://x'");
+        let args = HomeArgs {
+            flake: Some("github:org/cfg#alice".to_string()),
+            ..HomeArgs::default()
+        };
+
+        exec::set_retries(1);
+        let result = with_executor(recorder.clone(), || {
+            rebuild("switch", &args, None, &Config::default())
+        });
+        exec::set_retries(0);
+
+        assert!(result.is_err());
+        let lines = recorder.command_lines();
+        assert_eq!(
+            lines[lines.len() - 3..],
+            [
+                "nh home build github:org/cfg -c alice",
+                "nh home build github:org/cfg -c alice",
+                "nh home switch github:org/cfg -c alice",
+            ]
+        );
+    }
+}
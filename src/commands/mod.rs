@@ -1,7 +1,18 @@
 //! Command implementations.
 
 pub mod build;
+pub mod checks;
+pub mod complete;
+pub mod config;
+pub mod doctor;
+pub mod fleet;
+pub mod generations;
+pub mod history;
+pub mod home;
 pub mod os;
+pub mod review;
+pub mod rollback;
+pub mod specialisations;
 pub mod store;
 pub mod try_pkg;
 pub mod update;
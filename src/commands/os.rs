@@ -1,31 +1,256 @@
-//! OS commands - wraps `nh os switch` and `nh os boot`.
+//! OS commands - wraps `nh os switch`, `boot`, `test`, `build` and
+//! `build-vm`, and runs `switch-to-configuration dry-activate`.
 
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
 
 use crate::cli::OsArgs;
-use crate::env;
-use crate::exec::CommandRunner;
-use crate::flake::resolve_flake_path;
+use crate::commands::checks::{self, Checks, Deadline};
+use crate::commands::{fleet, review, rollback};
+use crate::config::{self, Config, HostProfile};
+use crate::configurations;
+use crate::elevate::{self, Privilege};
+use crate::exec::{self, CommandRunner};
+use crate::flake::{check_worktree, resolve_flake_path, FlakeRef};
+use crate::generations;
+use crate::history::{self, HostRecord};
 use crate::host::get_hostname;
 use crate::output;
 
 /// The nh os action to perform.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OsAction {
+    /// Activate now and add a boot entry.
     Switch,
+    /// Add a boot entry without activating.
     Boot,
+    /// Activate now without adding a boot entry.
+    Test,
+    /// Only build the system toplevel.
+    Build,
+    /// Build, then show which units activating would restart.
+    DryActivate,
+    /// Build a QEMU VM, and start it when `run` is set.
+    BuildVm { run: bool },
 }
 
 impl OsAction {
-    /// Return the nh subcommand string.
-    fn as_str(self) -> &'static str {
+    /// Name of the action, as used in messages.
+    pub fn as_str(self) -> &'static str {
         match self {
             OsAction::Switch => "switch",
             OsAction::Boot => "boot",
+            OsAction::Test => "test",
+            OsAction::Build => "build",
+            OsAction::DryActivate => "dry-activate",
+            OsAction::BuildVm { .. } => "build-vm",
         }
     }
+
+    /// The `nh os` subcommand that builds (and maybe activates) the system.
+    fn nh_command(self) -> &'static str {
+        match self {
+            OsAction::DryActivate => "build",
+            other => other.as_str(),
+        }
+    }
+
+    /// Whether the action activates the system, and so can deploy to a
+    /// target host.
+    pub fn deploys(self) -> bool {
+        !matches!(self, OsAction::Build | OsAction::BuildVm { .. })
+    }
+
+    /// Whether the action activates the new system right away, so it can be
+    /// health-checked and rolled back.
+    fn activates_now(self) -> bool {
+        matches!(self, OsAction::Switch | OsAction::Test)
+    }
+
+    /// Whether the action activates a configuration, and so can pick one of
+    /// its specialisations.
+    fn activates(self) -> bool {
+        matches!(self, OsAction::Switch | OsAction::Boot | OsAction::Test)
+    }
+
+    /// Where to link the built system, for actions that use it afterwards.
+    fn out_link(self, host: &str) -> Option<PathBuf> {
+        match self {
+            OsAction::DryActivate => Some(temp_link("dry-activate", host)),
+            // Like nixos-rebuild, leave `result` (and the VM's disk image) in
+            // the current directory.
+            OsAction::BuildVm { .. } => Some(PathBuf::from("result")),
+            _ => None,
+        }
+    }
+}
+
+/// Per-host settings resolved from flags, the host profile, env and config.
+#[derive(Debug, PartialEq)]
+struct HostPlan {
+    /// `nixosConfigurations` attribute to build.
+    host: String,
+    /// SSH address to deploy to, if not deploying locally.
+    deploy_target: Option<String>,
+    build: BuildOptions,
+    /// Health checks to run after activating.
+    checks: Checks,
+    /// Specialisation to activate instead of the base configuration.
+    specialisation: Option<String>,
+}
+
+impl HostPlan {
+    /// Resolve the plan for `selected` (the `-H` value), or for the local
+    /// hostname when `-H` was not given.
+    ///
+    /// A `[hosts.<name>]` profile is looked up by `-H` name or hostname.
+    /// Explicit flags always win over the profile, and the profile wins over
+    /// `BONK_BUILD_HOST` and the global config defaults. The profile's
+    /// `target` is only used when the profile was selected with `-H`, so
+    /// running on the machine itself never deploys to it over SSH.
+    fn resolve(args: &OsArgs, selected: Option<&str>, config: &Config) -> Result<Self> {
+        let name = match selected {
+            Some(h) => h.to_string(),
+            None => get_hostname().context("could not determine hostname for rebuild")?,
+        };
+        let profile = config.host_profile(&name).cloned().unwrap_or_default();
+        let host = profile
+            .configuration
+            .clone()
+            .unwrap_or_else(|| name.clone());
+
+        // Resolve where to deploy (-T / --target-host / profile target):
+        //  --target-host addr   -> deploy to a specific SSH address
+        //  -TH zebes           -> deploy to the profile target, else the -H name
+        //  -H zebes (profile)   -> deploy to the profile target, if it has one
+        //  neither              -> local deploy (no --target-host passed to nh)
+        let deploy_target = if let Some(ref th) = args.target_host {
+            Some(th.clone())
+        } else if args.target {
+            Some(profile.target.clone().unwrap_or(name))
+        } else if selected.is_some() {
+            profile.target.clone()
+        } else {
+            None
+        };
+
+        let build = BuildOptions::resolve(
+            args.build.build_host.as_deref(),
+            args.build.local,
+            args.build.substituter.as_deref(),
+            args.build.key.as_deref(),
+            &profile,
+            config,
+        );
+
+        Ok(Self {
+            host,
+            deploy_target,
+            build,
+            checks: Checks::resolve(config, &profile),
+            specialisation: None,
+        })
+    }
+}
+
+/// Where to build and which extra caches to use. Shared by the os and home
+/// commands.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BuildOptions {
+    pub build_host: Option<String>,
+    pub substituters: Vec<String>,
+    pub keys: Vec<String>,
+}
+
+impl BuildOptions {
+    /// Resolve from the `-B`, `--local`, `-s` and `-k` flags, then the host
+    /// profile, then `BONK_BUILD_HOST` and config.
+    pub fn resolve(
+        build_host: Option<&str>,
+        local: bool,
+        substituter: Option<&str>,
+        key: Option<&str>,
+        profile: &HostProfile,
+        config: &Config,
+    ) -> Self {
+        let build_host = if local {
+            None
+        } else {
+            build_host
+                .map(String::from)
+                .or_else(|| profile.build_host.clone())
+                .or_else(|| config.build_host())
+        };
+
+        // An explicit -s/-k replaces the configured caches; otherwise the
+        // profile's caches are added to the global ones.
+        let os = &config.settings().os;
+        let substituters = match substituter {
+            Some(sub) => vec![sub.to_string()],
+            None => combine(&os.substituters, &profile.substituters),
+        };
+        let keys = match key {
+            Some(key) => vec![key.to_string()],
+            None => combine(&os.trusted_public_keys, &profile.trusted_public_keys),
+        };
+
+        Self {
+            build_host,
+            substituters,
+            keys,
+        }
+    }
+
+    /// Pass the options to an nh command.
+    pub fn apply(&self, mut runner: CommandRunner) -> CommandRunner {
+        if let Some(ref bh) = self.build_host {
+            runner = runner.args(["--build-host", bh]);
+        }
+        if !self.substituters.is_empty() {
+            runner = runner.args(["--extra-substituters", &self.substituters.join(" ")]);
+        }
+        if !self.keys.is_empty() {
+            runner = runner.args(["--extra-trusted-public-keys", &self.keys.join(" ")]);
+        }
+        runner
+    }
+}
+
+/// Where `bonk` would deploy `selected` (the `-H` value) with the `-T` and
+/// `--target-host` flags in `args`, or `None` for this machine.
+pub fn deploy_target(
+    args: &OsArgs,
+    selected: Option<&str>,
+    config: &Config,
+) -> Result<Option<String>> {
+    HostPlan::resolve(args, selected, config).map(|plan| plan.deploy_target)
+}
+
+/// A temporary out-link for a system built for `purpose`.
+fn temp_link(purpose: &str, host: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("bonk-{}-{}-{}", purpose, host, std::process::id()))
+}
+
+/// Removes a temporary out-link when dropped. The link keeps the build from
+/// being garbage collected only for as long as bonk needs it.
+struct TempLink(PathBuf);
+
+impl Drop for TempLink {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Concatenate two optional lists.
+fn combine(global: &Option<Vec<String>>, profile: &Option<Vec<String>>) -> Vec<String> {
+    global
+        .iter()
+        .chain(profile.iter())
+        .flatten()
+        .cloned()
+        .collect()
 }
 
 /// Execute an os rebuild with the given action.
@@ -35,84 +260,1099 @@ impl OsAction {
 /// * `action` - Whether to `switch` (activate now) or `boot` (next boot only)
 /// * `args` - CLI arguments shared by both switch and boot
 /// * `flake_path` - Optional explicit flake path override
+/// * `config` - Layered config file defaults and host profiles
+///
+/// Several `-H` hosts or a `--group` hand off to [`fleet::run`], which
+/// deploys to each host in parallel.
 ///
 /// # Errors
 ///
 /// Returns an error if hostname detection, flake resolution, or the nh command fails.
-pub fn run(action: OsAction, args: &OsArgs, flake_path: Option<&Path>) -> Result<()> {
-    // Resolve which NixOS flake configuration to build (-H).
-    let host = match &args.host {
-        Some(h) => h.clone(),
-        None => get_hostname().context("could not determine hostname for rebuild")?,
+pub fn run(
+    action: OsAction,
+    args: &OsArgs,
+    flake_path: Option<&Path>,
+    config: &Config,
+) -> Result<()> {
+    let flake = match args.flake {
+        Some(ref flake) => FlakeRef::parse(flake)?,
+        None => resolve_flake_path(flake_path, config)?,
     };
+    history::set_flake(&flake);
+    check_worktree(&flake, args.build.require_clean, args.build.add_untracked)?;
+
+    let mut hosts = args.hosts();
 
-    // Resolve where to deploy (-T / --target-host):
-    //  -TH zebes           -> deploy to the -H host (zebes)
-    //  --target-host addr   -> deploy to a specific SSH address
-    //  neither              -> local deploy (no --target-host passed to nh)
-    let deploy_target = if let Some(ref th) = args.target_host {
-        Some(th.clone())
-    } else if args.target {
-        Some(host.clone())
+    // A `#host` attribute on the flake reference acts like -H.
+    if let Some(ref attr) = flake.attr {
+        if hosts.is_empty() && args.group.is_none() {
+            hosts.push(attr.clone());
+        } else if hosts != [attr.as_str()] {
+            anyhow::bail!(
+                "flake reference '{}' selects host '{}', which conflicts with -H/--group",
+                flake,
+                attr
+            );
+        }
+    }
+
+    if let Some(ref group) = args.group {
+        hosts.extend(config.group(group)?.iter().cloned());
+    }
+    let mut seen = std::collections::HashSet::new();
+    hosts.retain(|h| seen.insert(h.clone()));
+
+    // Fail fast on a host the flake doesn't define, rather than after nh
+    // has spent a while evaluating.
+    let wanted: Vec<String> = if hosts.is_empty() {
+        get_hostname().ok().into_iter().collect()
     } else {
-        None
+        hosts.clone()
     };
+    let wanted: Vec<String> = wanted.iter().map(|h| config.configuration(h)).collect();
+    configurations::validate(&flake, configurations::NIXOS, &wanted)?;
 
-    let flake = resolve_flake_path(flake_path)?;
+    if args.group.is_some() || hosts.len() > 1 {
+        if let OsAction::BuildVm { .. } = action {
+            anyhow::bail!("build-vm builds one host at a time");
+        }
+        return fleet::run(action, args, &hosts, &flake.url, config);
+    }
 
-    let build_host = if args.local {
-        None
-    } else {
-        args.build_host.clone().or_else(env::get_build_host)
+    rebuild(
+        action,
+        args,
+        hosts.first().map(String::as_str),
+        &flake.url,
+        config,
+    )
+}
+
+/// Rebuild a single host: `selected` is the `-H` name, or `None` for the
+/// local hostname.
+///
+/// # Errors
+///
+/// Returns an error if hostname detection or the nh command fails.
+pub fn rebuild(
+    action: OsAction,
+    args: &OsArgs,
+    selected: Option<&str>,
+    flake: &str,
+    config: &Config,
+) -> Result<()> {
+    if args.review && !action.activates() {
+        anyhow::bail!("--review only applies to switch, boot and test");
+    }
+    if !action.deploys() && (args.target || args.target_host.is_some()) {
+        anyhow::bail!(
+            "`bonk {}` doesn't deploy; drop -T/--target-host",
+            action.as_str()
+        );
+    }
+
+    if args.check && !action.activates_now() {
+        anyhow::bail!("--check only applies to switch and test");
+    }
+    let confirm = args
+        .confirm_timeout
+        .as_deref()
+        .map(config::parse_duration)
+        .transpose()?;
+    if confirm.is_some() && !action.activates_now() {
+        anyhow::bail!("--confirm-timeout only applies to switch and test");
+    }
+    if (args.specialisation.is_some() || args.no_specialisation) && !action.activates() {
+        anyhow::bail!("--specialisation only applies to switch, boot and test");
+    }
+    let check = action.activates_now()
+        && !args.build.dry_run
+        && (args.check || (!args.no_check && config.settings().checks.enabled.unwrap_or(false)));
+
+    let mut plan = HostPlan::resolve(args, selected, config)?;
+    if !action.deploys() {
+        plan.deploy_target = None;
+    }
+    if confirm.is_some() && plan.deploy_target.is_none() {
+        anyhow::bail!("--confirm-timeout only applies to deploys with -T/--target-host");
+    }
+    plan.specialisation = match args.specialisation {
+        Some(ref name) => Some(name.clone()),
+        // Switching this machine keeps the specialisation it is running.
+        None if action.activates() && !args.no_specialisation && plan.deploy_target.is_none() => {
+            generations::active_specialisation()
+        }
+        None => None,
     };
+    history::add_host(HostRecord {
+        host: plan.host.clone(),
+        target: plan.deploy_target.clone(),
+        build_host: plan.build.build_host.clone(),
+    });
 
-    let extra_args = env::get_extra_args();
     let label = action.as_str();
 
     output::info(&format!(
         "Rebuilding configuration for host: {} ({})",
-        host, label
+        plan.host, label
     ));
 
-    if let Some(ref dt) = deploy_target {
+    if let Some(ref dt) = plan.deploy_target {
         output::status(&format!("Deploying to target host: {}", dt));
     }
-    if let Some(ref bh) = build_host {
+    if let Some(ref bh) = plan.build.build_host {
         output::status(&format!("Building on remote host: {}", bh));
     }
+    if let Some(ref name) = plan.specialisation {
+        output::status(&format!("Activating specialisation: {}", name));
+    }
+
+    // Build first to review the changes, with --confirm-timeout so that the
+    // timeout doesn't count the build, and with --retries so that only the
+    // build is retried, never the activation. The activation below then
+    // finds everything already built.
+    let prebuild = args.review || confirm.is_some() || exec::retries() > 0;
+    let built = if action.activates() && prebuild {
+        let link = TempLink(temp_link("build", &plan.host));
+        let build = nh_runner(OsAction::Build, flake, &plan, args, config, Some(&link.0));
+        if args.review {
+            if !review::confirm(build, &link.0, plan.deploy_target.as_deref())? {
+                output::info("Cancelled.");
+                return Ok(());
+            }
+        } else {
+            build.nix_log().retryable().run()?;
+        }
+        Some(link)
+    } else {
+        None
+    };
+
+    // Note the generation to roll back to, and with --confirm-timeout have
+    // the target roll itself back unless told otherwise.
+    let target = plan.deploy_target.as_deref();
+    let previous = if (check || confirm.is_some()) && action == OsAction::Switch {
+        let current = generations::current(target)?;
+        if current.is_none() && !exec::planning() {
+            anyhow::bail!("could not determine the current system generation, needed to roll back");
+        }
+        current
+    } else {
+        None
+    };
+
+    // Units that had already failed don't count against the new system.
+    let failed_before = if check {
+        plan.checks.failed_before(target)
+    } else {
+        Vec::new()
+    };
+    // Schedule it only once the new system is on the target (--review has
+    // copied it already), so that the timeout covers just activating and
+    // confirming it.
+    let built_system = built.as_ref().map(|link| store_path(&link.0));
+    let mut deadline = match (confirm, target, &built_system) {
+        (Some(after), Some(target), Some(system)) if !args.build.dry_run => {
+            if !args.review {
+                copy_to(target, system)?;
+            }
+            Some(Deadline::arm(target, previous, label, after)?)
+        }
+        _ => None,
+    };
+
+    let out_link = action.out_link(&plan.host);
+    let activated = match (&deadline, &built_system) {
+        (Some(deadline), Some(system)) => activate_on(
+            deadline.target(),
+            system,
+            action,
+            plan.specialisation.as_deref(),
+        ),
+        _ => {
+            let mut runner =
+                nh_runner(action, flake, &plan, args, config, out_link.as_deref()).nix_log();
+            if !action.activates() {
+                runner = runner.retryable();
+            }
+            runner.run()
+        }
+    };
+    if let Some(ref deadline) = deadline {
+        activated.with_context(|| {
+            format!(
+                "{} rolls back to {} in {}s",
+                deadline.target(),
+                rollback_label(previous),
+                deadline.remaining().as_secs()
+            )
+        })?;
+    } else {
+        activated?;
+    }
+
+    if check {
+        verify(action, &plan, previous, &failed_before, &mut deadline)?;
+    }
+    if let Some(deadline) = deadline {
+        confirm_or_roll_back(action, previous, deadline)?;
+    }
+
+    if args.build.dry_run {
+        output::success(&format!("Dry run complete ({})", label));
+        return Ok(());
+    }
+
+    match (action, out_link) {
+        (OsAction::DryActivate, Some(link)) => {
+            let link = TempLink(link);
+            dry_activate(&link.0, plan.deploy_target.as_deref())?;
+            output::success("Dry activation complete (nothing was changed)");
+        }
+        (OsAction::BuildVm { run }, Some(link)) => {
+            let script = vm_script(&link, &plan.host);
+            if run {
+                output::info("Starting VM...");
+                CommandRunner::new(script.display().to_string()).run()?;
+            } else {
+                output::success(&format!("VM built! Start it with {}", script.display()));
+            }
+        }
+        (OsAction::Build, _) => output::success("Build complete! (build)"),
+        _ => output::success(&format!("Rebuild complete! ({})", label)),
+    }
+
+    Ok(())
+}
+
+/// Run the health checks after activating, and roll back to `previous` (the
+/// profile's generation, for `test`) if any fail. Units in `failed_before`
+/// had failed before activating, so don't count.
+fn verify(
+    action: OsAction,
+    plan: &HostPlan,
+    previous: Option<u64>,
+    failed_before: &[String],
+    deadline: &mut Option<Deadline>,
+) -> Result<()> {
+    let target = plan.deploy_target.as_deref();
+    let failures = checks::run(&plan.checks, target, failed_before);
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    output::warn("Health checks failed; rolling back");
+    rollback::activate(previous, action.as_str(), target).with_context(|| match deadline {
+        Some(ref deadline) => format!(
+            "rolling back failed; {} still rolls back on its own in {}s",
+            deadline.target(),
+            deadline.remaining().as_secs()
+        ),
+        None => "rolling back failed".to_string(),
+    })?;
+    if let Some(deadline) = deadline.take() {
+        if let Err(e) = deadline.cancel() {
+            output::warn(&format!("{:#}", e));
+        }
+    }
+
+    anyhow::bail!(
+        "health checks failed on {} ({}); rolled back to {}",
+        target.unwrap_or(&plan.host),
+        failures.join("; "),
+        rollback_label(previous)
+    )
+}
+
+/// What a rollback returns to: the previous generation after a switch, or
+/// the system profile after a test.
+fn rollback_label(previous: Option<u64>) -> String {
+    match previous {
+        Some(number) => format!("generation {}", number),
+        None => "the system profile".to_string(),
+    }
+}
+
+/// Ask whether to keep the new system before `deadline` runs out. Keeping it
+/// cancels the scheduled rollback; answering no rolls back now; no answer
+/// leaves the target to roll itself back.
+fn confirm_or_roll_back(action: OsAction, previous: Option<u64>, deadline: Deadline) -> Result<()> {
+    if exec::planning() {
+        return deadline.cancel();
+    }
+
+    let target = deadline.target().to_string();
+    let remaining = deadline.remaining();
+    if remaining.is_zero() {
+        anyhow::bail!(
+            "--confirm-timeout ran out before the new system on {} could be confirmed; \
+             it rolls back to {} on its own",
+            target,
+            rollback_label(previous)
+        );
+    }
+    let question = format!(
+        "Keep the new system on {}? (rolls back in {}s)",
+        target,
+        remaining.as_secs()
+    );
+    match output::confirm_within(&question, remaining)? {
+        Some(true) => {
+            deadline.cancel()?;
+            output::success(&format!("Kept the new system on {}", target));
+            Ok(())
+        }
+        Some(false) => {
+            rollback::activate(previous, action.as_str(), Some(&target))?;
+            deadline.cancel()?;
+            anyhow::bail!(
+                "new system on {} rejected; rolled back to {}",
+                target,
+                rollback_label(previous)
+            )
+        }
+        None => anyhow::bail!(
+            "new system on {} was not confirmed in time; it rolls back to {} on its own",
+            target,
+            rollback_label(previous)
+        ),
+    }
+}
+
+/// The `nh os` command for `action` on the host in `plan`, linking the result
+/// at `out_link` if given.
+fn nh_runner(
+    action: OsAction,
+    flake: &str,
+    plan: &HostPlan,
+    args: &OsArgs,
+    config: &Config,
+    out_link: Option<&Path>,
+) -> CommandRunner {
+    let trace = args
+        .build
+        .trace()
+        .or(config.settings().os.trace)
+        .unwrap_or(false);
+    let extra_args = config.extra_args();
+
+    // dry-activate only builds with nh; it activates on the target itself.
+    let nh_target = plan
+        .deploy_target
+        .as_ref()
+        .filter(|_| action.deploys() && action != OsAction::DryActivate);
 
     let mut runner = CommandRunner::new("nh")
-        .args(["os", label])
-        .arg(&flake)
-        .args(["-H", &host]);
+        .args(["os", action.nh_command()])
+        .arg(flake)
+        .args(["-H", &plan.host]);
 
-    if let Some(ref dt) = deploy_target {
+    if let Some(dt) = nh_target {
         runner = runner.args(["--target-host", dt]);
     }
-    if let Some(ref bh) = build_host {
-        runner = runner.args(["--build-host", bh]);
+    runner = plan.build.apply(runner);
+
+    // nh escalates on its own; tell it how, or that it already runs as root.
+    match elevate::current() {
+        Privilege::Root => runner = runner.arg("--bypass-root-check"),
+        Privilege::Program(program) if program != "sudo" => {
+            runner = runner.args(["--elevation-program", program]);
+        }
+        Privilege::Program(_) | Privilege::Off => {}
     }
-    if let Some(ref sub) = args.substituter {
-        runner = runner.args(["--extra-substituters", sub]);
+    if let Some(dt) = nh_target {
+        runner = runner.arg_if(elevate::needs_remote_sudo(dt), "--use-remote-sudo");
     }
-    if let Some(ref key) = args.key {
-        runner = runner.args(["--extra-trusted-public-keys", key]);
+    if let Some(link) = out_link {
+        runner = runner.args(["--out-link", &link.display().to_string()]);
     }
 
-    runner = runner.arg_if(args.trace, "--show-trace");
-    runner = runner.arg_if(args.dry_run, "--dry-run");
+    if action.activates() {
+        if let Some(ref name) = plan.specialisation {
+            runner = runner.args(["--specialisation", name]);
+        }
+        runner = runner.arg_if(args.no_specialisation, "--no-specialisation");
+    }
+
+    runner = runner.arg_if(trace, "--show-trace");
+    runner = runner.arg_if(args.build.dry_run, "--dry-run");
 
     if !extra_args.is_empty() {
         runner = runner.arg("--").args(&extra_args);
     }
 
-    runner.run()?;
+    runner
+}
 
-    if args.dry_run {
-        output::success(&format!("Dry run complete ({})", label));
-    } else {
-        output::success(&format!("Rebuild complete! ({})", label));
+/// Show what activating the system at `link` would change, on `target` if
+/// set (after copying the system there) or else on this machine.
+fn dry_activate(link: &Path, target: Option<&str>) -> Result<()> {
+    let Some(target) = target else {
+        return CommandRunner::new(
+            link.join("bin/switch-to-configuration")
+                .display()
+                .to_string(),
+        )
+        .arg("dry-activate")
+        .elevated()
+        .run();
+    };
+
+    let toplevel = store_path(link);
+    copy_to(target, &toplevel)?;
+
+    CommandRunner::ssh_sudo(target)
+        .arg(format!("{}/bin/switch-to-configuration", toplevel))
+        .arg("dry-activate")
+        .run()
+}
+
+/// The store path `link` points to, which a remote side needs instead of the
+/// local link. Falls back to the link itself when it isn't there (e.g. with
+/// `--plan`).
+fn store_path(link: &Path) -> String {
+    fs::read_link(link)
+        .unwrap_or_else(|_| link.to_path_buf())
+        .display()
+        .to_string()
+}
+
+/// Copy the system at `toplevel` to `target`.
+fn copy_to(target: &str, toplevel: &str) -> Result<()> {
+    output::status(&format!("Copying system to {}", target));
+    CommandRunner::new("nix")
+        .args(["copy", "--to", &format!("ssh://{}", target), toplevel])
+        .retryable()
+        .run()
+}
+
+/// Activate the system at `toplevel`, already on `target`, the way nh would
+/// but without evaluating and copying it again: point the system profile at
+/// it for a switch, then run its (or its specialisation's)
+/// `switch-to-configuration`.
+fn activate_on(
+    target: &str,
+    toplevel: &str,
+    action: OsAction,
+    specialisation: Option<&str>,
+) -> Result<()> {
+    let ssh = || CommandRunner::ssh_sudo(target);
+    if action == OsAction::Switch {
+        ssh()
+            .args([
+                "nix-env",
+                "-p",
+                generations::SYSTEM_PROFILE,
+                "--set",
+                toplevel,
+            ])
+            .run()?;
     }
+    let system = match specialisation {
+        Some(name) => format!("{}/specialisation/{}", toplevel, name),
+        None => toplevel.to_string(),
+    };
+    ssh()
+        .arg(format!("{}/bin/switch-to-configuration", system))
+        .arg(action.as_str())
+        .run()
+}
 
-    Ok(())
+/// The script that starts the VM built at `link`.
+///
+/// It is named after the VM's `networking.hostName`, which may differ from
+/// the configuration name; fall back to that name when the build isn't
+/// there to look at (e.g. with `--plan`).
+fn vm_script(link: &Path, host: &str) -> PathBuf {
+    let bin = link.join("bin");
+    fs::read_dir(&bin)
+        .ok()
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .find(|name| name.starts_with("run-") && name.ends_with("-vm"))
+        .map_or_else(
+            || bin.join(format!("run-{}-vm", host)),
+            |name| bin.join(name),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::os::BuildOptions;
+    use crate::cli::Elevation;
+    use crate::exec::{with_executor, Recorder};
+    use serial_test::serial;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const PROFILE: &str = r#"
+        [os]
+        substituters = ["https://global"]
+
+        [hosts.zebes]
+        target = "root@10.0.0.5"
+        build_host = "builder"
+        substituters = ["https://zebes"]
+
+        [hosts.laptop]
+        configuration = "rune"
+    "#;
+
+    #[test]
+    #[serial]
+    fn test_profile_applied_for_selected_host() {
+        std::env::remove_var("BONK_BUILD_HOST");
+        let plan = HostPlan::resolve(
+            &OsArgs::default(),
+            Some("zebes"),
+            &Config::parse_for_test(PROFILE),
+        )
+        .unwrap();
+        assert_eq!(plan.host, "zebes");
+        assert_eq!(plan.deploy_target.as_deref(), Some("root@10.0.0.5"));
+        assert_eq!(plan.build.build_host.as_deref(), Some("builder"));
+        assert_eq!(
+            plan.build.substituters,
+            vec!["https://global", "https://zebes"]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_flags_override_profile() {
+        let args = OsArgs {
+            target_host: Some("root@192.168.1.50".to_string()),
+            build: BuildOptions {
+                build_host: Some("other".to_string()),
+                substituter: Some("https://flag".to_string()),
+                ..BuildOptions::default()
+            },
+            ..OsArgs::default()
+        };
+        let plan =
+            HostPlan::resolve(&args, Some("zebes"), &Config::parse_for_test(PROFILE)).unwrap();
+        assert_eq!(plan.deploy_target.as_deref(), Some("root@192.168.1.50"));
+        assert_eq!(plan.build.build_host.as_deref(), Some("other"));
+        assert_eq!(plan.build.substituters, vec!["https://flag"]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_local_disables_profile_build_host() {
+        let args = OsArgs {
+            build: BuildOptions {
+                local: true,
+                ..BuildOptions::default()
+            },
+            ..OsArgs::default()
+        };
+        let plan =
+            HostPlan::resolve(&args, Some("zebes"), &Config::parse_for_test(PROFILE)).unwrap();
+        assert!(plan.build.build_host.is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn test_profile_maps_configuration_name() {
+        std::env::remove_var("BONK_BUILD_HOST");
+        let plan = HostPlan::resolve(
+            &OsArgs::default(),
+            Some("laptop"),
+            &Config::parse_for_test(PROFILE),
+        )
+        .unwrap();
+        assert_eq!(plan.host, "rune");
+        assert!(plan.deploy_target.is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn test_target_flag_without_profile_uses_host_name() {
+        let args = OsArgs {
+            target: true,
+            ..OsArgs::default()
+        };
+        let plan =
+            HostPlan::resolve(&args, Some("ridley"), &Config::parse_for_test(PROFILE)).unwrap();
+        assert_eq!(plan.deploy_target.as_deref(), Some("ridley"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_run_passes_profile_to_nh() {
+        std::env::remove_var("BONK_BUILD_HOST");
+        std::env::remove_var("BONK_EXTRA_ARGS");
+        let recorder = Arc::new(Recorder::default());
+        let args = OsArgs {
+            flake: Some("/etc/nixos#zebes".to_string()),
+            build: BuildOptions {
+                trace: true,
+                ..BuildOptions::default()
+            },
+            ..OsArgs::default()
+        };
+
+        with_executor(recorder.clone(), || {
+            run(
+                OsAction::Switch,
+                &args,
+                None,
+                &Config::parse_for_test(PROFILE),
+            )
+        })
+        .unwrap();
+
+        assert_eq!(
+            recorder.command_lines(),
+            vec![
+                "nix eval --json /etc/nixos#nixosConfigurations --apply builtins.attrNames",
+                "nh os switch /etc/nixos -H zebes --target-host root@10.0.0.5 \
+                 --build-host builder --extra-substituters 'https://global https://zebes' \
+                 --show-trace",
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_run_rejects_unknown_host() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.respond("nix eval", 0, "[\"kraid\",\"zebes\"]");
+        let args = OsArgs {
+            host: Some("kraid,zebs".to_string()),
+            ..OsArgs::default()
+        };
+
+        let err = with_executor(recorder.clone(), || {
+            run(
+                OsAction::Switch,
+                &args,
+                Some(Path::new("/nonexistent/nixos")),
+                &Config::default(),
+            )
+        })
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "/nonexistent/nixos has no nixosConfigurations.zebs; did you mean 'zebes'?"
+        );
+        assert_eq!(recorder.command_lines().len(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_run_many_hosts_reports_failure() {
+        std::env::remove_var("BONK_BUILD_HOST");
+        std::env::remove_var("BONK_EXTRA_ARGS");
+        let recorder = Arc::new(Recorder::default());
+        recorder.fail("nh os boot /etc/nixos -H ridley");
+        let args = OsArgs {
+            host: Some("ridley,kraid".to_string()),
+            ..OsArgs::default()
+        };
+
+        let result = with_executor(recorder.clone(), || {
+            run(
+                OsAction::Boot,
+                &args,
+                Some(Path::new("/etc/nixos")),
+                &Config::default(),
+            )
+        });
+
+        assert_eq!(result.unwrap_err().to_string(), "1 of 2 hosts failed");
+        let mut lines = recorder.command_lines();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "nh os boot /etc/nixos -H kraid --target-host kraid --use-remote-sudo",
+                "nh os boot /etc/nixos -H ridley --target-host ridley --use-remote-sudo",
+                "nix eval --json /etc/nixos#nixosConfigurations --apply builtins.attrNames",
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_rebuild_passes_elevation_program_to_nh() {
+        std::env::remove_var("BONK_BUILD_HOST");
+        std::env::remove_var("BONK_EXTRA_ARGS");
+        let recorder = Arc::new(Recorder::default());
+
+        elevate::set(Elevation::Doas);
+        let result = with_executor(recorder.clone(), || {
+            rebuild(
+                OsAction::Switch,
+                &OsArgs::default(),
+                Some("rune"),
+                "/etc/nixos",
+                &Config::default(),
+            )
+        });
+        elevate::set(Elevation::Auto);
+
+        result.unwrap();
+        assert_eq!(
+            recorder.command_lines(),
+            vec!["nh os switch /etc/nixos -H rune --elevation-program doas"]
+        );
+    }
+
+    fn rebuild_lines(action: OsAction, args: &OsArgs, host: &str) -> Result<Vec<String>> {
+        std::env::remove_var("BONK_BUILD_HOST");
+        std::env::remove_var("BONK_EXTRA_ARGS");
+        let recorder = Arc::new(Recorder::default());
+        with_executor(recorder.clone(), || {
+            rebuild(
+                action,
+                args,
+                Some(host),
+                "/etc/nixos",
+                &Config::parse_for_test(PROFILE),
+            )
+        })?;
+        Ok(recorder.command_lines())
+    }
+
+    #[test]
+    #[serial]
+    fn test_test_action_deploys_like_switch() {
+        let lines = rebuild_lines(OsAction::Test, &OsArgs::default(), "zebes").unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                "nh os test /etc/nixos -H zebes --target-host root@10.0.0.5 \
+                 --build-host builder --extra-substituters 'https://global https://zebes'"
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_specialisation_passed_to_nh() {
+        let args = OsArgs {
+            specialisation: Some("gaming".to_string()),
+            ..OsArgs::default()
+        };
+        let lines = rebuild_lines(OsAction::Boot, &args, "laptop").unwrap();
+        assert_eq!(
+            lines,
+            vec!["nh os boot /etc/nixos -H rune --extra-substituters https://global --specialisation gaming"]
+        );
+
+        let args = OsArgs {
+            no_specialisation: true,
+            ..OsArgs::default()
+        };
+        let lines = rebuild_lines(OsAction::Switch, &args, "laptop").unwrap();
+        assert_eq!(
+            lines,
+            vec!["nh os switch /etc/nixos -H rune --extra-substituters https://global --no-specialisation"]
+        );
+        assert!(rebuild_lines(OsAction::Build, &args, "laptop").is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_retries_build_first_and_never_repeat_activation() {
+        std::env::remove_var("BONK_BUILD_HOST");
+        std::env::remove_var("BONK_EXTRA_ARGS");
+        let recorder = Arc::new(Recorder::default());
+        recorder
+            .fail_once("nh os build", "error: unable to download 'https://x'")
+            .fail_once("nh os switch", "error: unable to download 'https://x'");
+
+        exec::set_retries(2);
+        let result = with_executor(recorder.clone(), || {
+            rebuild(
+                OsAction::Switch,
+                &OsArgs::default(),
+                Some("laptop"),
+                "/etc/nixos",
+                &Config::parse_for_test(PROFILE),
+            )
+        });
+        exec::set_retries(0);
+
+        assert!(result.is_err());
+        let link = temp_link("build", "rune");
+        let build = format!(
+            "nh os build /etc/nixos -H rune --extra-substituters https://global --out-link {}",
+            link.display()
+        );
+        assert_eq!(
+            recorder.command_lines(),
+            vec![
+                build.clone(),
+                build,
+                "nh os switch /etc/nixos -H rune --extra-substituters https://global".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_build_ignores_profile_target_and_rejects_target_flag() {
+        let lines = rebuild_lines(OsAction::Build, &OsArgs::default(), "zebes").unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                "nh os build /etc/nixos -H zebes --build-host builder \
+                 --extra-substituters 'https://global https://zebes'"
+            ]
+        );
+
+        let args = OsArgs {
+            target: true,
+            ..OsArgs::default()
+        };
+        assert!(rebuild_lines(OsAction::Build, &args, "zebes").is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_dry_activate_builds_then_activates() {
+        let lines = rebuild_lines(OsAction::DryActivate, &OsArgs::default(), "rune").unwrap();
+        let link = OsAction::DryActivate.out_link("rune").unwrap();
+        let link = link.display();
+        assert_eq!(
+            lines,
+            vec![
+                format!("nh os build /etc/nixos -H rune --extra-substituters https://global --out-link {}", link),
+                format!("sudo {}/bin/switch-to-configuration dry-activate", link),
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_dry_activate_on_target_copies_first() {
+        let args = OsArgs {
+            target_host: Some("deploy@10.0.0.9".to_string()),
+            build: BuildOptions {
+                local: true,
+                ..BuildOptions::default()
+            },
+            ..OsArgs::default()
+        };
+        let lines = rebuild_lines(OsAction::DryActivate, &args, "rune").unwrap();
+        let link = OsAction::DryActivate.out_link("rune").unwrap();
+        let link = link.display();
+        assert_eq!(
+            lines,
+            vec![
+                format!("nh os build /etc/nixos -H rune --extra-substituters https://global --out-link {}", link),
+                format!("nix copy --to ssh://deploy@10.0.0.9 {}", link),
+                format!(
+                    "ssh deploy@10.0.0.9 sudo {}/bin/switch-to-configuration dry-activate",
+                    link
+                ),
+            ]
+        );
+    }
+
+    /// `ssh <target> readlink ...` output: generations 41 and 42, 42 current
+    /// (so the one to roll back to from a new generation).
+    const REMOTE_GENERATIONS: &str = "system-42-link\n\
+        1717243200 /nix/var/nix/profiles/system-41-link\n\
+        1717500000 /nix/var/nix/profiles/system-42-link\n";
+
+    #[test]
+    #[serial]
+    fn test_failed_check_rolls_back() {
+        std::env::remove_var("BONK_BUILD_HOST");
+        std::env::remove_var("BONK_EXTRA_ARGS");
+        let recorder = Arc::new(Recorder::default());
+        recorder.respond(
+            "ssh -o BatchMode=yes -o ConnectTimeout=10 root@10.0.0.5 'readlink",
+            0,
+            REMOTE_GENERATIONS,
+        );
+        // backup.service had failed before the switch, so only sshd counts.
+        let failed = "ssh -o BatchMode=yes -o ConnectTimeout=10 root@10.0.0.5 systemctl --failed";
+        recorder.respond_once(failed, 0, "backup.service loaded failed failed Backup\n");
+        recorder.respond(
+            failed,
+            0,
+            "backup.service loaded failed failed Backup\n\
+             sshd.service loaded failed failed OpenSSH Daemon\n",
+        );
+        let args = OsArgs {
+            check: true,
+            ..OsArgs::default()
+        };
+
+        let err = with_executor(recorder.clone(), || {
+            rebuild(
+                OsAction::Switch,
+                &args,
+                Some("zebes"),
+                "/etc/nixos",
+                &Config::parse_for_test(PROFILE),
+            )
+        })
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "health checks failed on root@10.0.0.5 (failed systemd units: sshd.service); \
+             rolled back to generation 42"
+        );
+        let lines = recorder.command_lines();
+        assert_eq!(
+            lines[lines.len() - 2..],
+            [
+                "ssh root@10.0.0.5 nix-env -p /nix/var/nix/profiles/system --switch-generation 42",
+                "ssh root@10.0.0.5 /nix/var/nix/profiles/system/bin/switch-to-configuration switch",
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_checks_not_run_for_boot() {
+        let config = Config::parse_for_test(&format!("{}\n[checks]\nenabled = true\n", PROFILE));
+        let recorder = Arc::new(Recorder::default());
+        with_executor(recorder.clone(), || {
+            rebuild(
+                OsAction::Boot,
+                &OsArgs::default(),
+                Some("rune"),
+                "/etc/nixos",
+                &config,
+            )
+        })
+        .unwrap();
+        assert_eq!(recorder.command_lines().len(), 1);
+
+        let args = OsArgs {
+            check: true,
+            ..OsArgs::default()
+        };
+        assert!(rebuild(OsAction::Boot, &args, Some("rune"), "/etc/nixos", &config).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_confirm_timeout_needs_target() {
+        let args = OsArgs {
+            confirm_timeout: Some("90s".to_string()),
+            ..OsArgs::default()
+        };
+        let err = rebuild(
+            OsAction::Switch,
+            &args,
+            Some("rune"),
+            "/etc/nixos",
+            &Config::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("-T/--target-host"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_confirm_timeout_arms_rollback_after_building() {
+        std::env::remove_var("BONK_BUILD_HOST");
+        std::env::remove_var("BONK_EXTRA_ARGS");
+        let recorder = Arc::new(Recorder::default());
+        recorder.respond(
+            "ssh -o BatchMode=yes -o ConnectTimeout=10 root@10.0.0.5 'readlink",
+            0,
+            REMOTE_GENERATIONS,
+        );
+        let system = temp_link("build", "zebes");
+        recorder.fail(&format!("ssh root@10.0.0.5 {}", system.display()));
+        let args = OsArgs {
+            confirm_timeout: Some("2m".to_string()),
+            ..OsArgs::default()
+        };
+
+        let err = with_executor(recorder.clone(), || {
+            rebuild(
+                OsAction::Switch,
+                &args,
+                Some("zebes"),
+                "/etc/nixos",
+                &Config::parse_for_test(PROFILE),
+            )
+        })
+        .unwrap_err();
+
+        // A failed activation leaves the scheduled rollback in place.
+        assert!(err
+            .to_string()
+            .starts_with("root@10.0.0.5 rolls back to generation 42 in"));
+        let system = system.display();
+        let lines = recorder.command_lines();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[0],
+            format!(
+                "nh os build /etc/nixos -H zebes --build-host builder \
+                 --extra-substituters 'https://global https://zebes' --out-link {}",
+                system
+            )
+        );
+        assert_eq!(
+            lines[2],
+            format!("nix copy --to ssh://root@10.0.0.5 {}", system)
+        );
+        assert_eq!(
+            lines[3],
+            format!(
+                "ssh root@10.0.0.5 'systemd-run --unit=bonk-rollback-{} --on-active=120 \
+                 /bin/sh -c \"/run/current-system/sw/bin/nix-env -p /nix/var/nix/profiles/system \
+                 --switch-generation 42 && \
+                 /nix/var/nix/profiles/system/bin/switch-to-configuration switch\"'",
+                std::process::id()
+            )
+        );
+        assert_eq!(
+            lines[4..],
+            [
+                format!(
+                    "ssh root@10.0.0.5 nix-env -p /nix/var/nix/profiles/system --set {}",
+                    system
+                ),
+                format!(
+                    "ssh root@10.0.0.5 {}/bin/switch-to-configuration switch",
+                    system
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_confirm_refuses_to_prompt_after_timeout() {
+        let recorder = Arc::new(Recorder::default());
+        let err = with_executor(recorder.clone(), || {
+            let deadline = Deadline::arm("root@zebes", Some(42), "switch", Duration::ZERO)?;
+            confirm_or_roll_back(OsAction::Switch, Some(42), deadline)
+        })
+        .unwrap_err();
+        assert!(err.to_string().starts_with("--confirm-timeout ran out"));
+        assert_eq!(recorder.command_lines().len(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_build_vm_runs_script() {
+        let lines =
+            rebuild_lines(OsAction::BuildVm { run: true }, &OsArgs::default(), "rune").unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                "nh os build-vm /etc/nixos -H rune --extra-substituters https://global \
+                 --out-link result",
+                "result/bin/run-rune-vm",
+            ]
+        );
+    }
 }
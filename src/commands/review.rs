@@ -0,0 +1,269 @@
+//! `--review` for os rebuilds - shows what a new system changes before it is
+//! activated.
+//!
+//! The new system is built first, then compared against `/run/current-system`
+//! (on the target host for remote deploys) with `nix store diff-closures`.
+//! Bonk summarises the packages added, removed and changed, and the closure
+//! size delta, then asks whether to go ahead. `bonk rollback` shows the same
+//! summary for the generation it rolls back to.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use owo_colors::OwoColorize;
+
+use crate::exec::{self, CommandRunner};
+use crate::output;
+use crate::progress::format_bytes;
+
+/// The running system, compared against.
+const CURRENT_SYSTEM: &str = "/run/current-system";
+
+/// What changed between two system closures.
+#[derive(Debug, Default, PartialEq)]
+struct ClosureDiff {
+    /// `(name, version)` of packages only in the new system.
+    added: Vec<(String, String)>,
+    /// `(name, version)` of packages only in the current system.
+    removed: Vec<(String, String)>,
+    /// `(name, old versions, new versions)`.
+    changed: Vec<(String, String, String)>,
+    /// Packages with the same version whose size changed (rebuilt).
+    resized: usize,
+    /// Change in closure size, in bytes.
+    size_delta: i64,
+}
+
+impl ClosureDiff {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.resized == 0
+    }
+}
+
+/// Build the new system with `build` (which links it at `link`), show how it
+/// differs from the system running on `target` (or this machine), and ask
+/// whether to activate it.
+///
+/// In plan mode nothing is built, so there is nothing to show and the answer
+/// is yes.
+pub fn confirm(build: CommandRunner, link: &Path, target: Option<&str>) -> Result<bool> {
+    output::info("Building the new system for review...");
+    build.nix_log().retryable().run()?;
+
+    // Remote hosts need the store path itself, not the local link.
+    let system = fs::read_link(link).unwrap_or_else(|_| link.to_path_buf());
+    let system = system.display().to_string();
+
+    if let Some(target) = target {
+        output::status(&format!("Copying system to {} for comparison", target));
+        CommandRunner::new("nix")
+            .args(["copy", "--to", &format!("ssh://{}", target), &system])
+            .retryable()
+            .run()?;
+    }
+    show_changes(&system, target)?;
+
+    if exec::planning() {
+        return Ok(true);
+    }
+    Ok(output::confirm("Activate this system?")?)
+}
+
+/// Show how `system` (a path on `target`, or on this machine) differs from
+/// the system running there.
+pub fn show_changes(system: &str, target: Option<&str>) -> Result<()> {
+    let nix = match target {
+        None => CommandRunner::new("nix"),
+        Some(target) => CommandRunner::ssh(target).arg("nix"),
+    };
+    let (diff, _) = nix
+        .args(["--extra-experimental-features", "nix-command"])
+        .args(["store", "diff-closures", CURRENT_SYSTEM, system])
+        .run_output()?;
+
+    if !exec::planning() {
+        print_diff(&parse_diff(&diff), target.unwrap_or("this machine"));
+    }
+    Ok(())
+}
+
+/// Parse the output of `nix store diff-closures`.
+///
+/// Each line is `name: <old versions> → <new versions>, <±size> KiB`, where
+/// either part may be missing and `∅` stands for "not present".
+fn parse_diff(output: &str) -> ClosureDiff {
+    let mut diff = ClosureDiff::default();
+
+    for line in output.lines().map(strip_ansi) {
+        let Some((name, rest)) = line.trim().split_once(": ") else {
+            continue;
+        };
+
+        let (versions, size) = match rest.rsplit_once(", ") {
+            Some((versions, size)) if size.ends_with(" KiB") => (Some(versions), Some(size)),
+            _ if rest.ends_with(" KiB") => (None, Some(rest)),
+            _ => (Some(rest), None),
+        };
+
+        if let Some(kib) = size.and_then(|s| s.trim_end_matches(" KiB").parse::<f64>().ok()) {
+            diff.size_delta += (kib * 1024.0) as i64;
+        }
+
+        let name = name.to_string();
+        match versions.and_then(|v| v.split_once(" → ")) {
+            Some(("∅", new)) => diff.added.push((name, new.to_string())),
+            Some((old, "∅")) => diff.removed.push((name, old.to_string())),
+            Some((old, new)) => diff.changed.push((name, old.to_string(), new.to_string())),
+            None => diff.resized += 1,
+        }
+    }
+
+    diff
+}
+
+/// Remove ANSI colour codes, which nix adds to the size deltas.
+fn strip_ansi(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip to the end of the escape sequence.
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn print_diff(diff: &ClosureDiff, host: &str) {
+    output::header(&format!("Changes on {}", host));
+
+    if diff.is_empty() {
+        output::status("No package changes");
+        return;
+    }
+
+    for (name, old, new) in &diff.changed {
+        output::line(format_args!(
+            "  {} {} {} → {}",
+            "~".yellow(),
+            name,
+            old.dimmed(),
+            new
+        ));
+    }
+    for (name, version) in &diff.added {
+        output::line(format_args!("  {} {} {}", "+".green(), name, version));
+    }
+    for (name, version) in &diff.removed {
+        output::line(format_args!(
+            "  {} {} {}",
+            "-".red(),
+            name,
+            version.dimmed()
+        ));
+    }
+
+    println!();
+    output::kv(
+        "packages",
+        &format!(
+            "{} changed, {} added, {} removed, {} rebuilt",
+            diff.changed.len(),
+            diff.added.len(),
+            diff.removed.len(),
+            diff.resized
+        ),
+    );
+    let sign = if diff.size_delta < 0 { "-" } else { "+" };
+    output::kv(
+        "closure size",
+        &format!(
+            "{}{}",
+            sign,
+            format_bytes(diff.size_delta.unsigned_abs() as f64)
+        ),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{with_executor, Recorder};
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    const DIFF: &str = "\
+firefox: 120.0 → 121.0, \x1b[31;1m+1024.0 KiB\x1b[0m
+hello: ∅ → 2.12.1, \x1b[31;1m+200.0 KiB\x1b[0m
+nano: 7.2 → ∅, \x1b[32;1m-1536.0 KiB\x1b[0m
+systemd: \x1b[31;1m+12.5 KiB\x1b[0m
+linux: 6.6.1, 6.6.1-modules → 6.6.2, 6.6.2-modules
+";
+
+    #[test]
+    fn test_parse_diff() {
+        let diff = parse_diff(DIFF);
+        assert_eq!(
+            diff.changed,
+            vec![
+                (
+                    "firefox".to_string(),
+                    "120.0".to_string(),
+                    "121.0".to_string()
+                ),
+                (
+                    "linux".to_string(),
+                    "6.6.1, 6.6.1-modules".to_string(),
+                    "6.6.2, 6.6.2-modules".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            diff.added,
+            vec![("hello".to_string(), "2.12.1".to_string())]
+        );
+        assert_eq!(diff.removed, vec![("nano".to_string(), "7.2".to_string())]);
+        assert_eq!(diff.resized, 1);
+        assert_eq!(diff.size_delta, (-299.5 * 1024.0) as i64);
+    }
+
+    #[test]
+    fn test_parse_diff_empty() {
+        assert!(parse_diff("").is_empty());
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(strip_ansi("\x1b[31;1m+1.0 KiB\x1b[0m"), "+1.0 KiB");
+    }
+
+    #[test]
+    #[serial]
+    fn test_show_changes_enables_nix_command() {
+        let recorder = Arc::new(Recorder::default());
+        with_executor(recorder.clone(), || {
+            show_changes("/nix/store/abc-nixos-system", None).unwrap();
+            show_changes("/nix/store/abc-nixos-system", Some("zebes")).unwrap();
+        });
+        assert_eq!(
+            recorder.command_lines(),
+            vec![
+                "nix --extra-experimental-features nix-command store diff-closures \
+                 /run/current-system /nix/store/abc-nixos-system",
+                "ssh -o BatchMode=yes -o ConnectTimeout=10 zebes nix \
+                 --extra-experimental-features nix-command store diff-closures \
+                 /run/current-system /nix/store/abc-nixos-system",
+            ]
+        );
+    }
+}
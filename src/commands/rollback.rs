@@ -0,0 +1,302 @@
+//! Rollback command - switches the system profile back to an earlier
+//! generation and activates it.
+//!
+//! Nothing is built: `nix-env --switch-generation` points the system profile
+//! at the chosen generation, then its `switch-to-configuration` activates it
+//! (`switch`) or only makes it the boot default (`boot`). With `-T` or
+//! `--target-host` both steps run on the target over SSH.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::cli::{OsArgs, RollbackArgs};
+use crate::commands::{os, review};
+use crate::config::Config;
+use crate::exec::{self, CommandRunner};
+use crate::generations::{self, Generation, SYSTEM_PROFILE};
+use crate::history::{self, format_timestamp, HostRecord};
+use crate::host::get_hostname;
+use crate::output;
+
+/// Execute the rollback command.
+pub fn run(args: &RollbackArgs, config: &Config) -> Result<()> {
+    let target = if args.host.is_some() || args.target || args.target_host.is_some() {
+        let os_args = OsArgs {
+            target: args.target,
+            target_host: args.target_host.clone(),
+            ..OsArgs::default()
+        };
+        os::deploy_target(&os_args, args.host.as_deref(), config)?
+    } else {
+        None
+    };
+    let target = target.as_deref();
+
+    let host = match &args.host {
+        Some(host) => host.clone(),
+        None => get_hostname().unwrap_or_else(|_| "localhost".to_string()),
+    };
+    history::add_host(HostRecord {
+        host,
+        target: target.map(String::from),
+        build_host: None,
+    });
+
+    let gens = match target {
+        Some(target) => generations::list_remote(target)?,
+        None => generations::list(Path::new(SYSTEM_PROFILE))?,
+    };
+    let to = select(&gens, args.generation, args.to.as_deref())?;
+    let from = gens.iter().find(|g| g.current);
+    let action = if args.boot { "boot" } else { "switch" };
+
+    output::header(&format!("Rollback on {}", target.unwrap_or("this machine")));
+    if let Some(from) = from {
+        output::kv("from", &describe(from));
+    }
+    output::kv("to", &describe(to));
+    output::kv(
+        "action",
+        if args.boot {
+            "boot (make it the boot default, activate on next boot)"
+        } else {
+            "switch (activate now and make it the boot default)"
+        },
+    );
+
+    let path = to.path.display().to_string();
+    review::show_changes(&path, target)?;
+
+    if !args.yes && !exec::planning() {
+        println!();
+        if !output::confirm(&format!("Roll back to generation {}?", to.number))? {
+            output::info("Cancelled.");
+            return Ok(());
+        }
+    }
+
+    activate(Some(to.number), action, target).with_context(|| match from {
+        Some(from) => format!(
+            "the system profile may now point at generation {}; run `bonk rollback {}` to return to generation {}",
+            to.number, from.number, from.number
+        ),
+        None => format!("the system profile may now point at generation {}", to.number),
+    })?;
+
+    output::success(&format!(
+        "Rolled back to generation {} ({})",
+        to.number, action
+    ));
+    Ok(())
+}
+
+/// Point the system profile at generation `number`, if given, then run its
+/// `switch-to-configuration <action>`, on `target` or this machine.
+pub fn activate(number: Option<u64>, action: &str, target: Option<&str>) -> Result<()> {
+    let switch = number.map(|n| {
+        vec![
+            "nix-env".to_string(),
+            "-p".to_string(),
+            SYSTEM_PROFILE.to_string(),
+            "--switch-generation".to_string(),
+            n.to_string(),
+        ]
+    });
+    let activate = vec![
+        format!("{}/bin/switch-to-configuration", SYSTEM_PROFILE),
+        action.to_string(),
+    ];
+
+    for command in switch.into_iter().chain([activate]) {
+        let runner = match target {
+            None => CommandRunner::new(&command[0])
+                .args(&command[1..])
+                .elevated(),
+            Some(target) => CommandRunner::ssh_sudo(target).args(&command),
+        };
+        runner.run()?;
+    }
+    Ok(())
+}
+
+/// The generation to roll back to: the one numbered `generation`, the newest
+/// created by `to`, or else the one before the current generation.
+pub fn select<'a>(
+    gens: &'a [Generation],
+    generation: Option<u64>,
+    to: Option<&str>,
+) -> Result<&'a Generation> {
+    let Some(current) = gens.iter().find(|g| g.current) else {
+        bail!("could not determine the current generation");
+    };
+
+    let chosen = if let Some(number) = generation {
+        gens.iter().find(|g| g.number == number).with_context(|| {
+            format!(
+                "generation {} does not exist (run `bonk generations` to list them)",
+                number
+            )
+        })?
+    } else if let Some(date) = to {
+        let time = generations::parse_date(date).with_context(|| {
+            format!(
+                "invalid date '{}' (expected YYYY-MM-DD or \"YYYY-MM-DD HH:MM\")",
+                date
+            )
+        })?;
+        gens.iter()
+            .rev()
+            .find(|g| g.created <= time)
+            .with_context(|| format!("no generation was created at or before {}", date))?
+    } else {
+        gens.iter()
+            .rev()
+            .find(|g| g.number < current.number)
+            .with_context(|| format!("no generation before the current one ({})", current.number))?
+    };
+
+    if chosen.current {
+        bail!("generation {} is already the current one", chosen.number);
+    }
+    Ok(chosen)
+}
+
+/// e.g. `41 (2024-06-01 12:00, 24.05.1, kernel 6.6.1)`.
+pub fn describe(generation: &Generation) -> String {
+    let mut details = vec![format!("{} UTC", format_timestamp(generation.created))];
+    details.extend(generation.version.clone());
+    details.extend(generation.kernel.as_ref().map(|k| format!("kernel {}", k)));
+    format!("{} ({})", generation.number, details.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{with_executor, Recorder};
+    use serial_test::serial;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn generation(number: u64, created: u64, current: bool) -> Generation {
+        Generation {
+            number,
+            created,
+            path: PathBuf::from(format!("/nix/var/nix/profiles/system-{}-link", number)),
+            version: None,
+            kernel: None,
+            closure_size: None,
+            current,
+            booted: false,
+            boot_entry: false,
+        }
+    }
+
+    fn gens() -> Vec<Generation> {
+        vec![
+            generation(40, 1_717_000_000, false),
+            generation(41, 1_717_243_200, false),
+            generation(43, 1_717_500_000, true),
+        ]
+    }
+
+    fn selected(args: RollbackArgs) -> Result<u64> {
+        select(&gens(), args.generation, args.to.as_deref()).map(|g| g.number)
+    }
+
+    #[test]
+    fn test_select_previous() {
+        assert_eq!(selected(RollbackArgs::default()).unwrap(), 41);
+    }
+
+    #[test]
+    fn test_select_number() {
+        let args = RollbackArgs {
+            generation: Some(40),
+            ..RollbackArgs::default()
+        };
+        assert_eq!(selected(args).unwrap(), 40);
+
+        let args = RollbackArgs {
+            generation: Some(42),
+            ..RollbackArgs::default()
+        };
+        assert!(selected(args)
+            .unwrap_err()
+            .to_string()
+            .contains("generation 42 does not exist"));
+
+        let args = RollbackArgs {
+            generation: Some(43),
+            ..RollbackArgs::default()
+        };
+        assert!(selected(args).is_err());
+    }
+
+    #[test]
+    fn test_select_date() {
+        let to = |date: &str| RollbackArgs {
+            to: Some(date.to_string()),
+            ..RollbackArgs::default()
+        };
+        assert_eq!(selected(to("2024-06-01")).unwrap(), 41);
+        assert_eq!(selected(to("2024-06-01 11:59")).unwrap(), 40);
+        assert!(selected(to("2024-01-01")).is_err());
+        assert!(selected(to("june")).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_rollback_on_target() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.respond(
+            "ssh -o BatchMode=yes -o ConnectTimeout=10 deploy@10.0.0.5 'readlink",
+            0,
+            "system-43-link\n\
+             1717243200 /nix/var/nix/profiles/system-41-link\n\
+             1717500000 /nix/var/nix/profiles/system-43-link\n",
+        );
+        let args = RollbackArgs {
+            boot: true,
+            target_host: Some("deploy@10.0.0.5".to_string()),
+            yes: true,
+            ..RollbackArgs::default()
+        };
+
+        with_executor(recorder.clone(), || run(&args, &Config::default())).unwrap();
+
+        let lines = recorder.command_lines();
+        assert_eq!(
+            lines[1..],
+            [
+                "ssh -o BatchMode=yes -o ConnectTimeout=10 deploy@10.0.0.5 nix --extra-experimental-features nix-command store \
+                 diff-closures /run/current-system /nix/var/nix/profiles/system-41-link",
+                "ssh deploy@10.0.0.5 sudo nix-env -p /nix/var/nix/profiles/system \
+                 --switch-generation 41",
+                "ssh deploy@10.0.0.5 sudo /nix/var/nix/profiles/system/bin/switch-to-configuration boot",
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_failed_activation_says_how_to_undo() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.respond(
+            "ssh -o BatchMode=yes -o ConnectTimeout=10 root@zebes 'readlink",
+            0,
+            "system-43-link\n\
+             1717243200 /nix/var/nix/profiles/system-41-link\n\
+             1717500000 /nix/var/nix/profiles/system-43-link\n",
+        );
+        recorder.fail("ssh root@zebes /nix/var/nix/profiles/system/bin/switch-to-configuration");
+        let args = RollbackArgs {
+            target_host: Some("root@zebes".to_string()),
+            yes: true,
+            ..RollbackArgs::default()
+        };
+
+        let err = with_executor(recorder, || run(&args, &Config::default())).unwrap_err();
+        assert!(format!("{:#}", err).contains("run `bonk rollback 43`"));
+    }
+}
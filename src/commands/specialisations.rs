@@ -0,0 +1,118 @@
+//! Specialisations command - lists the `specialisation`s a host's NixOS
+//! configuration defines, by evaluating it.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use owo_colors::OwoColorize;
+use serde::Serialize;
+
+use crate::cli::SpecialisationsArgs;
+use crate::config::Config;
+use crate::configurations;
+use crate::flake::{resolve_flake_path, FlakeRef};
+use crate::generations;
+use crate::host::get_hostname;
+use crate::output;
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Specialisation {
+    name: String,
+    /// Whether this machine is running it.
+    active: bool,
+}
+
+/// Execute the specialisations command.
+pub fn run(args: &SpecialisationsArgs, flake_path: Option<&Path>, config: &Config) -> Result<()> {
+    let flake = match args.flake {
+        Some(ref flake) => FlakeRef::parse(flake)?,
+        None => resolve_flake_path(flake_path, config)?,
+    };
+    let hostname = get_hostname().ok();
+    let name = match args.host.clone().or(flake.attr.clone()) {
+        Some(name) => name,
+        None => hostname
+            .clone()
+            .context("could not determine hostname; pass -H")?,
+    };
+    let host = config.configuration(&name);
+    configurations::validate(&flake, configurations::NIXOS, std::slice::from_ref(&host))?;
+
+    // Only this machine's running specialisation is known.
+    let active = if hostname.as_deref() == Some(name.as_str()) {
+        generations::active_specialisation()
+    } else {
+        None
+    };
+    let specialisations: Vec<Specialisation> = evaluate(&flake.url, &host)?
+        .into_iter()
+        .map(|name| Specialisation {
+            active: active.as_deref() == Some(name.as_str()),
+            name,
+        })
+        .collect();
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&specialisations)?);
+        return Ok(());
+    }
+
+    output::header(&format!("Specialisations of {}", host));
+    if specialisations.is_empty() {
+        output::info("None defined.");
+        return Ok(());
+    }
+    for specialisation in &specialisations {
+        if specialisation.active {
+            println!("  {} {}", specialisation.name, "(active)".green().bold());
+        } else {
+            println!("  {}", specialisation.name);
+        }
+    }
+    Ok(())
+}
+
+/// Names of the specialisations `nixosConfigurations.<host>` defines.
+fn evaluate(flake: &str, host: &str) -> Result<Vec<String>> {
+    let attr = format!(
+        "{}#nixosConfigurations.{}.config.specialisation",
+        flake, host
+    );
+    configurations::attr_names(&attr)
+        .with_context(|| format!("failed to evaluate the specialisations of {}", host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{with_executor, Recorder};
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    #[test]
+    #[serial]
+    fn test_evaluate_lists_names() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.respond("nix eval", 0, "[\"gaming\",\"low-power\"]\n");
+
+        let names = with_executor(recorder.clone(), || evaluate("/etc/nixos", "zebes")).unwrap();
+        assert_eq!(names, ["gaming", "low-power"]);
+        assert_eq!(
+            recorder.command_lines(),
+            [
+                "nix eval --json /etc/nixos#nixosConfigurations.zebes.config.specialisation \
+              --apply builtins.attrNames"
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_evaluate_failure_names_host() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.fail("nix eval");
+
+        let err = with_executor(recorder, || evaluate("/etc/nixos", "ridley")).unwrap_err();
+        assert!(err.to_string().contains("specialisations of ridley"));
+    }
+}
@@ -3,11 +3,16 @@
 use anyhow::Result;
 
 use crate::cli::store::GcArgs;
+use crate::config::{Config, DEFAULT_GC_KEEP};
 use crate::exec::CommandRunner;
 use crate::output;
 
 /// Execute the store gc command.
-pub fn run(args: &GcArgs) -> Result<()> {
+pub fn run(args: &GcArgs, config: &Config) -> Result<()> {
+    let gc = &config.settings().gc;
+    let keep = args.keep.or(gc.keep).unwrap_or(DEFAULT_GC_KEEP);
+    let older_than = args.older_than.as_ref().or(gc.older_than.as_ref());
+
     if args.dry_run {
         output::info("Dry run: showing what would be garbage collected...");
     } else {
@@ -16,9 +21,9 @@ pub fn run(args: &GcArgs) -> Result<()> {
 
     let mut runner = CommandRunner::new("nh").args(["clean", "all"]);
 
-    runner = runner.args(["--keep", &args.keep.to_string()]);
+    runner = runner.args(["--keep", &keep.to_string()]);
 
-    if let Some(ref duration) = args.older_than {
+    if let Some(duration) = older_than {
         runner = runner.args(["--keep-since", duration]);
     }
 
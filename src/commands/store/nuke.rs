@@ -1,6 +1,5 @@
 //! Store nuke command - aggressive full cleanup.
 
-use std::io::{self, Write};
 use std::path::Path;
 
 use anyhow::Result;
@@ -8,7 +7,8 @@ use anyhow::Result;
 use crate::cli::os::OsArgs;
 use crate::cli::store::NukeArgs;
 use crate::commands::os::OsAction;
-use crate::exec::CommandRunner;
+use crate::config::Config;
+use crate::exec::{self, CommandRunner, Interrupted};
 use crate::output;
 
 /// Two passes ensure transitively-freed store paths are caught.
@@ -22,8 +22,9 @@ const NUKE_PASSES: u32 = 2;
 /// # Errors
 ///
 /// Returns an error if any subprocess fails or if user input cannot be read.
-pub fn run(args: &NukeArgs, flake_path: Option<&Path>) -> Result<()> {
-    if !args.yes {
+pub fn run(args: &NukeArgs, flake_path: Option<&Path>, config: &Config) -> Result<()> {
+    // Plan mode runs nothing, so there is nothing to confirm.
+    if !args.yes && !exec::planning() {
         output::warn("WARNING: This will perform aggressive cleanup:");
         println!("  - Rebuild bootloader entries (drop old generation GC roots)");
         println!("  - Remove ALL old generations (keeps only current)");
@@ -35,13 +36,7 @@ pub fn run(args: &NukeArgs, flake_path: Option<&Path>) -> Result<()> {
         }
         println!();
 
-        print!("Are you sure? [y/N] ");
-        io::stdout().flush()?;
-
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-
-        if !input.trim().eq_ignore_ascii_case("y") {
+        if !output::confirm("Are you sure?")? {
             output::info("Cancelled.");
             return Ok(());
         }
@@ -61,7 +56,17 @@ pub fn run(args: &NukeArgs, flake_path: Option<&Path>) -> Result<()> {
         output::warn("Skipping rebuild -- system may be unbootable until you rebuild manually!");
     } else {
         output::header("Rebuilding boot entries");
-        crate::commands::os::run(OsAction::Boot, &OsArgs::default(), flake_path)?;
+        crate::commands::os::run(OsAction::Boot, &OsArgs::default(), flake_path, config).map_err(
+            |e| {
+                if !e.is::<Interrupted>() {
+                    return e;
+                }
+                e.context(
+                    "interrupted while rebuilding boot entries after cleanup; \
+                     run `bonk boot` before rebooting",
+                )
+            },
+        )?;
     }
 
     output::success("Nuke complete! Store is now clean and optimized.");
@@ -69,31 +74,44 @@ pub fn run(args: &NukeArgs, flake_path: Option<&Path>) -> Result<()> {
     Ok(())
 }
 
+/// What each step of a cleanup pass does.
+const STEPS: [&str; 4] = [
+    "Rebuilding bootloader entries",
+    "Removing old generations",
+    "Garbage collecting store",
+    "Optimizing store",
+];
+
 /// Run a single cleanup pass (boot entries, clean, gc, optimise).
 fn run_cleanup_pass(pass: u32, total: u32) -> Result<()> {
-    output::header(&format!(
-        "  [{pass}/{total}] Step 1/4: Rebuilding bootloader entries"
-    ));
-    CommandRunner::new("sudo")
-        .args(["/run/current-system/bin/switch-to-configuration", "boot"])
-        .run()?;
-
-    output::header(&format!(
-        "  [{pass}/{total}] Step 2/4: Removing old generations"
-    ));
-    CommandRunner::new("nh")
-        .args(["clean", "all", "--keep", "0"])
-        .run()?;
-
-    output::header(&format!(
-        "  [{pass}/{total}] Step 3/4: Garbage collecting store"
-    ));
-    CommandRunner::new("nix-collect-garbage").arg("-d").run()?;
-
-    output::header(&format!("  [{pass}/{total}] Step 4/4: Optimizing store"));
-    CommandRunner::new("nix")
-        .args(["store", "optimise"])
-        .run()?;
+    let commands = [
+        CommandRunner::new("/run/current-system/bin/switch-to-configuration")
+            .arg("boot")
+            .elevated(),
+        CommandRunner::new("nh").args(["clean", "all", "--keep", "0"]),
+        CommandRunner::new("nix-collect-garbage").arg("-d"),
+        CommandRunner::new("nix").args(["store", "optimise"]),
+    ];
+
+    for (index, (label, runner)) in STEPS.iter().zip(commands).enumerate() {
+        let step = index + 1;
+        output::header(&format!(
+            "  [{pass}/{total}] Step {step}/{}: {label}",
+            STEPS.len()
+        ));
+        runner.run().map_err(|e| {
+            if !e.is::<Interrupted>() {
+                return e;
+            }
+            e.context(format!(
+                "interrupted during pass {pass}/{total}, step {step}/{} ({label}). \
+                 The store is consistent, but boot entries may still list \
+                 generations that were removed: run `bonk store nuke` again, \
+                 or at least `bonk boot`, before rebooting",
+                STEPS.len()
+            ))
+        })?;
+    }
 
     Ok(())
 }
@@ -112,6 +130,10 @@ fn remove_result_symlinks() -> Result<()> {
             if name == "result" || name.starts_with("result-") {
                 if let Ok(target) = std::fs::read_link(&path) {
                     if target.starts_with("/nix/store") {
+                        if exec::planning() {
+                            output::status(&format!("Would remove: {}", name));
+                            continue;
+                        }
                         output::status(&format!("Removing: {}", name));
                         std::fs::remove_file(&path)?;
                     }
@@ -122,3 +144,135 @@ fn remove_result_symlinks() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Elevation;
+    use crate::elevate;
+    use crate::exec::{with_executor, Recorder};
+    use crate::host::get_hostname;
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    const PASS: [&str; 4] = [
+        "sudo /run/current-system/bin/switch-to-configuration boot",
+        "nh clean all --keep 0",
+        "nix-collect-garbage -d",
+        "nix store optimise",
+    ];
+
+    fn args(no_rebuild: bool) -> NukeArgs {
+        NukeArgs {
+            yes: true,
+            remove_results: false,
+            no_rebuild,
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_nuke_runs_two_passes_then_rebuilds() {
+        std::env::remove_var("BONK_BUILD_HOST");
+        std::env::remove_var("BONK_EXTRA_ARGS");
+        let recorder = Arc::new(Recorder::default());
+
+        with_executor(recorder.clone(), || {
+            run(
+                &args(false),
+                Some(Path::new("/etc/nixos")),
+                &Config::default(),
+            )
+        })
+        .unwrap();
+
+        let mut expected: Vec<String> = PASS.iter().chain(&PASS).map(|s| s.to_string()).collect();
+        expected.push(
+            "nix eval --json /etc/nixos#nixosConfigurations --apply builtins.attrNames".to_string(),
+        );
+        expected.push(format!(
+            "nh os boot /etc/nixos -H {}",
+            get_hostname().unwrap()
+        ));
+        assert_eq!(recorder.command_lines(), expected);
+    }
+
+    #[test]
+    #[serial]
+    fn test_nuke_stops_at_failed_step() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.fail("nix-collect-garbage");
+
+        let result = with_executor(recorder.clone(), || {
+            run(
+                &args(false),
+                Some(Path::new("/etc/nixos")),
+                &Config::default(),
+            )
+        });
+
+        assert!(result.is_err());
+        assert_eq!(recorder.command_lines(), PASS[..3].to_vec());
+    }
+
+    #[test]
+    #[serial]
+    fn test_nuke_no_rebuild_skips_boot() {
+        let recorder = Arc::new(Recorder::default());
+
+        with_executor(recorder.clone(), || {
+            run(
+                &args(true),
+                Some(Path::new("/etc/nixos")),
+                &Config::default(),
+            )
+        })
+        .unwrap();
+
+        assert_eq!(recorder.command_lines().len(), PASS.len() * 2);
+    }
+
+    #[test]
+    #[serial]
+    fn test_nuke_uses_configured_elevation() {
+        let recorder = Arc::new(Recorder::default());
+
+        elevate::set(Elevation::None);
+        with_executor(recorder.clone(), || {
+            run(
+                &args(true),
+                Some(Path::new("/etc/nixos")),
+                &Config::default(),
+            )
+        })
+        .unwrap();
+        elevate::set(Elevation::Auto);
+
+        assert_eq!(
+            recorder.command_lines()[0],
+            "/run/current-system/bin/switch-to-configuration boot"
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_nuke_interrupt_names_step() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.interrupt("nix-collect-garbage");
+
+        let err = with_executor(recorder.clone(), || {
+            run(
+                &args(false),
+                Some(Path::new("/etc/nixos")),
+                &Config::default(),
+            )
+        })
+        .unwrap_err();
+
+        assert!(err.is::<Interrupted>());
+        assert!(err
+            .to_string()
+            .starts_with("interrupted during pass 1/2, step 3/4 (Garbage collecting store)"));
+        assert_eq!(recorder.command_lines(), PASS[..3].to_vec());
+    }
+}
@@ -19,7 +19,7 @@ pub fn run(args: &RepairArgs) -> Result<()> {
                 .args(&args.paths);
         }
 
-        runner.run()?;
+        runner.retryable().run()?;
         output::success("Store verification complete!");
     } else {
         output::info("Verifying and repairing store...");
@@ -33,7 +33,7 @@ pub fn run(args: &RepairArgs) -> Result<()> {
                 .args(&args.paths);
         }
 
-        runner.run()?;
+        runner.retryable().run()?;
         output::success("Store repair complete!");
     }
 
@@ -3,7 +3,7 @@
 use anyhow::Result;
 
 use crate::cli::TryArgs;
-use crate::exec::CommandRunner;
+use crate::exec::{self, CommandRunner};
 use crate::output;
 
 /// Execute the try command.
@@ -23,6 +23,17 @@ pub fn run(args: &TryArgs) -> Result<()> {
     let pkg_display: Vec<&str> = args.packages.iter().map(String::as_str).collect();
     output::info(&format!("Starting shell with: {}", pkg_display.join(", ")));
 
+    // The shell itself is interactive, so fetch and build the packages first
+    // to show progress and collect timings for that part.
+    if exec::nix_log_enabled() {
+        CommandRunner::new("nix")
+            .args(["build", "--no-link"])
+            .args(&packages)
+            .nix_log()
+            .retryable()
+            .run()?;
+    }
+
     let mut runner = CommandRunner::new("nix").arg("shell");
 
     for pkg in &packages {
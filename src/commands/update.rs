@@ -5,13 +5,18 @@ use std::path::Path;
 use anyhow::Result;
 
 use crate::cli::UpdateArgs;
+use crate::config::Config;
 use crate::exec::CommandRunner;
-use crate::flake::resolve_flake_path;
+use crate::flake::{check_worktree, resolve_flake_path};
+use crate::history;
 use crate::output;
 
 /// Execute the update command.
-pub fn run(args: &UpdateArgs, flake_path: Option<&Path>) -> Result<()> {
-    let flake = resolve_flake_path(flake_path)?;
+pub fn run(args: &UpdateArgs, flake_path: Option<&Path>, config: &Config) -> Result<()> {
+    let flake = resolve_flake_path(flake_path, config)?;
+    history::set_flake(&flake);
+    check_worktree(&flake, args.require_clean, args.add_untracked)?;
+    let flake = flake.url;
 
     if args.inputs.is_empty() {
         output::info("Updating all flake inputs...");
@@ -28,9 +33,39 @@ pub fn run(args: &UpdateArgs, flake_path: Option<&Path>) -> Result<()> {
 
     runner = runner.arg_if(args.commit, "--commit-lock-file");
 
-    runner.run()?;
+    runner.nix_log().retryable().run()?;
 
     output::success("Update complete!");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{with_executor, Recorder};
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    #[test]
+    #[serial]
+    fn test_update_selected_inputs_and_commit() {
+        let recorder = Arc::new(Recorder::default());
+        let args = UpdateArgs {
+            inputs: vec!["nixpkgs".to_string(), "home-manager".to_string()],
+            commit: true,
+            require_clean: false,
+            add_untracked: false,
+        };
+
+        with_executor(recorder.clone(), || {
+            run(&args, Some(Path::new("/etc/nixos")), &Config::default())
+        })
+        .unwrap();
+
+        assert_eq!(
+            recorder.command_lines(),
+            vec!["nix flake update --flake /etc/nixos nixpkgs home-manager --commit-lock-file"]
+        );
+    }
+}
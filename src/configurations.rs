@@ -0,0 +1,379 @@
+//! The flake's `nixosConfigurations` and `homeConfigurations`: listing them
+//! cheaply, checking `-H` against them, and suggesting close matches.
+//!
+//! Listing evaluates only the attribute names, and for local flakes the
+//! result is cached under `~/.cache/bonk` until `flake.lock` or the flake's
+//! sources change, so it is quick enough for shell completion.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::env;
+use crate::exec::{self, CommandRunner};
+use crate::flake::{self, FlakeRef};
+
+/// NixOS system configurations.
+pub const NIXOS: &str = "nixosConfigurations";
+
+/// Standalone Home Manager configurations.
+pub const HOME: &str = "homeConfigurations";
+
+/// How similar a name must be to be suggested, as in clap's suggestions.
+const SUGGESTION_THRESHOLD: f64 = 0.7;
+
+/// A cached listing for one flake.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CacheEntry {
+    fingerprint: String,
+    names: Vec<String>,
+}
+
+/// Names in the flake's `set` of configurations, e.g. [`NIXOS`].
+///
+/// Served from the cache when the flake is unchanged. In plan mode nothing
+/// is evaluated, so only a cached listing is returned (or an empty one).
+pub fn list(flake: &FlakeRef, set: &str) -> Result<Vec<String>> {
+    let dir = local_dir(flake);
+    let fingerprint = dir.as_deref().and_then(fingerprint);
+    let key = dir.as_deref().map(|dir| cache_key(dir, set));
+    let cache = cache_path();
+
+    if let (Some(key), Some(fingerprint), Some(cache)) = (&key, &fingerprint, &cache) {
+        if let Some(entry) = load(cache).remove(key) {
+            if &entry.fingerprint == fingerprint {
+                return Ok(entry.names);
+            }
+        }
+    }
+    if exec::planning() {
+        return Ok(Vec::new());
+    }
+
+    let names = evaluate(&flake.url, set)?;
+    if let (Some(key), Some(fingerprint), Some(cache)) = (key, fingerprint, cache) {
+        let entry = CacheEntry {
+            fingerprint,
+            names: names.clone(),
+        };
+        if let Err(e) = store(&cache, key, entry) {
+            tracing::info!("Could not cache {}: {:#}", set, e);
+        }
+    }
+    Ok(names)
+}
+
+/// The names last listed for the flake's `set`, without evaluating anything
+/// or checking whether the flake changed since. For shell completion, where
+/// a slightly stale list beats waiting on nix; empty until a command such as
+/// `bonk switch` has listed them.
+pub fn cached(flake: &FlakeRef, set: &str) -> Vec<String> {
+    let (Some(dir), Some(cache)) = (local_dir(flake), cache_path()) else {
+        return Vec::new();
+    };
+    load(&cache)
+        .remove(&cache_key(&dir, set))
+        .map(|entry| entry.names)
+        .unwrap_or_default()
+}
+
+/// Check that each of `wanted` is in the flake's `set` of configurations,
+/// failing with suggestions for the first that isn't.
+///
+/// Nothing is checked when the configurations can't be listed; the build
+/// itself then reports the problem.
+pub fn validate(flake: &FlakeRef, set: &str, wanted: &[String]) -> Result<()> {
+    let names = match list(flake, set) {
+        Ok(names) => names,
+        Err(e) => {
+            tracing::info!("Could not list {}: {:#}", set, e);
+            return Ok(());
+        }
+    };
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    match wanted.iter().find(|name| !names.contains(name)) {
+        Some(name) => Err(unknown(name, &flake.url, set, &names)),
+        None => Ok(()),
+    }
+}
+
+/// The error for a configuration `name` missing from the flake's `set`.
+pub fn unknown(name: &str, flake: &str, set: &str, names: &[String]) -> anyhow::Error {
+    let suggestions = suggest(name, names);
+    let hint = if suggestions.is_empty() {
+        format!("it has: {}", names.join(", "))
+    } else {
+        format!("did you mean '{}'?", suggestions.join("', '"))
+    };
+    anyhow::anyhow!("{} has no {}.{}; {}", flake, set, name, hint)
+}
+
+/// Names similar to `name`, most similar first.
+fn suggest<'a>(name: &str, names: &'a [String]) -> Vec<&'a str> {
+    let mut scored: Vec<(f64, &str)> = names
+        .iter()
+        .map(|candidate| (strsim::jaro(name, candidate), candidate.as_str()))
+        .filter(|(score, _)| *score > SUGGESTION_THRESHOLD)
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.into_iter().map(|(_, name)| name).take(3).collect()
+}
+
+/// Evaluate the attribute names of `flake#<set>`.
+fn evaluate(flake: &str, set: &str) -> Result<Vec<String>> {
+    attr_names(&format!("{}#{}", flake, set))
+        .with_context(|| format!("failed to list {} of {}", set, flake))
+}
+
+/// The names of the attribute set at the flake output `attr`
+/// (`<flake>#<path>`), as `nix eval --apply builtins.attrNames` gives them.
+/// Empty in plan mode, which runs nothing.
+pub fn attr_names(attr: &str) -> Result<Vec<String>> {
+    let (json, _) = CommandRunner::new("nix")
+        .args(["eval", "--json", attr, "--apply", "builtins.attrNames"])
+        .show_command(false)
+        .inherit_stdio(false)
+        .run_output()?;
+    if json.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    serde_json::from_str(&json).context("unexpected output from nix eval")
+}
+
+/// A hash of `flake.lock` and the sources of the flake at `dir`, or `None`
+/// if it isn't a flake directory.
+///
+/// In a git checkout the sources are what nix sees: the index (`git ls-files
+/// -s`) plus uncommitted changes to it (`git diff`), so gitignored trees such
+/// as build outputs don't count. Otherwise they are the name, size and
+/// modification time of every file.
+fn fingerprint(dir: &Path) -> Option<String> {
+    if !dir.join("flake.nix").is_file() {
+        return None;
+    }
+
+    let mut hasher = DefaultHasher::new();
+    fs::read(dir.join("flake.lock")).ok().hash(&mut hasher);
+    match flake::git(dir, &["ls-files", "-s"]) {
+        Some(index) => {
+            index.hash(&mut hasher);
+            flake::git(dir, &["diff", "--binary"]).hash(&mut hasher);
+        }
+        None => {
+            let mut files = Vec::new();
+            collect_files(dir, &mut files);
+            files.sort();
+            for (path, size, modified) in files {
+                (path, size, modified).hash(&mut hasher);
+            }
+        }
+    }
+    Some(format!("{:016x}", hasher.finish()))
+}
+
+/// Regular files under `dir`, skipping `.git` and symlinks such as `result`.
+fn collect_files(dir: &Path, files: &mut Vec<(PathBuf, u64, u128)>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let Ok(meta) = entry.path().symlink_metadata() else {
+            continue;
+        };
+        if meta.is_dir() {
+            if entry.file_name() != ".git" {
+                collect_files(&entry.path(), files);
+            }
+        } else if meta.is_file() {
+            let modified = meta
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_nanos());
+            files.push((entry.path(), meta.len(), modified));
+        }
+    }
+}
+
+/// The flake's directory, for local flakes, as cached listings are keyed.
+fn local_dir(flake: &FlakeRef) -> Option<PathBuf> {
+    flake
+        .local_path()
+        .and_then(|path| fs::canonicalize(path).ok())
+}
+
+fn cache_key(dir: &Path, set: &str) -> String {
+    format!("{}#{}", dir.display(), set)
+}
+
+fn cache_path() -> Option<PathBuf> {
+    env::cache_home().map(|dir| dir.join("bonk").join("configurations.json"))
+}
+
+fn load(path: &Path) -> BTreeMap<String, CacheEntry> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn store(path: &Path, key: String, entry: CacheEntry) -> Result<()> {
+    let mut cache = load(path);
+    cache.insert(key, entry);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string(&cache)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{with_executor, Recorder};
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    fn names() -> Vec<String> {
+        ["zebes", "ridley", "kraid", "rune"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    #[test]
+    fn test_suggest_close_matches() {
+        assert_eq!(suggest("zebs", &names()), ["zebes"]);
+        assert_eq!(suggest("ridly", &names()), ["ridley"]);
+        assert!(suggest("norfair", &names()).is_empty());
+    }
+
+    #[test]
+    fn test_unknown_message() {
+        let err = unknown("zebs", "/etc/nixos", NIXOS, &names());
+        assert_eq!(
+            err.to_string(),
+            "/etc/nixos has no nixosConfigurations.zebs; did you mean 'zebes'?"
+        );
+        let err = unknown("norfair", "/etc/nixos", NIXOS, &names());
+        assert!(err
+            .to_string()
+            .ends_with("it has: zebes, ridley, kraid, rune"));
+    }
+
+    #[test]
+    fn test_fingerprint_changes_with_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(fingerprint(dir.path()), None);
+
+        fs::write(dir.path().join("flake.nix"), "{ }").unwrap();
+        fs::create_dir(dir.path().join("hosts")).unwrap();
+        let before = fingerprint(dir.path()).unwrap();
+        assert_eq!(fingerprint(dir.path()).unwrap(), before);
+
+        fs::write(dir.path().join("hosts/zebes.nix"), "{ }").unwrap();
+        assert_ne!(fingerprint(dir.path()).unwrap(), before);
+    }
+
+    #[test]
+    fn test_fingerprint_follows_git_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let git = |args: &[&str]| {
+            flake::git(dir.path(), args).unwrap();
+        };
+        git(&["init", "-q"]);
+        fs::write(dir.path().join("flake.nix"), "{ }").unwrap();
+        fs::write(dir.path().join(".gitignore"), "target/\n").unwrap();
+        git(&["add", "flake.nix", ".gitignore"]);
+        let before = fingerprint(dir.path()).unwrap();
+
+        fs::create_dir(dir.path().join("target")).unwrap();
+        fs::write(dir.path().join("target/out"), "").unwrap();
+        assert_eq!(fingerprint(dir.path()).unwrap(), before);
+
+        fs::write(dir.path().join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+        assert_ne!(fingerprint(dir.path()).unwrap(), before);
+
+        git(&["add", "flake.nix"]);
+        assert_ne!(fingerprint(dir.path()).unwrap(), before);
+    }
+
+    #[test]
+    #[serial]
+    fn test_list_caches_local_flakes() {
+        let flake = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        fs::write(flake.path().join("flake.nix"), "{ }").unwrap();
+        std::env::set_var("XDG_CACHE_HOME", cache.path());
+        let flake_ref = FlakeRef::parse(&flake.path().display().to_string()).unwrap();
+
+        let recorder = Arc::new(Recorder::default());
+        recorder.respond("nix eval", 0, "[\"kraid\",\"zebes\"]");
+        let first = with_executor(recorder.clone(), || list(&flake_ref, NIXOS)).unwrap();
+        let second = with_executor(recorder.clone(), || list(&flake_ref, NIXOS)).unwrap();
+        std::env::remove_var("XDG_CACHE_HOME");
+
+        assert_eq!(first, ["kraid", "zebes"]);
+        assert_eq!(second, first);
+        assert_eq!(recorder.command_lines().len(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_cached_never_evaluates() {
+        let flake = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        fs::write(flake.path().join("flake.nix"), "{ }").unwrap();
+        std::env::set_var("XDG_CACHE_HOME", cache.path());
+        let flake_ref = FlakeRef::parse(&flake.path().display().to_string()).unwrap();
+
+        let recorder = Arc::new(Recorder::default());
+        recorder.respond("nix eval", 0, "[\"kraid\",\"zebes\"]");
+        let (before, after) = with_executor(recorder.clone(), || {
+            let before = cached(&flake_ref, NIXOS);
+            list(&flake_ref, NIXOS).unwrap();
+            // Still served once the flake has changed.
+            fs::write(flake.path().join("zebes.nix"), "{ }").unwrap();
+            (before, cached(&flake_ref, NIXOS))
+        });
+        std::env::remove_var("XDG_CACHE_HOME");
+
+        assert!(before.is_empty());
+        assert_eq!(after, ["kraid", "zebes"]);
+        assert_eq!(recorder.command_lines().len(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_validate_rejects_unknown_host() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.respond("nix eval", 0, "[\"kraid\",\"zebes\"]");
+        let flake = FlakeRef::parse("github:org/cfg").unwrap();
+
+        let result = with_executor(recorder.clone(), || {
+            validate(&flake, NIXOS, &["zebes".to_string(), "zebs".to_string()])
+        });
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("did you mean 'zebes'?"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_validate_skips_when_listing_fails() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.fail("nix eval");
+        let flake = FlakeRef::parse("github:org/cfg").unwrap();
+
+        with_executor(recorder, || validate(&flake, NIXOS, &["zebs".to_string()])).unwrap();
+    }
+}
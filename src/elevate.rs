@@ -0,0 +1,140 @@
+//! Privilege escalation for steps that need root.
+//!
+//! The method comes from `--elevation`, `BONK_ELEVATION` or the `elevation`
+//! config key (default `auto`). `auto` uses the first of sudo, doas and run0
+//! found on PATH. Escalation is skipped entirely when bonk already runs as
+//! root, as it often does in automation.
+
+use std::sync::Mutex;
+
+use crate::cli::Elevation;
+
+/// Programs tried, in order, by `auto`.
+const PROGRAMS: [&str; 3] = ["sudo", "doas", "run0"];
+
+/// How privileged commands are run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Privilege {
+    /// bonk already runs as root.
+    Root,
+    /// Prefix privileged commands with this program.
+    Program(&'static str),
+    /// Escalation is turned off (`none`): run privileged commands as-is.
+    Off,
+}
+
+/// Tells whether bonk runs as root, and whether a program is installed.
+struct Lookup {
+    root: fn() -> bool,
+    exists: fn(&str) -> bool,
+}
+
+#[cfg(not(test))]
+const LOOKUP: Lookup = Lookup {
+    root: || unsafe { libc::geteuid() } == 0,
+    exists: crate::exec::program_exists,
+};
+/// Tests describe commands as an ordinary user with sudo installed, whoever
+/// runs them.
+#[cfg(test)]
+const LOOKUP: Lookup = Lookup {
+    root: || false,
+    exists: |program| program == "sudo",
+};
+
+/// The configured method.
+static METHOD: Mutex<Elevation> = Mutex::new(Elevation::Auto);
+
+/// The method resolved on first use.
+static RESOLVED: Mutex<Option<Privilege>> = Mutex::new(None);
+
+/// Use `method` for privileged commands from now on.
+pub fn set(method: Elevation) {
+    *METHOD.lock().unwrap() = method;
+    *RESOLVED.lock().unwrap() = None;
+}
+
+/// How privileged commands are run in this process.
+pub fn current() -> Privilege {
+    let mut resolved = RESOLVED.lock().unwrap();
+    *resolved
+        .get_or_insert_with(|| resolve(*METHOD.lock().unwrap(), (LOOKUP.root)(), LOOKUP.exists))
+}
+
+/// Resolve `method` for a user who is (or isn't) `root`, given which
+/// programs exist.
+///
+/// `auto` falls back to sudo when none is installed, so the failure names a
+/// program the user can install.
+fn resolve(method: Elevation, root: bool, exists: impl Fn(&str) -> bool) -> Privilege {
+    if root {
+        return Privilege::Root;
+    }
+
+    match method {
+        Elevation::Auto => {
+            Privilege::Program(PROGRAMS.into_iter().find(|p| exists(p)).unwrap_or("sudo"))
+        }
+        Elevation::Sudo => Privilege::Program("sudo"),
+        Elevation::Doas => Privilege::Program("doas"),
+        Elevation::Run0 => Privilege::Program("run0"),
+        Elevation::None => Privilege::Off,
+    }
+}
+
+/// Whether activating on `target` (an SSH address) needs sudo there: true
+/// unless bonk connects as root or escalation is turned off.
+///
+/// Without a `user@`, ssh connects as the local user (barring ssh config).
+pub fn needs_remote_sudo(target: &str) -> bool {
+    if current() == Privilege::Off {
+        return false;
+    }
+    match target.split_once('@') {
+        Some((user, _)) => user != "root",
+        None => current() != Privilege::Root,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    fn test_resolve_skips_escalation_as_root() {
+        assert_eq!(resolve(Elevation::Doas, true, |_| true), Privilege::Root);
+        assert_eq!(resolve(Elevation::Auto, true, |_| true), Privilege::Root);
+    }
+
+    #[test]
+    fn test_resolve_auto_detects_installed_program() {
+        let only_doas = |p: &str| p == "doas";
+        assert_eq!(
+            resolve(Elevation::Auto, false, only_doas),
+            Privilege::Program("doas")
+        );
+        assert_eq!(
+            resolve(Elevation::Auto, false, |_| false),
+            Privilege::Program("sudo")
+        );
+        assert_eq!(
+            resolve(Elevation::Run0, false, only_doas),
+            Privilege::Program("run0")
+        );
+        assert_eq!(resolve(Elevation::None, false, only_doas), Privilege::Off);
+    }
+
+    #[test]
+    #[serial]
+    fn test_needs_remote_sudo() {
+        assert!(needs_remote_sudo("deploy@10.0.0.5"));
+        assert!(!needs_remote_sudo("root@10.0.0.5"));
+        assert!(needs_remote_sudo("zebes"));
+
+        set(Elevation::None);
+        let off = needs_remote_sudo("deploy@10.0.0.5");
+        set(Elevation::Auto);
+        assert!(!off);
+    }
+}
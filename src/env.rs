@@ -5,6 +5,8 @@
 //! | `BONK_FLAKE_PATH` | Default flake path                 |
 //! | `BONK_BUILD_HOST` | Default build host (empty = local) |
 //! | `BONK_EXTRA_ARGS` | Extra args (colon-separated)       |
+//! | `BONK_ELEVATION`  | Privilege escalation method        |
+//! | `BONK_CONFIG`     | User config file override          |
 
 use std::env;
 use std::path::PathBuf;
@@ -36,6 +38,53 @@ pub fn get_extra_args() -> Vec<String> {
         .unwrap_or_default()
 }
 
+/// Get the privilege escalation method from environment.
+pub fn get_elevation() -> Option<String> {
+    env::var("BONK_ELEVATION").ok().filter(|s| !s.is_empty())
+}
+
+/// Get what the shell completion scripts ask for (`BONK_COMPLETE=hosts`).
+pub fn get_completion_request() -> Option<String> {
+    env::var("BONK_COMPLETE").ok().filter(|s| !s.is_empty())
+}
+
+/// Get user config file override from environment.
+pub fn get_config_path() -> Option<PathBuf> {
+    env::var("BONK_CONFIG")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Get the XDG config home (`$XDG_CONFIG_HOME`, falling back to `~/.config`).
+pub fn config_home() -> Option<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+/// Get the XDG state home (`$XDG_STATE_HOME`, falling back to `~/.local/state`).
+pub fn state_home() -> Option<PathBuf> {
+    xdg_dir("XDG_STATE_HOME", ".local/state")
+}
+
+/// Get the XDG cache home (`$XDG_CACHE_HOME`, falling back to `~/.cache`).
+pub fn cache_home() -> Option<PathBuf> {
+    xdg_dir("XDG_CACHE_HOME", ".cache")
+}
+
+/// Resolve an XDG base directory, falling back to a path under `$HOME`.
+fn xdg_dir(var: &str, home_fallback: &str) -> Option<PathBuf> {
+    env::var(var)
+        .ok()
+        .filter(|s| !s.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var("HOME")
+                .ok()
+                .filter(|s| !s.is_empty())
+                .map(|home| PathBuf::from(home).join(home_fallback))
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -55,4 +104,12 @@ mod tests {
         env::remove_var("BONK_EXTRA_ARGS");
         assert!(get_extra_args().is_empty());
     }
+
+    #[test]
+    #[serial]
+    fn test_config_home_prefers_xdg() {
+        env::set_var("XDG_CONFIG_HOME", "/xdg/config");
+        assert_eq!(config_home(), Some(PathBuf::from("/xdg/config")));
+        env::remove_var("XDG_CONFIG_HOME");
+    }
 }
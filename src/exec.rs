@@ -1,17 +1,541 @@
 //! External command execution utilities.
+//!
+//! Commands are built with [`CommandRunner`] and handed to the current
+//! [`Executor`]. Normally that is [`System`], which spawns real processes.
+//! In plan mode (`--plan` / `--explain`) it is a [`Recorder`], which runs
+//! nothing and treats every command as having succeeded with empty output.
+//! Tests install a scripted [`Recorder`] to check the exact commands a
+//! pipeline runs, and to simulate failures at any step.
+//!
+//! Builds, fetches and copies are marked [`CommandRunner::retryable`]: they
+//! honour `--timeout`, and are retried with backoff (`--retries`) when they
+//! fail with a known-transient error such as a substituter download failure.
+//! Steps that activate a system are never retryable, as re-running a failed
+//! activation does more harm than good.
+//! See [`crate::process`] for how children are signalled and timed out.
 
-use std::process::{Command, ExitStatus, Stdio};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
+use owo_colors::OwoColorize;
+use serde::Serialize;
 
+use crate::cli::PlanFormat;
+use crate::elevate::{self, Privilege};
+use crate::history;
 use crate::output;
+use crate::process;
+use crate::progress::{self, format_ms, Timings};
+
+/// Runs the commands built by [`CommandRunner`].
+pub trait Executor: Send + Sync {
+    /// Run a command to completion with its output going to the terminal.
+    ///
+    /// `stdout` is empty; `stderr` holds the tail of what the command wrote
+    /// to stderr when it was watched for transient errors.
+    fn run(&self, cmd: &CommandRunner) -> Result<Output>;
+
+    /// Run a command and capture its output.
+    fn output(&self, cmd: &CommandRunner) -> Result<Output>;
+}
+
+/// Executor used in place of [`System`]; `None` runs commands for real.
+static EXECUTOR: Mutex<Option<Arc<dyn Executor>>> = Mutex::new(None);
+
+/// Recorder installed by plan mode.
+static PLAN: Mutex<Option<Arc<Recorder>>> = Mutex::new(None);
+
+/// Whether displayed commands include the environment they run with.
+static SHOW_ENV: AtomicBool = AtomicBool::new(false);
+
+/// Inherited variables that nix and nh read, shown with `--show-env`.
+const SHOWN_ENV_PREFIXES: &[&str] = &["NIX_", "NH_"];
+
+/// Whether nix commands that support it render bonk's progress display.
+static PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Enable (or disable) the progress display for nix commands.
+pub fn set_progress(enabled: bool) {
+    PROGRESS.store(enabled, Ordering::Relaxed);
+}
+
+/// Number of slowest derivations in the `--timings` report; 0 when off.
+static TIMINGS: AtomicUsize = AtomicUsize::new(0);
+
+/// Timings collected from nix logs during this run.
+static COLLECTED: Mutex<Timings> = Mutex::new(Timings {
+    builds: Vec::new(),
+    downloads: Vec::new(),
+});
+
+/// Collect build timings (reporting the `top` slowest), or stop with `None`.
+pub fn set_timings(top: Option<usize>) {
+    TIMINGS.store(top.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Take the build timings collected so far.
+pub fn take_timings() -> Timings {
+    std::mem::take(&mut *COLLECTED.lock().unwrap())
+}
+
+/// Whether the progress display is enabled and can be drawn.
+pub fn progress_enabled() -> bool {
+    PROGRESS.load(Ordering::Relaxed) && progress::supported()
+}
+
+/// Whether nix logs are parsed, for the progress display or timings.
+pub fn nix_log_enabled() -> bool {
+    progress_enabled() || TIMINGS.load(Ordering::Relaxed) > 0
+}
+
+/// Retries for retryable commands that fail with a transient error.
+static RETRIES: AtomicU32 = AtomicU32::new(0);
+
+/// Timeout for retryable commands that don't set their own.
+static TIMEOUT: Mutex<Option<Duration>> = Mutex::new(None);
+
+/// Delay before the first retry; doubled for each further one.
+#[cfg(not(test))]
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+#[cfg(test)]
+const RETRY_DELAY: Duration = Duration::ZERO;
+
+/// Longest delay between retries.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Lines of stderr kept to recognise transient failures.
+const TAIL_LINES: usize = 50;
+
+/// Error output (lowercased) that means a retry may well succeed: nix
+/// failing to fetch a source or substitute, or to reach a remote store.
+/// Kept to nix's own messages, so that a failure that merely mentions the
+/// network (a service timing out on activation, say) is not retried.
+const TRANSIENT_ERRORS: &[&str] = &[
+    "unable to download",
+    "failed (usually happens due to networking issues)",
+    "fatal: unable to access",
+    "failed to start ssh connection",
+    "cannot open connection to remote store",
+];
+
+/// What sudo prints when it needs a password but has no terminal.
+const SUDO_NO_TTY: &[&str] = &[
+    "sudo: a terminal is required to read the password",
+    "sudo: no tty present and no askpass program specified",
+    "sudo: a password is required",
+];
+
+/// Retry retryable commands up to `retries` times on transient failures.
+pub fn set_retries(retries: u32) {
+    RETRIES.store(retries, Ordering::Relaxed);
+}
+
+/// How many times retryable commands are retried.
+pub fn retries() -> u32 {
+    RETRIES.load(Ordering::Relaxed)
+}
+
+/// Stop retryable commands that run longer than `timeout`.
+pub fn set_timeout(timeout: Option<Duration>) {
+    *TIMEOUT.lock().unwrap() = timeout;
+}
+
+/// A command was stopped because bonk was interrupted (Ctrl-C or SIGTERM).
+#[derive(Debug)]
+pub struct Interrupted {
+    pub command: String,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "interrupted while running '{}'", self.command)
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// A command was stopped for running longer than its timeout.
+#[derive(Debug)]
+pub struct TimedOut {
+    pub command: String,
+    pub after: Duration,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "'{}' timed out after {}",
+            self.command,
+            format_ms(self.after.as_millis() as u64)
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Whether a failed command's stderr points to a transient (network) error.
+fn is_transient(stderr: &[u8]) -> bool {
+    let stderr = String::from_utf8_lossy(stderr).to_lowercase();
+    TRANSIENT_ERRORS
+        .iter()
+        .any(|pattern| stderr.contains(pattern))
+}
+
+/// Whether sudo failed for want of a terminal to ask for a password on, as
+/// it does in multi-host deploys, whose commands get no stdin, or when bonk
+/// itself runs without one.
+fn needs_sudo_password(stderr: &[u8]) -> bool {
+    let stderr = String::from_utf8_lossy(stderr);
+    SUDO_NO_TTY.iter().any(|pattern| stderr.contains(pattern))
+}
+
+/// Whether commands bonk runs can prompt on its terminal: stdin is one, and
+/// no multi-host deploy is tagging their output.
+#[cfg(not(test))]
+fn has_terminal() -> bool {
+    io::stdin().is_terminal() && output::prefix().is_none()
+}
+
+/// Tests describe commands as run without a terminal, wherever they run.
+#[cfg(test)]
+fn has_terminal() -> bool {
+    false
+}
+
+/// Delay before retry number `attempt` (starting at 1).
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_DELAY
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Include (or leave out) the environment when displaying commands.
+pub fn set_show_env(show: bool) {
+    SHOW_ENV.store(show, Ordering::Relaxed);
+}
+
+fn executor() -> Arc<dyn Executor> {
+    EXECUTOR
+        .lock()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| Arc::new(System))
+}
+
+/// Replace the executor for every command run from now on (`None` restores
+/// [`System`]).
+pub fn set_executor(executor: Option<Arc<dyn Executor>>) {
+    *EXECUTOR.lock().unwrap() = executor;
+}
+
+/// Run `f` with `executor` installed, restoring the previous one afterwards
+/// (even if `f` panics).
+#[cfg(test)]
+pub fn with_executor<T>(executor: Arc<dyn Executor>, f: impl FnOnce() -> T) -> T {
+    struct Restore(Option<Arc<dyn Executor>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            set_executor(self.0.take());
+        }
+    }
+
+    let _restore = Restore(EXECUTOR.lock().unwrap().replace(executor));
+    f()
+}
+
+/// An external command recorded instead of run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Environment variables set for the command.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<(String, String)>,
+    /// The full command line as it would be displayed.
+    pub command: String,
+}
+
+/// Start recording commands instead of running them.
+pub fn enable_plan() {
+    let recorder = Arc::new(Recorder::default());
+    set_executor(Some(recorder.clone()));
+    *PLAN.lock().unwrap() = Some(recorder);
+}
+
+/// Whether commands are being recorded instead of run.
+pub fn planning() -> bool {
+    PLAN.lock().unwrap().is_some()
+}
+
+/// Print the commands recorded in plan mode.
+pub fn print_plan(format: PlanFormat) -> Result<()> {
+    let plan = take_plan();
+
+    if format == PlanFormat::Json {
+        let doc = serde_json::json!({ "commands": plan });
+        println!("{}", serde_json::to_string_pretty(&doc)?);
+        return Ok(());
+    }
+
+    output::header("Execution plan (nothing was run)");
+    if plan.is_empty() {
+        output::status("No external commands");
+    }
+    for (index, cmd) in plan.iter().enumerate() {
+        println!("  {:>2}. {}", index + 1, cmd.command);
+    }
+
+    Ok(())
+}
+
+/// Take the commands recorded so far in plan mode.
+pub fn take_plan() -> Vec<PlannedCommand> {
+    PLAN.lock()
+        .unwrap()
+        .as_ref()
+        .map(|recorder| recorder.take())
+        .unwrap_or_default()
+}
+
+/// Spawns real processes.
+pub struct System;
+
+impl Executor for System {
+    fn run(&self, cmd: &CommandRunner) -> Result<Output> {
+        if cmd.show_command {
+            output::show_cmd(&cmd.command_string());
+        }
+
+        let mut command = cmd.command();
+
+        // With an output prefix set (multi-host deploys), tag every line of
+        // the child's output instead of letting it write to the terminal.
+        if let Some(prefix) = output::prefix().filter(|_| cmd.inherit_stdio) {
+            return run_prefixed(cmd, command, &prefix);
+        }
+
+        if cmd.capture_log {
+            return run_with_progress(cmd, command);
+        }
+
+        let watch = cmd.watch_stderr();
+        command
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(if watch {
+                Stdio::piped()
+            } else {
+                Stdio::inherit()
+            });
+
+        let interactive = cmd.inherit_stdio && io::stdin().is_terminal();
+        let mut child = spawn(cmd, &mut command, interactive)?;
+        let stderr = child.take_stderr().map(watch_stderr);
+        let status = wait(cmd, &mut child)?;
+
+        Ok(Output {
+            status,
+            stdout: Vec::new(),
+            stderr: stderr.map(join).unwrap_or_default(),
+        })
+    }
+
+    fn output(&self, cmd: &CommandRunner) -> Result<Output> {
+        if cmd.show_command {
+            output::show_cmd(&cmd.command_string());
+        }
+
+        let mut command = cmd.command();
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let mut child = spawn(cmd, &mut command, false)?;
+        let stdout = child.take_stdout().map(read_all);
+        let stderr = child.take_stderr().map(read_all);
+        let status = wait(cmd, &mut child)?;
+
+        Ok(Output {
+            status,
+            stdout: stdout.map(join).unwrap_or_default(),
+            stderr: stderr.map(join).unwrap_or_default(),
+        })
+    }
+}
+
+/// Records commands instead of running them.
+///
+/// Every command succeeds with empty output unless a scripted response
+/// matches it.
+#[derive(Default)]
+pub struct Recorder {
+    commands: Mutex<Vec<PlannedCommand>>,
+    responses: Mutex<Vec<Response>>,
+}
+
+/// A scripted result for commands whose command line starts with `prefix`.
+struct Response {
+    prefix: String,
+    code: i32,
+    stdout: String,
+    stderr: String,
+    /// Used up by the first command it matches.
+    once: bool,
+    /// Act as if bonk was interrupted while the command ran.
+    interrupt: bool,
+}
+
+impl Recorder {
+    /// Make commands starting with `prefix` exit with `code` and print
+    /// `stdout`. The first matching response wins.
+    #[cfg(test)]
+    pub fn respond(&self, prefix: &str, code: i32, stdout: &str) -> &Self {
+        self.responses.lock().unwrap().push(Response {
+            prefix: prefix.to_string(),
+            code,
+            stdout: stdout.to_string(),
+            stderr: String::new(),
+            once: false,
+            interrupt: false,
+        });
+        self
+    }
+
+    /// Like [`Recorder::respond`], for the next matching command only.
+    #[cfg(test)]
+    pub fn respond_once(&self, prefix: &str, code: i32, stdout: &str) -> &Self {
+        self.responses.lock().unwrap().push(Response {
+            prefix: prefix.to_string(),
+            code,
+            stdout: stdout.to_string(),
+            stderr: String::new(),
+            once: true,
+            interrupt: false,
+        });
+        self
+    }
+
+    /// Make commands starting with `prefix` fail with exit code 1.
+    #[cfg(test)]
+    pub fn fail(&self, prefix: &str) -> &Self {
+        self.respond(prefix, 1, "")
+    }
+
+    /// Make the next command starting with `prefix` fail with exit code 1,
+    /// writing `stderr`.
+    #[cfg(test)]
+    pub fn fail_once(&self, prefix: &str, stderr: &str) -> &Self {
+        self.responses.lock().unwrap().push(Response {
+            prefix: prefix.to_string(),
+            code: 1,
+            stdout: String::new(),
+            stderr: stderr.to_string(),
+            once: true,
+            interrupt: false,
+        });
+        self
+    }
+
+    /// Make commands starting with `prefix` fail as if interrupted by Ctrl-C.
+    #[cfg(test)]
+    pub fn interrupt(&self, prefix: &str) -> &Self {
+        self.responses.lock().unwrap().push(Response {
+            prefix: prefix.to_string(),
+            code: 130,
+            stdout: String::new(),
+            stderr: String::new(),
+            once: false,
+            interrupt: true,
+        });
+        self
+    }
+
+    /// Command lines recorded so far.
+    #[cfg(test)]
+    pub fn command_lines(&self) -> Vec<String> {
+        self.commands
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| c.command.clone())
+            .collect()
+    }
+
+    /// Take the commands recorded so far.
+    pub fn take(&self) -> Vec<PlannedCommand> {
+        std::mem::take(&mut *self.commands.lock().unwrap())
+    }
+
+    fn record(&self, cmd: &CommandRunner) -> Result<Output> {
+        let command = cmd.command_string();
+        let mut responses = self.responses.lock().unwrap();
+        let (code, stdout, stderr, interrupt) = match responses
+            .iter()
+            .position(|r| command.starts_with(&r.prefix))
+        {
+            Some(index) if responses[index].once => {
+                let r = responses.remove(index);
+                (r.code, r.stdout, r.stderr, r.interrupt)
+            }
+            Some(index) => {
+                let r = &responses[index];
+                (r.code, r.stdout.clone(), r.stderr.clone(), r.interrupt)
+            }
+            None => (0, String::new(), String::new(), false),
+        };
+        drop(responses);
+
+        self.commands.lock().unwrap().push(PlannedCommand {
+            program: cmd.program.clone(),
+            args: cmd.args.clone(),
+            env: cmd.env.clone(),
+            command: command.clone(),
+        });
+
+        if interrupt {
+            return Err(Interrupted { command }.into());
+        }
+
+        Ok(Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.into_bytes(),
+            stderr: stderr.into_bytes(),
+        })
+    }
+}
+
+impl Executor for Recorder {
+    fn run(&self, cmd: &CommandRunner) -> Result<Output> {
+        self.record(cmd)
+    }
+
+    fn output(&self, cmd: &CommandRunner) -> Result<Output> {
+        self.record(cmd)
+    }
+}
 
 /// Builder for executing external commands.
 pub struct CommandRunner {
     program: String,
     args: Vec<String>,
+    env: Vec<(String, String)>,
     show_command: bool,
     inherit_stdio: bool,
+    /// Parse nix's internal-json log for the progress display and timings.
+    capture_log: bool,
+    timeout: Option<Duration>,
+    retryable: bool,
 }
 
 impl CommandRunner {
@@ -20,11 +544,36 @@ impl CommandRunner {
         Self {
             program: program.into(),
             args: Vec::new(),
+            env: Vec::new(),
             show_command: true,
             inherit_stdio: true,
+            capture_log: false,
+            timeout: None,
+            retryable: false,
         }
     }
 
+    /// `ssh` to `target`, for commands whose output bonk reads. Those run in
+    /// the background, where ssh can't ask for a password or whether to trust
+    /// a host key, so it fails instead of prompting, and gives up on hosts it
+    /// can't reach.
+    #[must_use]
+    pub fn ssh(target: &str) -> Self {
+        Self::new("ssh").args(["-o", "BatchMode=yes", "-o", "ConnectTimeout=10", target])
+    }
+
+    /// `ssh` to `target`, for a command run as root there: behind `sudo`
+    /// unless bonk connects as root. When bonk has a terminal, ssh gets one
+    /// too (`-t`) so that sudo can ask for a password on it.
+    #[must_use]
+    pub fn ssh_sudo(target: &str) -> Self {
+        let sudo = elevate::needs_remote_sudo(target);
+        Self::new("ssh")
+            .arg_if(sudo && has_terminal(), "-t")
+            .arg(target)
+            .arg_if(sudo, "sudo")
+    }
+
     #[must_use]
     pub fn args<I, S>(mut self, args: I) -> Self
     where
@@ -64,6 +613,67 @@ impl CommandRunner {
         }
     }
 
+    /// Set an environment variable for the command.
+    #[must_use]
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Have nix log as internal-json and parse it, for the progress display
+    /// (`--progress`, when stderr is a terminal) and build timings
+    /// (`--timings`). Otherwise nix's output is shown as-is.
+    ///
+    /// Only use with nix or nh commands that build or fetch. For nh, the
+    /// nix-output-monitor display is turned off and the log format is passed
+    /// through to nix after `--`.
+    #[must_use]
+    pub fn nix_log(mut self) -> Self {
+        if !nix_log_enabled() || output::prefix().is_some() {
+            return self;
+        }
+
+        self.capture_log = true;
+        let log_format = ["--log-format", "internal-json"];
+        if self.program != "nh" {
+            return self.args(log_format);
+        }
+
+        match self.args.iter().position(|a| a == "--") {
+            Some(split) => self.args.insert(split, "--no-nom".to_string()),
+            None => self.args.extend(["--no-nom".to_string(), "--".to_string()]),
+        }
+        self.args(log_format)
+    }
+
+    /// Stop the command (and everything it started) if it runs longer than
+    /// `timeout`.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Mark a build, fetch or copy: it is stopped after `--timeout` (unless it
+    /// has its own), and retried up to `--retries` times when it fails with a
+    /// transient error.
+    #[must_use]
+    pub fn retryable(mut self) -> Self {
+        self.retryable = true;
+        self
+    }
+
+    /// Run the command as root, through the configured escalation program
+    /// (see [`crate::elevate`]).
+    #[must_use]
+    pub fn elevated(mut self) -> Self {
+        if let Privilege::Program(program) = elevate::current() {
+            let program = std::mem::replace(&mut self.program, program.to_string());
+            self.args.insert(0, program);
+        }
+        self
+    }
+
     #[must_use]
     pub fn show_command(mut self, show: bool) -> Self {
         self.show_command = show;
@@ -76,51 +686,91 @@ impl CommandRunner {
         self
     }
 
+    /// The command line, quoted so it can be pasted into a POSIX shell.
+    ///
+    /// With `--show-env`, the environment that shapes the run is prepended
+    /// as `KEY=value` assignments: inherited `NIX_*` and `NH_*` variables,
+    /// then the ones bonk sets for the command.
     fn command_string(&self) -> String {
-        std::iter::once(&self.program)
+        let env = self
+            .shown_env()
+            .into_iter()
+            .map(|(key, value)| format!("{}={}", key, shell_quote(&value)));
+        let argv = std::iter::once(&self.program)
             .chain(self.args.iter())
-            .cloned()
-            .collect::<Vec<_>>()
-            .join(" ")
+            .map(|arg| shell_quote(arg).into_owned());
+
+        env.chain(argv).collect::<Vec<_>>().join(" ")
     }
 
-    pub fn run(self) -> Result<()> {
-        let status = self.run_status()?;
-        if status.success() {
-            Ok(())
-        } else {
-            let code = status.code().unwrap_or(-1);
-            anyhow::bail!("command failed with exit code {}", code)
+    /// Variables shown with `--show-env`.
+    fn shown_env(&self) -> Vec<(String, String)> {
+        if !SHOW_ENV.load(Ordering::Relaxed) {
+            return Vec::new();
         }
+
+        let mut env: Vec<(String, String)> = std::env::vars()
+            .filter(|(key, _)| SHOWN_ENV_PREFIXES.iter().any(|p| key.starts_with(p)))
+            .filter(|(key, _)| !self.env.iter().any(|(set, _)| set == key))
+            .collect();
+        env.sort();
+        env.extend(self.env.iter().cloned());
+        env
     }
 
-    pub fn run_status(self) -> Result<ExitStatus> {
-        if self.show_command {
-            output::show_cmd(&self.command_string());
-        }
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args).envs(self.env.iter().cloned());
+        command
+    }
 
-        let mut cmd = Command::new(&self.program);
-        cmd.args(&self.args);
+    fn effective_timeout(&self) -> Option<Duration> {
+        self.timeout
+            .or_else(|| (*TIMEOUT.lock().unwrap()).filter(|_| self.retryable))
+    }
 
-        if self.inherit_stdio {
-            cmd.stdin(Stdio::inherit())
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit());
+    fn attempts(&self) -> u32 {
+        if self.retryable {
+            retries() + 1
+        } else {
+            1
         }
+    }
 
-        cmd.status()
-            .with_context(|| format!("failed to execute '{}'", self.program))
+    /// Whether stderr is piped through bonk to look for transient errors.
+    fn watch_stderr(&self) -> bool {
+        self.attempts() > 1
     }
 
-    pub fn run_output(self) -> Result<(String, String)> {
-        if self.show_command {
-            output::show_cmd(&self.command_string());
+    /// Add this command to the run history.
+    fn note(&self, status: Option<&ExitStatus>, start: Instant) {
+        history::add_command(
+            self.command_string(),
+            status.and_then(ExitStatus::code),
+            start.elapsed(),
+        );
+    }
+
+    pub fn run(self) -> Result<()> {
+        let output = self.execute(false)?;
+        if output.status.success() {
+            return Ok(());
         }
 
-        let output = Command::new(&self.program)
-            .args(&self.args)
-            .output()
-            .with_context(|| format!("failed to execute '{}'", self.program))?;
+        let code = output.status.code().unwrap_or(-1);
+        if needs_sudo_password(&output.stderr) {
+            anyhow::bail!(
+                "command failed with exit code {}: sudo on the target asked for a password, \
+                 which can't be typed without a terminal, as in a multi-host deploy (use \
+                 passwordless sudo for the deploy user, or deploy as root)",
+                code
+            )
+        }
+        anyhow::bail!("command failed with exit code {}", code)
+    }
+
+    pub fn run_output(self) -> Result<(String, String)> {
+        let output = self.execute(true)?;
 
         if !output.status.success() {
             let code = output.status.code().unwrap_or(-1);
@@ -135,9 +785,208 @@ impl CommandRunner {
 
         Ok((stdout, stderr))
     }
+
+    /// Run the command, retrying transient failures of retryable commands.
+    fn execute(&self, capture: bool) -> Result<Output> {
+        let attempts = self.attempts();
+        let mut attempt = 1;
+
+        loop {
+            let start = Instant::now();
+            let result = if capture {
+                executor().output(self)
+            } else {
+                executor().run(self)
+            };
+            self.note(result.as_ref().ok().map(|o| &o.status), start);
+            let result = result?;
+
+            if result.status.success() || attempt >= attempts || !is_transient(&result.stderr) {
+                return Ok(result);
+            }
+
+            let delay = retry_delay(attempt);
+            attempt += 1;
+            output::warn(&format!(
+                "'{}' hit a transient error; retrying in {} (attempt {} of {})",
+                self.program,
+                format_ms(delay.as_millis() as u64),
+                attempt,
+                attempts
+            ));
+            thread::sleep(delay);
+        }
+    }
+}
+
+fn spawn(cmd: &CommandRunner, command: &mut Command, interactive: bool) -> Result<process::Child> {
+    process::spawn(command, interactive, cmd.effective_timeout().is_some())
+        .with_context(|| format!("failed to execute '{}'", cmd.program))
+}
+
+/// Wait for `child`, turning a timeout or an interruption into an error.
+fn wait(cmd: &CommandRunner, child: &mut process::Child) -> Result<ExitStatus> {
+    let timeout = cmd.effective_timeout();
+    let status = child
+        .wait(timeout)
+        .with_context(|| format!("failed to wait for '{}'", cmd.program))?;
+
+    match status {
+        None => Err(TimedOut {
+            command: cmd.command_string(),
+            after: timeout.unwrap_or_default(),
+        }
+        .into()),
+        Some(_) if child.interrupted() => Err(Interrupted {
+            command: cmd.command_string(),
+        }
+        .into()),
+        Some(status) => Ok(status),
+    }
+}
+
+/// The last [`TAIL_LINES`] lines of a stream.
+#[derive(Default)]
+struct Tail(VecDeque<String>);
+
+impl Tail {
+    fn push(&mut self, line: &str) {
+        if self.0.len() == TAIL_LINES {
+            self.0.pop_front();
+        }
+        self.0.push_back(line.to_string());
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        Vec::from(self.0).join("\n").into_bytes()
+    }
+}
+
+/// Copy a child's stderr to ours, keeping the tail.
+fn watch_stderr(pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut tail = Tail::default();
+        for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+            eprintln!("{}", line);
+            tail.push(&line);
+        }
+        tail.into_bytes()
+    })
+}
+
+fn read_all(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+fn join(handle: thread::JoinHandle<Vec<u8>>) -> Vec<u8> {
+    handle.join().unwrap_or_default()
+}
+
+/// Run `command`, tagging each line of its output with `prefix`.
+fn run_prefixed(cmd: &CommandRunner, mut command: Command, prefix: &str) -> Result<Output> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = spawn(cmd, &mut command, false)?;
+
+    let stdout = child
+        .take_stdout()
+        .map(|out| forward_prefixed(out, prefix, false));
+    let stderr = child
+        .take_stderr()
+        .map(|err| forward_prefixed(err, prefix, true));
+
+    let status = wait(cmd, &mut child)?;
+    if let Some(handle) = stdout {
+        join(handle);
+    }
+
+    Ok(Output {
+        status,
+        stdout: Vec::new(),
+        stderr: stderr.map(join).unwrap_or_default(),
+    })
+}
+
+/// Run `command`, parsing its internal-json stderr for the progress display
+/// and timings.
+fn run_with_progress(cmd: &CommandRunner, mut command: Command) -> Result<Output> {
+    command
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::piped());
+    let interactive = cmd.inherit_stdio && io::stdin().is_terminal();
+    let mut child = spawn(cmd, &mut command, interactive)?;
+
+    // Read stderr on a thread so that `wait` can time the command out.
+    let stderr = child.take_stderr().map(watch_progress);
+    let status = wait(cmd, &mut child)?;
+
+    Ok(Output {
+        status,
+        stdout: Vec::new(),
+        stderr: stderr.map(join).unwrap_or_default(),
+    })
+}
+
+/// Feed a child's internal-json stderr to the progress display, collecting
+/// its timings and keeping the tail.
+fn watch_progress(pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+    let enabled = progress_enabled();
+    thread::spawn(move || {
+        let mut display = progress::Display::new(enabled);
+        let mut tail = Tail::default();
+        for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+            display.line(&line);
+            tail.push(&line);
+        }
+        COLLECTED.lock().unwrap().extend(display.finish());
+        tail.into_bytes()
+    })
+}
+
+/// Quote `arg` for a POSIX shell, leaving it alone when no quoting is needed.
+fn shell_quote(arg: &str) -> Cow<'_, str> {
+    let safe = |(i, c): (usize, char)| {
+        c.is_ascii_alphanumeric()
+            || matches!(c, '_' | '-' | '.' | '/' | ':' | '@' | '%' | '+' | ',' | '=')
+            // `#` only starts a comment at the beginning of a word.
+            || (c == '#' && i > 0)
+    };
+
+    if !arg.is_empty() && arg.char_indices().all(safe) {
+        Cow::Borrowed(arg)
+    } else {
+        Cow::Owned(format!("'{}'", arg.replace('\'', r"'\''")))
+    }
+}
+
+/// Copy lines from a child pipe to our stdout/stderr, tagged with `prefix`.
+fn forward_prefixed(
+    pipe: impl Read + Send + 'static,
+    prefix: &str,
+    to_stderr: bool,
+) -> thread::JoinHandle<Vec<u8>> {
+    let prefix = prefix.magenta().to_string();
+    thread::spawn(move || {
+        let mut tail = Tail::default();
+        for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+            if to_stderr {
+                eprintln!("{} {}", prefix, line);
+            } else {
+                println!("{} {}", prefix, line);
+            }
+            tail.push(&line);
+        }
+        tail.into_bytes()
+    })
 }
 
-#[allow(dead_code)]
 pub fn program_exists(program: &str) -> bool {
     match Command::new("which")
         .arg(program)
@@ -158,6 +1007,7 @@ pub fn program_exists(program: &str) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serial_test::serial;
 
     #[test]
     fn test_command_string() {
@@ -168,6 +1018,95 @@ mod tests {
         assert_eq!(runner.command_string(), "nh os switch . -H rune");
     }
 
+    #[test]
+    fn test_command_string_quotes_args() {
+        let runner = CommandRunner::new("nix").args([
+            "shell",
+            "nixpkgs#python3",
+            "--command",
+            "python",
+            "-c",
+            "print('hi')",
+        ]);
+        assert_eq!(
+            runner.command_string(),
+            r#"nix shell nixpkgs#python3 --command python -c 'print('\''hi'\'')'"#
+        );
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("github:org/cfg#zebes"), "github:org/cfg#zebes");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("#comment"), "'#comment'");
+        assert_eq!(shell_quote("~/nixos"), "'~/nixos'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    #[serial]
+    fn test_command_string_show_env() {
+        let inherited: Vec<(String, String)> = std::env::vars()
+            .filter(|(key, _)| SHOWN_ENV_PREFIXES.iter().any(|p| key.starts_with(p)))
+            .collect();
+        for (key, _) in &inherited {
+            std::env::remove_var(key);
+        }
+        std::env::set_var("NIX_SSHOPTS", "-p 2222");
+        std::env::set_var("NIX_CONFIG", "inherited");
+
+        let runner = CommandRunner::new("nh")
+            .env("NIX_CONFIG", "max-jobs = 0")
+            .arg("os");
+        let hidden = runner.command_string();
+        set_show_env(true);
+        let shown = runner.command_string();
+        set_show_env(false);
+
+        std::env::remove_var("NIX_SSHOPTS");
+        std::env::remove_var("NIX_CONFIG");
+        for (key, value) in inherited {
+            std::env::set_var(key, value);
+        }
+
+        assert_eq!(hidden, "nh os");
+        assert_eq!(
+            shown,
+            "NIX_SSHOPTS='-p 2222' NIX_CONFIG='max-jobs = 0' nh os"
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_nix_log_off_by_default() {
+        set_progress(false);
+        let runner = CommandRunner::new("nix").arg("build").nix_log();
+        assert!(!runner.capture_log);
+        assert_eq!(runner.args, vec!["build"]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_nix_log_for_nh_goes_after_separator() {
+        set_timings(Some(10));
+        let plain = CommandRunner::new("nh").args(["os", "switch"]).nix_log();
+        let extra = CommandRunner::new("nh")
+            .args(["os", "switch", "--", "--impure"])
+            .nix_log();
+        set_timings(None);
+
+        assert!(plain.capture_log);
+        assert_eq!(
+            plain.command_string(),
+            "nh os switch --no-nom -- --log-format internal-json"
+        );
+        assert_eq!(
+            extra.command_string(),
+            "nh os switch --no-nom -- --impure --log-format internal-json"
+        );
+    }
+
     #[test]
     fn test_args_if_true() {
         let runner = CommandRunner::new("test").args_if(true, ["--flag"]);
@@ -184,4 +1123,179 @@ mod tests {
     fn test_program_exists_false() {
         assert!(!program_exists("nonexistent_program_xyz_123"));
     }
+
+    #[test]
+    #[serial]
+    fn test_plan_mode_records_instead_of_running() {
+        enable_plan();
+        CommandRunner::new("nonexistent_program_xyz_123")
+            .arg("--flag")
+            .run()
+            .unwrap();
+        let (stdout, _) = CommandRunner::new("nonexistent_program_xyz_123")
+            .run_output()
+            .unwrap();
+        let plan = take_plan();
+        *PLAN.lock().unwrap() = None;
+        set_executor(None);
+
+        assert!(stdout.is_empty());
+        assert_eq!(plan.len(), 2);
+        assert_eq!(plan[0].program, "nonexistent_program_xyz_123");
+        assert_eq!(plan[0].args, vec!["--flag"]);
+        assert_eq!(plan[0].command, "nonexistent_program_xyz_123 --flag");
+    }
+
+    #[test]
+    #[serial]
+    fn test_recorder_scripted_responses() {
+        let recorder = Arc::new(Recorder::default());
+        recorder
+            .respond("nix eval", 0, "[\"rune\"]")
+            .fail("nix build");
+
+        let (stdout, err) = with_executor(recorder.clone(), || {
+            let (stdout, _) = CommandRunner::new("nix")
+                .args(["eval", "--json"])
+                .run_output()
+                .unwrap();
+            let err = CommandRunner::new("nix").args(["build", "."]).run();
+            (stdout, err)
+        });
+
+        assert_eq!(stdout, "[\"rune\"]");
+        assert!(err.unwrap_err().to_string().contains("exit code 1"));
+        assert_eq!(
+            recorder.command_lines(),
+            vec!["nix eval --json", "nix build ."]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_retryable_retries_transient_failures() {
+        let recorder = Arc::new(Recorder::default());
+        recorder
+            .fail_once("nix flake update", "error: unable to download 'https://x'")
+            .fail_once("nix build", "error: builder for 'hello.drv' failed");
+
+        set_retries(2);
+        let (update, build) = with_executor(recorder.clone(), || {
+            let update = CommandRunner::new("nix")
+                .args(["flake", "update"])
+                .retryable()
+                .run();
+            let build = CommandRunner::new("nix").arg("build").retryable().run();
+            (update, build)
+        });
+        set_retries(0);
+
+        assert!(update.is_ok());
+        assert!(build.is_err());
+        assert_eq!(
+            recorder.command_lines(),
+            vec!["nix flake update", "nix flake update", "nix build"]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_not_retryable_fails_first_time() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.fail_once("nix flake update", "error: unable to download 'https://x'");
+
+        set_retries(2);
+        let result = with_executor(recorder.clone(), || {
+            CommandRunner::new("nix").args(["flake", "update"]).run()
+        });
+        set_retries(0);
+
+        assert!(result.is_err());
+        assert_eq!(recorder.command_lines().len(), 1);
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(b"error: unable to download 'https://cache'"));
+        assert!(is_transient(
+            b"error: some substitutes for the outputs of derivation 'x.drv' \
+              failed (usually happens due to networking issues)"
+        ));
+        assert!(is_transient(
+            b"fatal: unable to access 'https://github.com/org/cfg/': \
+              Could not resolve host: github.com"
+        ));
+        assert!(!is_transient(b"error: attribute 'foo' missing"));
+        assert!(!is_transient(
+            b"Job for nginx.service failed because a timeout was exceeded.\n\
+              warning: error(s) occurred while switching to the new configuration"
+        ));
+        assert!(!is_transient(
+            b"ssh: connect to host db port 22: Connection refused"
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_ssh_sudo() {
+        let line = |target| CommandRunner::ssh_sudo(target).arg("true").command_string();
+        assert_eq!(line("deploy@zebes"), "ssh deploy@zebes sudo true");
+        assert_eq!(line("root@zebes"), "ssh root@zebes true");
+    }
+
+    #[test]
+    #[serial]
+    fn test_sudo_without_terminal_is_explained() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.fail_once(
+            "nh os switch",
+            "sudo: a terminal is required to read the password; either use the -S option",
+        );
+
+        let err = with_executor(recorder, || {
+            CommandRunner::new("nh").args(["os", "switch"]).run()
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("passwordless sudo"));
+    }
+
+    #[test]
+    fn test_retry_delay_is_capped() {
+        assert!(retry_delay(1) <= retry_delay(2));
+        assert!(retry_delay(40) <= MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    #[serial]
+    fn test_timeout_stops_command() {
+        let err = CommandRunner::new("sh")
+            .args(["-c", "sleep 10"])
+            .show_command(false)
+            .timeout(Duration::from_millis(100))
+            .run()
+            .unwrap_err();
+        assert!(err.is::<TimedOut>());
+        assert!(err.to_string().contains("timed out after 0.1s"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_timeout_stops_command_with_nix_log() {
+        set_timings(Some(5));
+        let result = CommandRunner::new("sh")
+            .args(["-c", "sleep 10"])
+            .show_command(false)
+            .nix_log()
+            .timeout(Duration::from_millis(100))
+            .run();
+        set_timings(None);
+        assert!(result.unwrap_err().is::<TimedOut>());
+    }
+
+    #[test]
+    #[serial]
+    fn test_with_executor_restores_previous() {
+        with_executor(Arc::new(Recorder::default()), || {});
+        assert!(EXECUTOR.lock().unwrap().is_none());
+    }
 }
@@ -1,47 +1,561 @@
 //! Flake path resolution.
+//!
+//! A flake may be a local directory or any flake reference nix understands
+//! (`github:org/cfg/release`, `git+ssh://git@host/cfg?ref=prod`, `path:/x`),
+//! optionally followed by `#attribute`.
 
-use std::path::Path;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 use anyhow::{Context, Result};
 
+use crate::config::Config;
 use crate::env;
+use crate::exec::CommandRunner;
+use crate::output;
+
+/// URL schemes accepted in flake references.
+const FLAKE_SCHEMES: &[&str] = &[
+    "path",
+    "git",
+    "git+file",
+    "git+http",
+    "git+https",
+    "git+ssh",
+    "github",
+    "gitlab",
+    "sourcehut",
+    "hg+file",
+    "hg+http",
+    "hg+https",
+    "hg+ssh",
+    "tarball+file",
+    "tarball+http",
+    "tarball+https",
+    "file",
+    "file+file",
+    "file+http",
+    "file+https",
+    "http",
+    "https",
+    "flake",
+];
+
+/// A validated flake reference, split from its optional `#attribute`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlakeRef {
+    /// Flake location: a local path or a `scheme:` URL.
+    pub url: String,
+    /// Attribute after `#`, with any `nixosConfigurations.` prefix removed.
+    pub attr: Option<String>,
+}
+
+impl FlakeRef {
+    /// Parse and normalize a flake reference such as `.#zebes`,
+    /// `~/nixos`, or `github:org/cfg/release#zebes`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error for an unknown URL scheme, a URL with no location,
+    /// a malformed `github:`-style shorthand, or an empty `#attribute`.
+    pub fn parse(input: &str) -> Result<Self> {
+        let input = input.trim();
+        if input.is_empty() {
+            anyhow::bail!("empty flake reference");
+        }
+
+        let (url, attr) = match input.split_once('#') {
+            Some((_, "")) => anyhow::bail!("flake reference '{}' has an empty #attribute", input),
+            Some((url, attr)) => {
+                let attr = attr.strip_prefix("nixosConfigurations.").unwrap_or(attr);
+                (url, Some(attr.to_string()))
+            }
+            None => (input, None),
+        };
+
+        // `#zebes` alone refers to the flake in the current directory.
+        let url = if url.is_empty() { "." } else { url };
+
+        let url = match url_scheme(url) {
+            Some((scheme, rest)) => {
+                validate_url(url, scheme, rest)?;
+                url.to_string()
+            }
+            None => normalize_path(url),
+        };
+
+        Ok(Self { url, attr })
+    }
+
+    /// Local directory this reference points at, if any.
+    pub fn local_path(&self) -> Option<PathBuf> {
+        let path = match url_scheme(&self.url) {
+            None => self.url.as_str(),
+            Some(("path", rest)) | Some(("git+file", rest)) => {
+                let rest = rest.split('?').next().unwrap_or(rest);
+                rest.strip_prefix("//").unwrap_or(rest)
+            }
+            Some(_) => return None,
+        };
+        Some(PathBuf::from(path))
+    }
+}
+
+impl fmt::Display for FlakeRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.attr {
+            Some(attr) => write!(f, "{}#{}", self.url, attr),
+            None => write!(f, "{}", self.url),
+        }
+    }
+}
+
+/// Split `scheme:rest` if `url` starts with a URL scheme.
+///
+/// A `:` only marks a scheme when it comes before any `/`, so relative paths
+/// like `./cfg:old` are still treated as paths.
+fn url_scheme(url: &str) -> Option<(&str, &str)> {
+    let (scheme, rest) = url.split_once(':')?;
+    let valid = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    valid.then_some((scheme, rest))
+}
+
+fn validate_url(url: &str, scheme: &str, rest: &str) -> Result<()> {
+    if !FLAKE_SCHEMES.contains(&scheme) {
+        anyhow::bail!(
+            "unsupported flake reference scheme '{}:' in '{}'. Supported: {}",
+            scheme,
+            url,
+            FLAKE_SCHEMES.join(", ")
+        );
+    }
+
+    let location = rest.split('?').next().unwrap_or_default();
+    if location.trim_start_matches('/').is_empty() {
+        anyhow::bail!("flake reference '{}' is missing a location", url);
+    }
+
+    if matches!(scheme, "github" | "gitlab" | "sourcehut")
+        && location.split('/').filter(|p| !p.is_empty()).count() < 2
+    {
+        anyhow::bail!("'{}' should look like {}:owner/repo[/ref]", url, scheme);
+    }
+
+    Ok(())
+}
+
+/// Expand `~` and drop a trailing slash from a local path.
+fn normalize_path(path: &str) -> String {
+    let expanded = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => match std::env::var("HOME") {
+            Ok(home) => format!("{}{}", home, rest),
+            Err(_) => path.to_string(),
+        },
+        _ => path.to_string(),
+    };
+
+    match expanded.strip_suffix('/') {
+        Some(trimmed) if !trimmed.is_empty() => trimmed.to_string(),
+        _ => expanded,
+    }
+}
+
+/// Where a resolved flake path came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlakeOrigin {
+    /// `-p` / `BONK_FLAKE_PATH`.
+    Explicit,
+    /// `flake.nix` in the current directory.
+    Cwd,
+    /// `flake.nix` in a parent of the current directory.
+    Parent,
+    /// `BONK_FLAKE_PATH` / `FLAKE` environment variable.
+    Env,
+    /// `flake_path` in the user config file.
+    Config,
+}
 
 /// Resolve flake path.
-pub fn resolve_flake_path(explicit_path: Option<&Path>) -> Result<String> {
+///
+/// Checks, in order: the explicit path (`-p` / `BONK_FLAKE_PATH`), the
+/// nearest `flake.nix` at or above the current directory, the `FLAKE`
+/// environment variable, and finally `flake_path` from the config file.
+pub fn resolve_flake_path(explicit_path: Option<&Path>, config: &Config) -> Result<FlakeRef> {
+    locate_flake(explicit_path, config).map(|(flake, _)| flake)
+}
+
+/// Resolve flake path, also reporting which source supplied it.
+pub fn locate_flake(
+    explicit_path: Option<&Path>,
+    config: &Config,
+) -> Result<(FlakeRef, FlakeOrigin)> {
+    let parse = |path: &Path| {
+        FlakeRef::parse(&path.display().to_string())
+            .with_context(|| format!("invalid flake path '{}'", path.display()))
+    };
+
     if let Some(path) = explicit_path {
-        return Ok(path.display().to_string());
+        return Ok((parse(path)?, FlakeOrigin::Explicit));
     }
 
     let current_dir = std::env::current_dir().context("failed to get current directory")?;
     if current_dir.join("flake.nix").exists() {
-        return Ok(".".to_string());
+        return Ok((parse(Path::new("."))?, FlakeOrigin::Cwd));
+    }
+
+    if config.settings().flake_search_parents.unwrap_or(true) {
+        if let Some(root) = find_flake_root(&current_dir) {
+            tracing::info!("Found flake.nix in parent directory: {}", root.display());
+            return Ok((parse(&root)?, FlakeOrigin::Parent));
+        }
     }
 
     if let Some(env_path) = env::get_flake_path() {
-        return Ok(env_path.display().to_string());
+        return Ok((parse(&env_path)?, FlakeOrigin::Env));
+    }
+
+    if let Some(path) = config.flake_path() {
+        return Ok((parse(path)?, FlakeOrigin::Config));
     }
 
     anyhow::bail!(
         "no flake path found. Either:\n\
-         - Run from a directory containing flake.nix\n\
+         - Run from a directory containing flake.nix (or one of its subdirectories)\n\
          - Set BONK_FLAKE_PATH environment variable\n\
+         - Set flake_path in ~/.config/bonk/config.toml\n\
          - Use --flake-path / -p option"
     )
 }
 
+/// Find the nearest directory at or above `start` that contains `flake.nix`.
+///
+/// Like git's search for `.git`, the walk stops at the repository root (a
+/// directory containing `.git`) and never crosses onto another filesystem.
+pub fn find_flake_root(start: &Path) -> Option<PathBuf> {
+    let start_dev = fs::metadata(start).ok()?.dev();
+
+    for dir in start.ancestors() {
+        match fs::metadata(dir) {
+            Ok(meta) if meta.dev() == start_dev => {}
+            _ => break,
+        }
+        if dir.join("flake.nix").is_file() {
+            return Some(dir.to_path_buf());
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+
+    None
+}
+
+/// Revision and dirty state of a flake's git checkout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GitState {
+    pub rev: String,
+    /// Whether there are uncommitted or untracked changes.
+    pub dirty: bool,
+}
+
+/// Inspect the git checkout at `dir`, if it is one.
+pub fn git_state(dir: &Path) -> Option<GitState> {
+    let rev = git(dir, &["rev-parse", "HEAD"])?.trim().to_string();
+    let dirty = !git(dir, &["status", "--porcelain"])?.trim().is_empty();
+    Some(GitState { rev, dirty })
+}
+
+/// Run `git -C <dir> <args>`, returning its output if it succeeds.
+pub fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Files in a git-backed flake that won't evaluate the way they look.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Worktree {
+    /// Untracked `.nix` files, which the flake silently leaves out.
+    pub untracked: Vec<String>,
+    /// Tracked files with uncommitted changes, relative to the flake root.
+    pub modified: Vec<String>,
+}
+
+impl Worktree {
+    pub fn is_clean(&self) -> bool {
+        self.untracked.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// Untracked `.nix` files and uncommitted changes under the flake at `dir`,
+/// or `None` if it isn't in a git checkout.
+pub fn worktree(dir: &Path) -> Option<Worktree> {
+    // Paths listed with `-z`.
+    let paths = |args: &[&str]| {
+        git(dir, args).map(|out| {
+            out.split('\0')
+                .filter(|path| !path.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        })
+    };
+
+    git(dir, &["rev-parse", "--is-inside-work-tree"])?;
+    let untracked = paths(&[
+        "ls-files",
+        "-z",
+        "--others",
+        "--exclude-standard",
+        "--",
+        "*.nix",
+    ])?;
+    // Without a first commit there is nothing to diff against.
+    let modified = paths(&["diff", "HEAD", "--name-only", "-z", "--relative"]).unwrap_or_default();
+    Some(Worktree {
+        untracked,
+        modified,
+    })
+}
+
+/// Before building a local git flake, warn about untracked `.nix` files and
+/// uncommitted changes, or refuse to go on with `require_clean`.
+///
+/// With `add_untracked` the untracked files are `git add`ed instead, so the
+/// flake sees them; never before `require_clean` has passed, so a refused
+/// build leaves the index alone. `path:` flakes copy the whole directory and
+/// aren't checked.
+pub fn check_worktree(flake: &FlakeRef, require_clean: bool, add_untracked: bool) -> Result<()> {
+    if url_scheme(&flake.url).is_some_and(|(scheme, _)| scheme == "path") {
+        return Ok(());
+    }
+    let Some(dir) = flake
+        .local_path()
+        .filter(|dir| dir.join("flake.nix").is_file())
+    else {
+        return Ok(());
+    };
+    let Some(mut tree) = worktree(&dir) else {
+        return Ok(());
+    };
+
+    if require_clean && !tree.is_clean() {
+        let files: Vec<String> = tree
+            .untracked
+            .iter()
+            .map(|path| format!("  {} (untracked)", path))
+            .chain(tree.modified.iter().map(|path| format!("  {}", path)))
+            .collect();
+        anyhow::bail!(
+            "{} has uncommitted changes (--require-clean):\n{}",
+            flake.url,
+            files.join("\n")
+        );
+    }
+
+    if add_untracked && !tree.untracked.is_empty() {
+        CommandRunner::new("git")
+            .args(["-C", &dir.display().to_string(), "add", "--"])
+            .args(&tree.untracked)
+            .run()
+            .context("failed to add untracked files")?;
+        output::info(&format!("Added {}", tree.untracked.join(", ")));
+        tree.modified.append(&mut tree.untracked);
+    }
+
+    if !tree.untracked.is_empty() {
+        output::warn(&format!(
+            "Not tracked by git, so the flake ignores them: {} \
+             (git add them, or pass --add-untracked)",
+            tree.untracked.join(", ")
+        ));
+    }
+    if !tree.modified.is_empty() {
+        output::warn(&format!(
+            "Uncommitted changes: {}",
+            tree.modified.join(", ")
+        ));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::exec::{with_executor, Recorder};
+    use serial_test::serial;
+    use std::sync::Arc;
 
     #[test]
     fn test_resolve_flake_path_explicit() {
-        let result = resolve_flake_path(Some(Path::new("/some/path"))).unwrap();
-        assert_eq!(result, "/some/path");
+        let result = resolve_flake_path(Some(Path::new("/some/path")), &Config::default()).unwrap();
+        assert_eq!(result.to_string(), "/some/path");
     }
 
     #[test]
     fn test_resolve_flake_path_explicit_relative() {
-        let result = resolve_flake_path(Some(Path::new("./relative/path"))).unwrap();
-        assert_eq!(result, "./relative/path");
+        let result =
+            resolve_flake_path(Some(Path::new("./relative/path")), &Config::default()).unwrap();
+        assert_eq!(result.to_string(), "./relative/path");
+    }
+
+    #[test]
+    fn test_locate_flake_reports_origin() {
+        let (_, origin) = locate_flake(Some(Path::new("/explicit")), &Config::default()).unwrap();
+        assert_eq!(origin, FlakeOrigin::Explicit);
+    }
+
+    #[test]
+    fn test_find_flake_root_walks_up() {
+        let root = tempfile::tempdir().unwrap();
+        let nested = root.path().join("hosts/zebes");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(root.path().join("flake.nix"), "{}").unwrap();
+
+        assert_eq!(find_flake_root(&nested), Some(root.path().to_path_buf()));
+    }
+
+    #[test]
+    fn test_find_flake_root_stops_at_git_root() {
+        let outer = tempfile::tempdir().unwrap();
+        let repo = outer.path().join("repo");
+        let nested = repo.join("sub");
+        fs::create_dir_all(&nested).unwrap();
+        fs::create_dir_all(repo.join(".git")).unwrap();
+        fs::write(outer.path().join("flake.nix"), "{}").unwrap();
+
+        assert_eq!(find_flake_root(&nested), None);
+    }
+
+    #[test]
+    fn test_flake_ref_local_with_attr() {
+        let flake = FlakeRef::parse(".#zebes").unwrap();
+        assert_eq!(flake.url, ".");
+        assert_eq!(flake.attr.as_deref(), Some("zebes"));
+        assert_eq!(flake.local_path(), Some(PathBuf::from(".")));
+    }
+
+    #[test]
+    fn test_flake_ref_attr_only() {
+        let flake = FlakeRef::parse("#zebes").unwrap();
+        assert_eq!(flake.url, ".");
+        assert_eq!(flake.attr.as_deref(), Some("zebes"));
+    }
+
+    #[test]
+    fn test_flake_ref_strips_nixos_configurations_prefix() {
+        let flake = FlakeRef::parse(".#nixosConfigurations.zebes").unwrap();
+        assert_eq!(flake.attr.as_deref(), Some("zebes"));
+    }
+
+    #[test]
+    fn test_flake_ref_remote() {
+        let flake = FlakeRef::parse("github:org/cfg/release#zebes").unwrap();
+        assert_eq!(flake.url, "github:org/cfg/release");
+        assert_eq!(flake.attr.as_deref(), Some("zebes"));
+        assert_eq!(flake.local_path(), None);
+
+        let flake = FlakeRef::parse("git+ssh://git@host/cfg?ref=prod").unwrap();
+        assert_eq!(flake.url, "git+ssh://git@host/cfg?ref=prod");
+        assert!(flake.attr.is_none());
+    }
+
+    #[test]
+    fn test_flake_ref_path_scheme_is_local() {
+        let flake = FlakeRef::parse("path:/etc/nixos").unwrap();
+        assert_eq!(flake.local_path(), Some(PathBuf::from("/etc/nixos")));
+    }
+
+    #[test]
+    fn test_flake_ref_rejects_bad_refs() {
+        assert!(FlakeRef::parse("gihub:org/cfg").is_err());
+        assert!(FlakeRef::parse("github:org").is_err());
+        assert!(FlakeRef::parse("git+ssh://").is_err());
+        assert!(FlakeRef::parse(".#").is_err());
+    }
+
+    #[test]
+    fn test_flake_ref_normalizes_trailing_slash() {
+        assert_eq!(FlakeRef::parse("/etc/nixos/").unwrap().url, "/etc/nixos");
+        assert_eq!(FlakeRef::parse("/").unwrap().url, "/");
+    }
+
+    #[test]
+    fn test_flake_ref_colon_after_slash_is_path() {
+        assert_eq!(FlakeRef::parse("./cfg:old").unwrap().url, "./cfg:old");
+    }
+
+    /// A git flake with `flake.nix` committed.
+    fn git_flake() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(dir.path())
+                .args(["-c", "user.name=bonk", "-c", "user.email=bonk@localhost"])
+                .args(args)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {:?}", args);
+        };
+        git(&["init", "-q"]);
+        fs::write(dir.path().join("flake.nix"), "{ }").unwrap();
+        git(&["add", "flake.nix"]);
+        git(&["commit", "-q", "-m", "init"]);
+        dir
+    }
+
+    #[test]
+    fn test_worktree_lists_untracked_nix_and_changes() {
+        let dir = git_flake();
+        assert!(worktree(dir.path()).unwrap().is_clean());
+
+        fs::create_dir(dir.path().join("hosts")).unwrap();
+        fs::write(dir.path().join("hosts/new.nix"), "{ }").unwrap();
+        fs::write(dir.path().join("notes.txt"), "").unwrap();
+        fs::write(dir.path().join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+
+        let tree = worktree(dir.path()).unwrap();
+        assert_eq!(tree.untracked, ["hosts/new.nix"]);
+        assert_eq!(tree.modified, ["flake.nix"]);
+
+        let plain = tempfile::tempdir().unwrap();
+        assert_eq!(worktree(plain.path()), None);
+    }
+
+    #[test]
+    #[serial]
+    fn test_check_worktree_require_clean_and_add_untracked() {
+        let dir = git_flake();
+        fs::write(dir.path().join("new.nix"), "{ }").unwrap();
+        let flake = FlakeRef::parse(&dir.path().display().to_string()).unwrap();
+
+        let err = check_worktree(&flake, true, false).unwrap_err();
+        assert!(err.to_string().contains("new.nix (untracked)"));
+        check_worktree(&flake, false, false).unwrap();
+
+        let recorder = Arc::new(Recorder::default());
+        with_executor(recorder.clone(), || check_worktree(&flake, false, true)).unwrap();
+        assert_eq!(
+            recorder.command_lines(),
+            [format!("git -C {} add -- new.nix", dir.path().display())]
+        );
+
+        // A refused build adds nothing.
+        let recorder = Arc::new(Recorder::default());
+        let result = with_executor(recorder.clone(), || check_worktree(&flake, true, true));
+        assert!(result.is_err());
+        assert!(recorder.command_lines().is_empty());
     }
 }
@@ -0,0 +1,512 @@
+//! Nix profile generations.
+//!
+//! A profile such as `/nix/var/nix/profiles/system` is a symlink to its
+//! current generation, `system-<N>-link`, which sits next to it and points at
+//! a store path. Each generation's creation date is the mtime of its link,
+//! as with `nix-env --list-generations`.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+
+use crate::env;
+use crate::exec::CommandRunner;
+
+/// The NixOS system profile.
+pub const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+/// The system the machine booted into.
+const BOOTED_SYSTEM: &str = "/run/booted-system";
+
+/// The system that is running now.
+const CURRENT_SYSTEM: &str = "/run/current-system";
+
+/// Names the active specialisation on systems that set
+/// `environment.etc.specialisation.text`, as nh suggests.
+const SPECIALISATION_FILE: &str = "/etc/specialisation";
+
+/// Where systemd-boot entries live, depending on where the ESP is mounted.
+const LOADER_ENTRIES: [&str; 2] = ["/boot/loader/entries", "/efi/loader/entries"];
+
+/// GRUB's generated config.
+const GRUB_CONFIG: &str = "/boot/grub/grub.cfg";
+
+/// One generation of a profile.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Generation {
+    pub number: u64,
+    /// Creation time, in seconds since the Unix epoch.
+    pub created: u64,
+    /// Store path the generation points at.
+    pub path: PathBuf,
+    /// NixOS version (system profiles) or Home Manager version, if recorded.
+    pub version: Option<String>,
+    pub kernel: Option<String>,
+    /// Closure size in bytes, when it has been looked up.
+    pub closure_size: Option<u64>,
+    /// The generation the profile points at.
+    pub current: bool,
+    /// The generation the machine booted into.
+    pub booted: bool,
+    /// Whether the bootloader has an entry for the generation.
+    pub boot_entry: bool,
+}
+
+/// Resolve a `--profile` name to a profile path.
+///
+/// `system`, `home` (Home Manager) and `user` (the per-user `nix profile`)
+/// are looked up in the usual places; anything else is taken as a path.
+pub fn profile_path(name: &str) -> Result<PathBuf> {
+    let candidates = match name {
+        "system" => return Ok(PathBuf::from(SYSTEM_PROFILE)),
+        "home" | "home-manager" => user_profiles("home-manager"),
+        "user" => user_profiles("profile"),
+        path => return Ok(PathBuf::from(path)),
+    };
+
+    if candidates.is_empty() {
+        bail!("could not determine profile directory (set HOME or XDG_STATE_HOME)");
+    }
+    match candidates.iter().find(|p| p.symlink_metadata().is_ok()) {
+        Some(path) => Ok(path.clone()),
+        None => bail!(
+            "no {} profile found (looked for {})",
+            name,
+            candidates
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Where a per-user profile called `name` may live: the XDG location newer
+/// nix versions use, then the legacy per-user directory.
+fn user_profiles(name: &str) -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    if let Some(state) = env::state_home() {
+        candidates.push(state.join("nix/profiles").join(name));
+    }
+    if let Ok(user) = std::env::var("USER") {
+        candidates.push(
+            Path::new("/nix/var/nix/profiles/per-user")
+                .join(user)
+                .join(name),
+        );
+    }
+    candidates
+}
+
+/// List the generations of `profile`, oldest first.
+///
+/// Booted and bootloader markers are only looked up for the system profile.
+pub fn list(profile: &Path) -> Result<Vec<Generation>> {
+    let (booted, entries) = if profile == Path::new(SYSTEM_PROFILE) {
+        let booted = fs::read_link(BOOTED_SYSTEM).ok();
+        let mut entries = loader_entries(LOADER_ENTRIES.iter().map(Path::new));
+        if let Ok(grub) = fs::read_to_string(GRUB_CONFIG) {
+            entries.extend(grub_entries(&grub));
+        }
+        (booted, entries)
+    } else {
+        (None, BTreeSet::new())
+    };
+
+    scan(profile, booted.as_deref(), &entries)
+}
+
+/// List the system generations on `target` over SSH, oldest first.
+///
+/// Only the number, creation date and current marker are known; `path` is
+/// the generation's link on the target.
+pub fn list_remote(target: &str) -> Result<Vec<Generation>> {
+    let script = format!("readlink {0}; stat -c '%Y %n' {0}-*-link", SYSTEM_PROFILE);
+    let (listing, _) = CommandRunner::ssh(target)
+        .arg(&script)
+        .show_command(false)
+        .run_output()
+        .with_context(|| format!("failed to list generations on {}", target))?;
+    Ok(parse_remote(&listing))
+}
+
+/// The current system generation on `target`, or on this machine.
+pub fn current(target: Option<&str>) -> Result<Option<u64>> {
+    let gens = match target {
+        Some(target) => list_remote(target)?,
+        None => list(Path::new(SYSTEM_PROFILE))?,
+    };
+    Ok(gens.iter().find(|g| g.current).map(|g| g.number))
+}
+
+/// The specialisation this machine is running, if any.
+///
+/// Found by matching the running system against the system profile's
+/// `specialisation/<name>` links, falling back to `/etc/specialisation`.
+pub fn active_specialisation() -> Option<String> {
+    specialisation_of(Path::new(SYSTEM_PROFILE), Path::new(CURRENT_SYSTEM)).or_else(|| {
+        fs::read_to_string(SPECIALISATION_FILE)
+            .ok()
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+    })
+}
+
+/// The specialisation of `system` that `running` resolves to, if any.
+fn specialisation_of(system: &Path, running: &Path) -> Option<String> {
+    let running = fs::canonicalize(running).ok()?;
+    fs::read_dir(system.join("specialisation"))
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find(|entry| fs::canonicalize(entry.path()).ok().as_ref() == Some(&running))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+}
+
+/// Parse the `readlink` line and `<mtime> <link>` lines from [`list_remote`].
+fn parse_remote(listing: &str) -> Vec<Generation> {
+    let mut lines = listing.lines();
+    let current = lines
+        .next()
+        .and_then(|link| generation_number("system", link.trim()));
+
+    let mut generations: Vec<Generation> = lines
+        .filter_map(|line| {
+            let (created, link) = line.trim().split_once(' ')?;
+            let name = Path::new(link).file_name()?.to_string_lossy();
+            let number = generation_number("system", &name)?;
+            Some(Generation {
+                number,
+                created: created.parse().ok()?,
+                path: PathBuf::from(link),
+                version: None,
+                kernel: None,
+                closure_size: None,
+                current: current == Some(number),
+                booted: false,
+                boot_entry: false,
+            })
+        })
+        .collect();
+    generations.sort_by_key(|g| g.number);
+    generations
+}
+
+/// List the generations of `profile`, marking the one whose store path is
+/// `booted` and those numbered in `entries`.
+fn scan(profile: &Path, booted: Option<&Path>, entries: &BTreeSet<u64>) -> Result<Vec<Generation>> {
+    let dir = profile
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let name = profile
+        .file_name()
+        .with_context(|| format!("invalid profile path {}", profile.display()))?
+        .to_string_lossy();
+
+    let current = fs::read_link(profile)
+        .ok()
+        .and_then(|link| generation_number(&name, &link.file_name()?.to_string_lossy()));
+
+    let links = fs::read_dir(dir)
+        .with_context(|| format!("failed to read profile directory {}", dir.display()))?;
+
+    let mut generations = Vec::new();
+    for link in links.filter_map(Result::ok) {
+        let Some(number) = generation_number(&name, &link.file_name().to_string_lossy()) else {
+            continue;
+        };
+        let link = link.path();
+        let Ok(target) = fs::read_link(&link) else {
+            continue;
+        };
+        let path = if target.is_relative() {
+            dir.join(target)
+        } else {
+            target
+        };
+
+        let created = link
+            .symlink_metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+
+        generations.push(Generation {
+            number,
+            created,
+            version: read_version(&path),
+            kernel: kernel_version(&path),
+            closure_size: None,
+            current: current == Some(number),
+            booted: booted == Some(path.as_path()),
+            boot_entry: entries.contains(&number),
+            path,
+        });
+    }
+
+    generations.sort_by_key(|g| g.number);
+    Ok(generations)
+}
+
+/// The number in `<profile>-<N>-link`.
+fn generation_number(profile: &str, link: &str) -> Option<u64> {
+    link.strip_prefix(profile)?
+        .strip_prefix('-')?
+        .strip_suffix("-link")?
+        .parse()
+        .ok()
+}
+
+fn read_version(path: &Path) -> Option<String> {
+    ["nixos-version", "hm-version"].iter().find_map(|file| {
+        fs::read_to_string(path.join(file))
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    })
+}
+
+/// Kernel version of a system, from its module directory or failing that
+/// the kernel's store path (`...-linux-<version>/bzImage`).
+fn kernel_version(path: &Path) -> Option<String> {
+    let modules = fs::read_dir(path.join("kernel-modules/lib/modules")).ok();
+    if let Some(version) = modules
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .next()
+    {
+        return Some(version);
+    }
+
+    let kernel = fs::read_link(path.join("kernel")).ok()?;
+    let package = kernel.parent()?.file_name()?.to_string_lossy().into_owned();
+    package
+        .split_once("-linux-")
+        .map(|(_, version)| version.to_string())
+}
+
+/// Parse a UTC date, `YYYY-MM-DD` or `YYYY-MM-DD HH:MM`, as seconds since the
+/// Unix epoch. A bare date means the end of that day.
+pub fn parse_date(date: &str) -> Option<u64> {
+    let (day, time) = match date.trim().split_once([' ', 'T']) {
+        Some((day, time)) => (day, Some(time)),
+        None => (date.trim(), None),
+    };
+
+    let mut parts = day.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let seconds = match time {
+        Some(time) => {
+            let (hour, minute) = time.split_once(':')?;
+            let (hour, minute) = (hour.parse::<i64>().ok()?, minute.parse::<i64>().ok()?);
+            if !(0..24).contains(&hour) || !(0..60).contains(&minute) {
+                return None;
+            }
+            hour * 3_600 + minute * 60
+        }
+        None => 86_399,
+    };
+
+    // Days since 1970-01-01 from a civil date (Howard Hinnant's algorithm).
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    u64::try_from(days * 86_400 + seconds).ok()
+}
+
+/// Generation numbers with a systemd-boot entry (`nixos-generation-<N>.conf`,
+/// or `nixos-generation-<N>-specialisation-<name>.conf`) in `dirs`.
+fn loader_entries<'a>(dirs: impl Iterator<Item = &'a Path>) -> BTreeSet<u64> {
+    dirs.filter_map(|dir| fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let rest = name
+                .strip_prefix("nixos-generation-")?
+                .strip_suffix(".conf")?;
+            let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse().ok()
+        })
+        .collect()
+}
+
+/// Generation numbers with a GRUB menu entry
+/// (`menuentry "NixOS - Configuration <N> (...)"`).
+fn grub_entries(config: &str) -> BTreeSet<u64> {
+    config
+        .lines()
+        .filter(|line| line.trim_start().starts_with("menuentry"))
+        .filter_map(|line| {
+            let rest = &line[line.find("Configuration ")? + "Configuration ".len()..];
+            let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse().ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// A profile directory with generations 1-3 of `system`, the current
+    /// being 2. Returns the store paths of each generation.
+    fn fake_profile(root: &Path) -> Vec<PathBuf> {
+        let profiles = root.join("profiles");
+        fs::create_dir_all(&profiles).unwrap();
+
+        let mut stores = Vec::new();
+        for (n, kernel) in [(1, "6.6.1"), (2, "6.6.2"), (3, "6.6.3")] {
+            let store = root.join(format!("store/gen{}", n));
+            fs::create_dir_all(store.join("kernel-modules/lib/modules").join(kernel)).unwrap();
+            fs::write(store.join("nixos-version"), format!("24.05.{}\n", n)).unwrap();
+            symlink(&store, profiles.join(format!("system-{}-link", n))).unwrap();
+            stores.push(store);
+        }
+        symlink("system-2-link", profiles.join("system")).unwrap();
+        // Other profiles in the same directory are ignored.
+        symlink(&stores[0], profiles.join("system-other-1-link")).unwrap();
+        stores
+    }
+
+    #[test]
+    fn test_specialisation_of() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = fake_profile(dir.path());
+        let gaming = dir.path().join("store/gaming");
+        fs::create_dir_all(&gaming).unwrap();
+        fs::create_dir_all(stores[1].join("specialisation")).unwrap();
+        symlink(&gaming, stores[1].join("specialisation/gaming")).unwrap();
+        let running = dir.path().join("current-system");
+        let system = dir.path().join("profiles/system");
+
+        symlink(&gaming, &running).unwrap();
+        assert_eq!(
+            specialisation_of(&system, &running).as_deref(),
+            Some("gaming")
+        );
+
+        fs::remove_file(&running).unwrap();
+        symlink(&stores[1], &running).unwrap();
+        assert_eq!(specialisation_of(&system, &running), None);
+    }
+
+    #[test]
+    fn test_scan_lists_generations() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = fake_profile(dir.path());
+        let profile = dir.path().join("profiles/system");
+
+        let gens = scan(&profile, Some(&stores[0]), &BTreeSet::from([2, 3])).unwrap();
+        assert_eq!(
+            gens.iter().map(|g| g.number).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(gens[1].path, stores[1]);
+        assert_eq!(gens[1].version.as_deref(), Some("24.05.2"));
+        assert_eq!(gens[2].kernel.as_deref(), Some("6.6.3"));
+        assert!(gens[1].current && !gens[0].current && !gens[2].current);
+        assert!(gens[0].booted && !gens[1].booted);
+        assert!(!gens[0].boot_entry && gens[1].boot_entry && gens[2].boot_entry);
+        assert!(gens[0].created > 0);
+    }
+
+    #[test]
+    fn test_parse_remote() {
+        let listing = "system-3-link
+1717243200 /nix/var/nix/profiles/system-2-link
+1717329600 /nix/var/nix/profiles/system-3-link
+";
+        let gens = parse_remote(listing);
+        assert_eq!(gens.len(), 2);
+        assert_eq!(gens[0].number, 2);
+        assert_eq!(gens[0].created, 1717243200);
+        assert_eq!(
+            gens[0].path,
+            PathBuf::from("/nix/var/nix/profiles/system-2-link")
+        );
+        assert!(!gens[0].current && gens[1].current);
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("2024-06-01 12:00"), Some(1717243200));
+        assert_eq!(parse_date("2024-06-01"), Some(1717286399));
+        assert_eq!(parse_date("1970-01-01 00:00"), Some(0));
+        assert_eq!(
+            parse_date("2024-06-01 12:00").map(crate::history::format_timestamp),
+            Some("2024-06-01 12:00".to_string())
+        );
+        assert_eq!(parse_date("2024-13-01"), None);
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_kernel_version_from_kernel_link() {
+        let dir = tempfile::tempdir().unwrap();
+        symlink(
+            "/nix/store/abc123-linux-6.1.90/bzImage",
+            dir.path().join("kernel"),
+        )
+        .unwrap();
+        assert_eq!(kernel_version(dir.path()).as_deref(), Some("6.1.90"));
+    }
+
+    #[test]
+    fn test_generation_number() {
+        assert_eq!(generation_number("system", "system-42-link"), Some(42));
+        assert_eq!(generation_number("system", "system-other-4-link"), None);
+        assert_eq!(generation_number("system", "system"), None);
+        assert_eq!(
+            generation_number("home-manager", "home-manager-7-link"),
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn test_loader_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "nixos-generation-12.conf",
+            "nixos-generation-13-specialisation-gaming.conf",
+            "other.conf",
+        ] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        let missing = dir.path().join("missing");
+        let entries = loader_entries([dir.path(), missing.as_path()].into_iter());
+        assert_eq!(entries, BTreeSet::from([12, 13]));
+    }
+
+    #[test]
+    fn test_grub_entries() {
+        let config = r#"
+menuentry "NixOS - Default" {
+}
+submenu "NixOS - All configurations" {
+menuentry "NixOS - Configuration 41 (2024-06-01 - 24.05.1)" --class nixos {
+}
+  menuentry "NixOS - Configuration 40 (2024-05-20 - 24.05.0)" --class nixos {
+}
+}
+"#;
+        assert_eq!(grub_entries(config), BTreeSet::from([40, 41]));
+    }
+}
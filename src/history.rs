@@ -0,0 +1,451 @@
+//! Run history.
+//!
+//! Every invocation (except `bonk history` itself and `--plan` runs) is
+//! appended as one JSON line to `$XDG_STATE_HOME/bonk/history.jsonl`. While a
+//! run is in progress, commands note what they resolved (flake, hosts) and
+//! [`CommandRunner`](crate::exec::CommandRunner) notes every external command
+//! it ran; [`finish`] writes the entry out. Once the file passes 8 MiB, the
+//! oldest half of it is dropped.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::env;
+use crate::flake::{git_state, FlakeRef};
+use crate::progress::Timings;
+
+/// Size past which the history file is trimmed to its newest half.
+const MAX_HISTORY_BYTES: u64 = 8 << 20;
+
+/// The run being recorded, if any.
+static CURRENT: Mutex<Option<Active>> = Mutex::new(None);
+
+struct Active {
+    entry: Entry,
+    start: Instant,
+}
+
+/// One bonk invocation.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: u64,
+    /// Start time, in seconds since the Unix epoch.
+    pub started: u64,
+    pub duration_ms: u64,
+    /// Subcommand name, e.g. `switch` or `store gc`.
+    pub subcommand: String,
+    /// Arguments as given on the command line.
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flake: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flake_rev: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flake_dirty: Option<bool>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<HostRecord>,
+    #[serde(default)]
+    pub commands: Vec<CommandRecord>,
+    /// Time spent on each build and download.
+    #[serde(default, skip_serializing_if = "Timings::is_empty")]
+    pub timings: Timings,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A host a run rebuilt, with where it was deployed and built.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HostRecord {
+    pub host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_host: Option<String>,
+}
+
+/// An external command a run executed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandRecord {
+    pub command: String,
+    /// `None` if the command could not be started or was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+}
+
+/// Start recording a run.
+pub fn begin(subcommand: &str, args: Vec<String>) {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let cwd = std::env::current_dir()
+        .ok()
+        .map(|dir| dir.display().to_string());
+
+    *CURRENT.lock().unwrap() = Some(Active {
+        entry: Entry {
+            started,
+            subcommand: subcommand.to_string(),
+            args,
+            cwd,
+            ..Entry::default()
+        },
+        start: Instant::now(),
+    });
+}
+
+/// Apply `f` to the current entry, if a run is being recorded.
+fn update(f: impl FnOnce(&mut Entry)) {
+    if let Some(active) = CURRENT.lock().unwrap().as_mut() {
+        f(&mut active.entry);
+    }
+}
+
+fn recording() -> bool {
+    CURRENT.lock().unwrap().is_some()
+}
+
+/// Note the flake the run is using, with its git revision when it is a local
+/// checkout.
+pub fn set_flake(flake: &FlakeRef) {
+    if !recording() {
+        return;
+    }
+
+    let git = flake.local_path().and_then(|dir| git_state(&dir));
+    update(|entry| {
+        entry.flake = Some(flake.to_string());
+        entry.flake_rev = git.as_ref().map(|g| g.rev.clone());
+        entry.flake_dirty = git.as_ref().map(|g| g.dirty);
+    });
+}
+
+/// Note a host the run rebuilt.
+pub fn add_host(host: HostRecord) {
+    update(|entry| entry.hosts.push(host));
+}
+
+/// Hosts the current run has noted so far.
+pub fn hosts() -> Vec<HostRecord> {
+    CURRENT
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|active| active.entry.hosts.clone())
+        .unwrap_or_default()
+}
+
+/// Note an external command the run executed.
+pub fn add_command(command: String, exit_code: Option<i32>, duration: Duration) {
+    update(|entry| {
+        entry.commands.push(CommandRecord {
+            command,
+            exit_code,
+            duration_ms: duration.as_millis() as u64,
+        })
+    });
+}
+
+/// Note the build timings collected during the run.
+pub fn set_timings(timings: &Timings) {
+    update(|entry| entry.timings = timings.clone());
+}
+
+/// Stop recording and append the run to the history file.
+///
+/// Does nothing if no run was being recorded.
+pub fn finish(result: &Result<()>) -> Result<()> {
+    let Some(Active { mut entry, start }) = CURRENT.lock().unwrap().take() else {
+        return Ok(());
+    };
+    let Some(path) = history_path() else {
+        return Ok(());
+    };
+
+    entry.duration_ms = start.elapsed().as_millis() as u64;
+    entry.success = result.is_ok();
+    entry.error = result.as_ref().err().map(|e| format!("{:#}", e));
+
+    append(&path, entry)
+}
+
+/// Path of the history file.
+pub fn history_path() -> Option<PathBuf> {
+    env::state_home().map(|dir| dir.join("bonk").join("history.jsonl"))
+}
+
+/// Read every entry in the history file, oldest first.
+///
+/// A missing file is empty history; unreadable lines are skipped.
+pub fn load(path: &Path) -> Result<Vec<Entry>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                tracing::warn!("Skipping unreadable history entry: {}", e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Append `entry` to the history file, giving it the next id.
+fn append(path: &Path, entry: Entry) -> Result<()> {
+    append_capped(path, entry, MAX_HISTORY_BYTES)
+}
+
+/// Append `entry` with the id after the last entry's. The file is locked
+/// meanwhile, so concurrent runs get distinct ids. Once it grows past
+/// `max_bytes`, the oldest entries are dropped to halve it.
+fn append_capped(path: &Path, mut entry: Entry, max_bytes: u64) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    let _lock = lock(path)?;
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let last = match last_line(&mut file)? {
+        Some(line) => match serde_json::from_str::<Entry>(&line) {
+            Ok(last) => Some(last),
+            // A damaged last line: fall back to the newest readable entry.
+            Err(_) => load(path)?.pop(),
+        },
+        None => None,
+    };
+    entry.id = last.map_or(1, |last| last.id + 1);
+
+    writeln!(file, "{}", serde_json::to_string(&entry)?)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+
+    if file.metadata()?.len() > max_bytes {
+        trim(path, max_bytes / 2)?;
+    }
+    Ok(())
+}
+
+/// Take an exclusive lock next to the history file, held until the returned
+/// file is dropped.
+fn lock(path: &Path) -> Result<File> {
+    let lock_path = path.with_extension("jsonl.lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("failed to open {}", lock_path.display()))?;
+    // SAFETY: flock only operates on the descriptor, which `file` owns.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("failed to lock {}", lock_path.display()));
+    }
+    Ok(file)
+}
+
+/// The last non-empty line of `file`, read backwards from the end so the
+/// cost doesn't grow with the history.
+fn last_line(file: &mut File) -> Result<Option<String>> {
+    const CHUNK: u64 = 8 * 1024;
+
+    let mut end = file.metadata()?.len();
+    let mut tail: Vec<u8> = Vec::new();
+    loop {
+        let trimmed = tail.iter().rposition(|&b| b != b'\n').map_or(0, |i| i + 1);
+        let start = tail[..trimmed].iter().rposition(|&b| b == b'\n');
+        if start.is_some() || end == 0 {
+            let line = &tail[start.map_or(0, |i| i + 1)..trimmed];
+            if line.is_empty() {
+                return Ok(None);
+            }
+            return Ok(Some(String::from_utf8_lossy(line).into_owned()));
+        }
+
+        let size = CHUNK.min(end);
+        end -= size;
+        let mut chunk = vec![0; size as usize];
+        file.seek(SeekFrom::Start(end))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend(tail);
+        tail = chunk;
+    }
+}
+
+/// Rewrite the history file with only the newest entries that fit in
+/// `keep_bytes` (always at least the newest one).
+fn trim(path: &Path, keep_bytes: u64) -> Result<()> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+
+    let mut kept = Vec::new();
+    let mut size = 0;
+    for line in contents
+        .lines()
+        .rev()
+        .filter(|line| !line.trim().is_empty())
+    {
+        size += line.len() as u64 + 1;
+        if size > keep_bytes && !kept.is_empty() {
+            break;
+        }
+        kept.push(line);
+    }
+    kept.reverse();
+
+    let tmp = path.with_extension("jsonl.tmp");
+    let mut out = kept.join("\n");
+    out.push('\n');
+    fs::write(&tmp, out).with_context(|| format!("failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to replace {}", path.display()))
+}
+
+/// Format a Unix timestamp as `YYYY-MM-DD HH:MM` (UTC).
+pub fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(format_timestamp(1_709_251_199), "2024-02-29 23:59");
+    }
+
+    #[test]
+    fn test_append_assigns_increasing_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state/history.jsonl");
+
+        append(&path, Entry::default()).unwrap();
+        append(&path, Entry::default()).unwrap();
+
+        let ids: Vec<u64> = load(&path).unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_append_reads_last_id_past_one_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let big = Entry {
+            args: vec!["x".repeat(20_000)],
+            ..Entry::default()
+        };
+
+        append(&path, big.clone()).unwrap();
+        append(&path, big).unwrap();
+        append(&path, Entry::default()).unwrap();
+
+        let ids: Vec<u64> = load(&path).unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_append_trims_oldest_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        for _ in 0..20 {
+            append_capped(&path, Entry::default(), 1_000).unwrap();
+        }
+
+        let ids: Vec<u64> = load(&path).unwrap().iter().map(|e| e.id).collect();
+        assert!(fs::metadata(&path).unwrap().len() <= 1_000);
+        assert_eq!(ids.last(), Some(&20));
+        assert!(ids.windows(2).all(|w| w[1] == w[0] + 1));
+        assert!(ids.len() < 20);
+    }
+
+    #[test]
+    fn test_load_skips_bad_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        fs::write(
+            &path,
+            "not json\n{\"id\":7,\"started\":0,\"duration_ms\":0,\"subcommand\":\"switch\",\
+             \"args\":[],\"success\":true}\n",
+        )
+        .unwrap();
+
+        let entries = load(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, 7);
+        assert!(load(&dir.path().join("missing")).unwrap().is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_run_is_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_var("XDG_STATE_HOME", dir.path());
+
+        begin(
+            "switch",
+            vec!["switch".to_string(), "-H".to_string(), "zebes".to_string()],
+        );
+        add_host(HostRecord {
+            host: "zebes".to_string(),
+            ..HostRecord::default()
+        });
+        add_command(
+            "nh os switch".to_string(),
+            Some(1),
+            Duration::from_millis(5),
+        );
+        finish(&Err(anyhow::anyhow!("command failed"))).unwrap();
+        std::env::remove_var("XDG_STATE_HOME");
+
+        let entries = load(&dir.path().join("bonk/history.jsonl")).unwrap();
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!(entry.subcommand, "switch");
+        assert_eq!(entry.hosts[0].host, "zebes");
+        assert_eq!(entry.commands[0].exit_code, Some(1));
+        assert!(!entry.success);
+        assert_eq!(entry.error.as_deref(), Some("command failed"));
+    }
+}
@@ -0,0 +1,331 @@
+//! User-defined hooks run before and after each bonk action.
+//!
+//! Hooks for an action come from two places, run in this order:
+//!
+//! 1. `pre`/`post` shell commands in a `[hooks.<action>]` config table
+//! 2. executables in `~/.config/bonk/hooks/<action>.d/`, with `pre` or
+//!    `post` as their only argument, in file name order
+//!
+//! The action is the subcommand with spaces as dashes, e.g. `switch` or
+//! `store-gc`. A failing pre-hook aborts the action. Post-hooks run whether
+//! or not the action succeeded, and a failing post-hook only warns.
+//!
+//! Hooks get the run's context in their environment:
+//!
+//! | Variable          | Value                                              |
+//! |-------------------|----------------------------------------------------|
+//! | `BONK_HOOK`       | `pre` or `post`                                    |
+//! | `BONK_ACTION`     | the action, e.g. `switch`                          |
+//! | `BONK_HOST`       | the host(s) acted on, comma-separated              |
+//! | `BONK_FLAKE`      | the flake, when one could be resolved              |
+//! | `BONK_STATUS`     | post only: `0`, `1` on failure, `130` if interrupted |
+//! | `BONK_ERROR`      | post only, on failure: the error message           |
+//! | `BONK_GENERATION` | post only, after `switch`, `boot` or `rollback`: the system generation now current |
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+
+use crate::config::Config;
+use crate::env;
+use crate::exec::{self, CommandRunner, Interrupted};
+use crate::generations;
+use crate::history;
+use crate::output;
+
+/// Actions that leave a new system generation current.
+const GENERATION_ACTIONS: &[&str] = &["switch", "boot", "rollback"];
+
+/// What the hooks are run around.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Context {
+    pub action: String,
+    pub host: Option<String>,
+    pub flake: Option<String>,
+}
+
+/// Run the pre-hooks for `ctx.action`, stopping at the first that fails.
+pub fn pre(ctx: &Context, config: &Config) -> Result<()> {
+    let env = environment(ctx, "pre");
+    for hook in hooks(ctx, config, "pre") {
+        let name = hook.name.clone();
+        hook.runner(&env)
+            .run()
+            .with_context(|| format!("pre-{} hook `{}` failed", ctx.action, name))?;
+    }
+    Ok(())
+}
+
+/// Run the post-hooks for `ctx.action`, telling them how it went.
+pub fn post(ctx: &Context, config: &Config, result: &Result<()>) {
+    let hooks = hooks(ctx, config, "post");
+    if hooks.is_empty() {
+        return;
+    }
+
+    // Prefer the hosts the action actually noted, e.g. a group's members.
+    let mut ctx = ctx.clone();
+    let recorded = history::hosts();
+    if !recorded.is_empty() {
+        let names: Vec<String> = recorded.iter().map(|h| h.host.clone()).collect();
+        ctx.host = Some(names.join(","));
+    }
+
+    let mut env = environment(&ctx, "post");
+    let status = match result {
+        Ok(()) => "0",
+        Err(e) if e.downcast_ref::<Interrupted>().is_some() => "130",
+        Err(_) => "1",
+    };
+    env.push(("BONK_STATUS".to_string(), status.to_string()));
+    if let Err(e) = result {
+        env.push(("BONK_ERROR".to_string(), format!("{:#}", e)));
+    }
+    if let Some(generation) = new_generation(&ctx.action, &recorded) {
+        env.push(("BONK_GENERATION".to_string(), generation.to_string()));
+    }
+
+    for hook in hooks {
+        let name = hook.name.clone();
+        if let Err(e) = hook.runner(&env).run() {
+            output::warn(&format!(
+                "post-{} hook `{}` failed: {:#}",
+                ctx.action, name, e
+            ));
+        }
+    }
+}
+
+/// The system generation current after an action that activates one, on
+/// the single host it deployed to.
+fn new_generation(action: &str, hosts: &[history::HostRecord]) -> Option<u64> {
+    if !GENERATION_ACTIONS.contains(&action) || exec::planning() || hosts.len() > 1 {
+        return None;
+    }
+    let target = hosts.first().and_then(|h| h.target.as_deref());
+    generations::current(target).ok().flatten()
+}
+
+fn environment(ctx: &Context, stage: &str) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("BONK_HOOK".to_string(), stage.to_string()),
+        ("BONK_ACTION".to_string(), ctx.action.clone()),
+    ];
+    if let Some(ref host) = ctx.host {
+        env.push(("BONK_HOST".to_string(), host.clone()));
+    }
+    if let Some(ref flake) = ctx.flake {
+        env.push(("BONK_FLAKE".to_string(), flake.clone()));
+    }
+    env
+}
+
+/// One hook to run.
+struct Hook {
+    /// The command or file shown in messages.
+    name: String,
+    runner: CommandRunner,
+}
+
+impl Hook {
+    fn runner(self, env: &[(String, String)]) -> CommandRunner {
+        env.iter()
+            .fold(self.runner, |runner, (key, value)| runner.env(key, value))
+    }
+}
+
+/// The `stage` hooks for `ctx.action`: configured commands, then executables
+/// in the hooks directory.
+fn hooks(ctx: &Context, config: &Config, stage: &str) -> Vec<Hook> {
+    let configured = config.settings().hooks.get(&ctx.action).and_then(|hooks| {
+        if stage == "pre" {
+            hooks.pre.clone()
+        } else {
+            hooks.post.clone()
+        }
+    });
+
+    let mut hooks: Vec<Hook> = configured
+        .into_iter()
+        .flatten()
+        .map(|command| Hook {
+            runner: CommandRunner::new("sh").args(["-c", &command]),
+            name: command,
+        })
+        .collect();
+
+    if let Some(dir) = hooks_dir(&ctx.action) {
+        hooks.extend(dir_hooks(&dir).into_iter().map(|path| Hook {
+            name: path.display().to_string(),
+            runner: CommandRunner::new(path.display().to_string()).arg(stage),
+        }));
+    }
+    hooks
+}
+
+/// `~/.config/bonk/hooks/<action>.d`.
+fn hooks_dir(action: &str) -> Option<PathBuf> {
+    env::config_home().map(|dir| dir.join("bonk").join("hooks").join(format!("{}.d", action)))
+}
+
+/// Executable files in `dir`, sorted by name. Hidden files are skipped, as
+/// are files without an execute bit, so a hook can be disabled with
+/// `chmod -x`.
+fn dir_hooks(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut hooks: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .map(|entry| entry.path())
+        .filter(|path| {
+            fs::metadata(path)
+                .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
+        })
+        .collect();
+    hooks.sort();
+    hooks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{with_executor, Recorder};
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    fn context() -> Context {
+        Context {
+            action: "switch".to_string(),
+            host: Some("zebes".to_string()),
+            flake: Some("/etc/nixos".to_string()),
+        }
+    }
+
+    const HOOKS: &str = r#"
+        [hooks.switch]
+        pre = ["git pull", "btrfs-snap"]
+        post = ["notify"]
+    "#;
+
+    #[test]
+    fn test_dir_hooks_only_executables() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, mode: u32| {
+            let path = dir.path().join(name);
+            fs::write(&path, "#!/bin/sh\n").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        };
+        write("20-notify", 0o755);
+        write("10-snapshot", 0o700);
+        write("30-disabled", 0o644);
+        write(".hidden", 0o755);
+        fs::create_dir(dir.path().join("40-dir")).unwrap();
+
+        let names: Vec<String> = dir_hooks(dir.path())
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, ["10-snapshot", "20-notify"]);
+        assert!(dir_hooks(&dir.path().join("missing")).is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_pre_hooks_get_context() {
+        let recorder = Arc::new(Recorder::default());
+        with_executor(recorder.clone(), || {
+            pre(&context(), &Config::parse_for_test(HOOKS))
+        })
+        .unwrap();
+
+        let commands = recorder.take();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].args, ["-c", "git pull"]);
+        assert_eq!(
+            commands[0].env,
+            [
+                ("BONK_HOOK".to_string(), "pre".to_string()),
+                ("BONK_ACTION".to_string(), "switch".to_string()),
+                ("BONK_HOST".to_string(), "zebes".to_string()),
+                ("BONK_FLAKE".to_string(), "/etc/nixos".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_failing_pre_hook_aborts() {
+        let recorder = Arc::new(Recorder::default());
+        recorder.fail("sh -c 'git pull'");
+
+        let err = with_executor(recorder.clone(), || {
+            pre(&context(), &Config::parse_for_test(HOOKS))
+        })
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("pre-switch hook `git pull` failed"));
+        assert_eq!(recorder.command_lines().len(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_post_hooks_get_failure_status() {
+        let recorder = Arc::new(Recorder::default());
+        let result = Err(anyhow::anyhow!("nh failed"));
+        let ctx = Context {
+            action: "boot".to_string(),
+            ..context()
+        };
+        let config = Config::parse_for_test(
+            r#"
+            [hooks.boot]
+            post = ["notify"]
+            "#,
+        );
+        with_executor(recorder.clone(), || post(&ctx, &config, &result));
+
+        let env = &recorder.take()[0].env;
+        assert!(env.contains(&("BONK_HOOK".to_string(), "post".to_string())));
+        assert!(env.contains(&("BONK_STATUS".to_string(), "1".to_string())));
+        assert!(env.contains(&("BONK_ERROR".to_string(), "nh failed".to_string())));
+    }
+
+    #[test]
+    #[serial]
+    fn test_post_hooks_run_after_interrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let config = Config::parse_for_test(&format!(
+            "[hooks.switch]\npost = [\"touch {}\"]\n",
+            marker.display()
+        ));
+
+        // Ctrl-C during the action must not fail the hooks that follow it.
+        crate::process::interrupt();
+        let hook = hooks(&context(), &config, "post").remove(0);
+        hook.runner(&environment(&context(), "post")).run().unwrap();
+        assert!(marker.exists());
+    }
+
+    #[test]
+    #[serial]
+    fn test_other_actions_have_no_hooks() {
+        let recorder = Arc::new(Recorder::default());
+        let ctx = Context {
+            action: "store-gc".to_string(),
+            ..context()
+        };
+        with_executor(recorder.clone(), || {
+            pre(&ctx, &Config::parse_for_test(HOOKS))
+        })
+        .unwrap();
+        assert!(recorder.command_lines().is_empty());
+    }
+}
@@ -2,21 +2,51 @@
 
 mod cli;
 mod commands;
+mod config;
+mod configurations;
+mod elevate;
 mod env;
 mod exec;
 mod flake;
+mod generations;
+mod history;
+mod hooks;
 mod host;
 mod output;
+mod process;
+mod progress;
 
 use anyhow::Result;
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches};
 use tracing_subscriber::EnvFilter;
 
-use cli::{Cli, Commands, StoreCommands};
+use cli::{Cli, Commands, PlanFormat, StoreCommands};
 use commands::os::OsAction;
+use config::{Config, Source};
 
 fn main() -> Result<()> {
-    let cli = Cli::parse();
+    // The completion scripts ask for `-H` values as the user types; answer
+    // with nothing but the names.
+    if env::get_completion_request().as_deref() == Some("hosts") {
+        if let Ok(config) = Config::load(None) {
+            commands::complete::hosts(&config);
+        }
+        return Ok(());
+    }
+
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    // clap reads -p and BONK_FLAKE_PATH into the same field; keep track of which.
+    let flake_source = match matches.value_source("flake_path") {
+        Some(ValueSource::EnvVariable) => Source::Env {
+            name: "BONK_FLAKE_PATH",
+        },
+        _ => Source::Flag {
+            name: "--flake-path",
+        },
+    };
 
     let default_level = if cli.verbose { "info" } else { "warn" };
     tracing_subscriber::fmt()
@@ -27,36 +57,230 @@ fn main() -> Result<()> {
         .without_time()
         .init();
 
+    // `config` and `doctor` are how a broken config file gets fixed, so they
+    // run with whatever could be loaded.
+    // The project config comes from the flake acted on.
+    let lenient = matches!(cli.command, Commands::Config { .. } | Commands::Doctor(_));
+    let config_flake = cli.config_flake();
+    let config = if lenient {
+        Config::load_lenient(config_flake.as_deref())
+    } else {
+        Config::load(config_flake.as_deref())?
+    };
+
     if cli.verbose {
         if let Some(ref path) = cli.flake_path {
             output::status(&format!("Using flake path: {}", path.display()));
         }
+        for layer in config.layers() {
+            output::status(&format!("Loaded config: {}", layer.path.display()));
+        }
+    }
+
+    // In plan mode commands are recorded rather than run. For JSON, keep
+    // stdout clean for the plan document by sending everything else to stderr.
+    exec::set_show_env(cli.show_env);
+    exec::set_timings(cli.timings);
+    exec::set_progress(
+        cli.progress()
+            .or(config.settings().progress)
+            .unwrap_or(false),
+    );
+    exec::set_retries(cli.retries.or(config.settings().retries).unwrap_or(0));
+    let timeout = cli.timeout.as_ref().or(config.settings().timeout.as_ref());
+    let timeout = timeout.map(|t| config::parse_duration(t)).transpose();
+    let elevation = config.elevation();
+    let (timeout, elevation) = if lenient {
+        (ignore_invalid(timeout), ignore_invalid(elevation))
+    } else {
+        (timeout?, elevation?)
+    };
+    exec::set_timeout(timeout);
+    if let Some(method) = cli.elevation.or(elevation) {
+        elevate::set(method);
+    }
+    let plan = cli.plan;
+    if plan.is_some() {
+        exec::enable_plan();
+    }
+    let redirect = match plan {
+        Some(PlanFormat::Json) => Some(output::stdout_to_stderr()?),
+        _ => None,
+    };
+
+    // Record the run unless nothing is really run, or it is `bonk history` itself.
+    let record = plan.is_none() && !matches!(cli.command, Commands::History(_));
+    if record {
+        history::begin(
+            &subcommand_name(&matches),
+            std::env::args().skip(1).collect(),
+        );
+    }
+
+    let hook_context = hook_context(&cli, &subcommand_name(&matches), &config);
+    let top = cli.timings;
+    let result =
+        hooks::pre(&hook_context, &config).and_then(|()| dispatch(cli, flake_source, &config));
+    hooks::post(&hook_context, &config, &result);
+
+    let timings = exec::take_timings();
+    if let Some(top) = top {
+        timings.print(top);
+    }
+
+    if record {
+        history::set_timings(&timings);
+        if let Err(e) = history::finish(&result) {
+            output::warn(&format!("Could not save run history: {:#}", e));
+        }
+    }
+
+    drop(redirect);
+    if let Some(format) = plan {
+        exec::print_plan(format)?;
+    }
+
+    // Exit like an interrupted shell command would, after saying which step
+    // was cut short.
+    if let Err(ref e) = result {
+        if e.downcast_ref::<exec::Interrupted>().is_some() {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(130);
+        }
+    }
+
+    result
+}
+
+/// A setting's value, or `None` with a warning if it is invalid.
+fn ignore_invalid<T>(value: Result<Option<T>>) -> Option<T> {
+    value.unwrap_or_else(|e| {
+        output::warn(&format!("Ignoring invalid setting: {:#}", e));
+        None
+    })
+}
+
+/// Full subcommand name, e.g. `switch` or `store gc`.
+fn subcommand_name(matches: &clap::ArgMatches) -> String {
+    let mut names = Vec::new();
+    let mut current = matches.subcommand();
+    while let Some((name, sub)) = current {
+        names.push(name);
+        current = sub.subcommand();
+    }
+    names.join(" ")
+}
+
+/// The action, host and flake passed to hooks.
+fn hook_context(cli: &Cli, subcommand: &str, config: &Config) -> hooks::Context {
+    let os = match &cli.command {
+        Commands::Switch(args)
+        | Commands::Boot(args)
+        | Commands::Test(args)
+        | Commands::BuildSystem(args)
+        | Commands::DryActivate(args) => Some(args),
+        Commands::BuildVm(args) => Some(&args.os),
+        _ => None,
+    };
+    let selected = match &cli.command {
+        Commands::Rollback(args) => args.host.clone(),
+        _ => os.and_then(|args| args.host.clone().or(args.group.clone())),
+    };
+
+    let flake = match cli.flake() {
+        Some(flake) => flake::FlakeRef::parse(flake).ok(),
+        None => flake::resolve_flake_path(cli.flake_path.as_deref(), config).ok(),
+    };
+    let flake_host = flake.as_ref().and_then(|f| f.attr.clone());
+    let flake = flake.map(
+        |f| match f.local_path().and_then(|p| p.canonicalize().ok()) {
+            Some(path) => path.display().to_string(),
+            None => f.to_string(),
+        },
+    );
+
+    hooks::Context {
+        action: subcommand.replace(' ', "-"),
+        host: selected
+            .or(flake_host)
+            .or_else(|| host::get_hostname().ok()),
+        flake,
     }
+}
 
+fn dispatch(cli: Cli, flake_source: Source, config: &Config) -> Result<()> {
     match cli.command {
         Commands::Switch(args) => {
             if cli.verbose {
                 output::status("Running switch command");
             }
-            commands::os::run(OsAction::Switch, &args, cli.flake_path.as_deref())?;
+            commands::os::run(OsAction::Switch, &args, cli.flake_path.as_deref(), config)?;
         }
         Commands::Boot(args) => {
             if cli.verbose {
                 output::status("Running boot command");
             }
-            commands::os::run(OsAction::Boot, &args, cli.flake_path.as_deref())?;
+            commands::os::run(OsAction::Boot, &args, cli.flake_path.as_deref(), config)?;
+        }
+        Commands::Test(args) => {
+            if cli.verbose {
+                output::status("Running test command");
+            }
+            commands::os::run(OsAction::Test, &args, cli.flake_path.as_deref(), config)?;
+        }
+        Commands::BuildSystem(args) => {
+            if cli.verbose {
+                output::status("Running build-system command");
+            }
+            commands::os::run(OsAction::Build, &args, cli.flake_path.as_deref(), config)?;
+        }
+        Commands::DryActivate(args) => {
+            if cli.verbose {
+                output::status("Running dry-activate command");
+            }
+            commands::os::run(
+                OsAction::DryActivate,
+                &args,
+                cli.flake_path.as_deref(),
+                config,
+            )?;
+        }
+        Commands::BuildVm(args) => {
+            if cli.verbose {
+                output::status("Running build-vm command");
+            }
+            let action = OsAction::BuildVm { run: !args.no_run };
+            commands::os::run(action, &args.os, cli.flake_path.as_deref(), config)?;
+        }
+        Commands::Rollback(args) => {
+            if cli.verbose {
+                output::status("Running rollback command");
+            }
+            commands::rollback::run(&args, config)?;
+        }
+        Commands::Specialisations(args) => {
+            if cli.verbose {
+                output::status("Running specialisations command");
+            }
+            commands::specialisations::run(&args, cli.flake_path.as_deref(), config)?;
+        }
+        Commands::Home { command } => {
+            if cli.verbose {
+                output::status("Running home command");
+            }
+            commands::home::run(&command, cli.flake_path.as_deref(), config)?;
         }
         Commands::Build(args) => {
             if cli.verbose {
                 output::status("Running build command");
             }
-            commands::build::run(&args, cli.flake_path.as_deref())?;
+            commands::build::run(&args, cli.flake_path.as_deref(), config)?;
         }
         Commands::Update(args) => {
             if cli.verbose {
                 output::status("Running update command");
             }
-            commands::update::run(&args, cli.flake_path.as_deref())?;
+            commands::update::run(&args, cli.flake_path.as_deref(), config)?;
         }
         Commands::Try(args) => {
             if cli.verbose {
@@ -69,7 +293,7 @@ fn main() -> Result<()> {
                 if cli.verbose {
                     output::status("Running store gc command");
                 }
-                commands::store::gc::run(&args)?;
+                commands::store::gc::run(&args, config)?;
             }
             StoreCommands::Optimize(args) => {
                 if cli.verbose {
@@ -87,7 +311,7 @@ fn main() -> Result<()> {
                 if cli.verbose {
                     output::status("Running store nuke command");
                 }
-                commands::store::nuke::run(&args, cli.flake_path.as_deref())?;
+                commands::store::nuke::run(&args, cli.flake_path.as_deref(), config)?;
             }
             StoreCommands::Info(args) => {
                 if cli.verbose {
@@ -96,6 +320,30 @@ fn main() -> Result<()> {
                 commands::store::info::run(&args)?;
             }
         },
+        Commands::Config { command } => {
+            if cli.verbose {
+                output::status("Running config command");
+            }
+            commands::config::run(&command, cli.flake_path.as_deref(), flake_source, config)?;
+        }
+        Commands::Doctor(args) => {
+            if cli.verbose {
+                output::status("Running doctor command");
+            }
+            commands::doctor::run(&args, cli.flake_path.as_deref(), config)?;
+        }
+        Commands::History(args) => {
+            if cli.verbose {
+                output::status("Running history command");
+            }
+            commands::history::run(&args)?;
+        }
+        Commands::Generations(args) => {
+            if cli.verbose {
+                output::status("Running generations command");
+            }
+            commands::generations::run(&args)?;
+        }
     }
 
     Ok(())
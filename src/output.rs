@@ -1,33 +1,139 @@
 //! Colored terminal output helpers.
 
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
 use owo_colors::OwoColorize;
 
+thread_local! {
+    /// Per-thread line prefix, used to tag output when deploying many hosts.
+    static PREFIX: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Set (or clear) the line prefix for output on the current thread.
+pub fn set_prefix(prefix: Option<String>) {
+    PREFIX.with(|p| *p.borrow_mut() = prefix);
+}
+
+/// Current thread's line prefix, if any.
+pub fn prefix() -> Option<String> {
+    PREFIX.with(|p| p.borrow().clone())
+}
+
+/// Print a line, tagged with the current thread's prefix.
+pub fn line(text: impl Display) {
+    match prefix() {
+        Some(prefix) => println!("{} {}", prefix.magenta(), text),
+        None => println!("{}", text),
+    }
+}
+
 pub fn info(message: &str) {
-    println!("{} {}", "::".blue().bold(), message);
+    line(format_args!("{} {}", "::".blue().bold(), message));
 }
 
 pub fn success(message: &str) {
-    println!("{} {}", "::".green().bold(), message);
+    line(format_args!("{} {}", "::".green().bold(), message));
 }
 
 pub fn warn(message: &str) {
-    println!("{} {}", "::".yellow().bold(), message);
+    line(format_args!("{} {}", "::".yellow().bold(), message));
 }
 
 pub fn show_cmd(cmd: &str) {
-    println!("{} {}", ">".dimmed(), cmd.dimmed());
+    line(format_args!("{} {}", ">".dimmed(), cmd.dimmed()));
 }
 
 pub fn status(message: &str) {
-    println!("{} {}", "->".cyan(), message);
+    line(format_args!("{} {}", "->".cyan(), message));
 }
 
 pub fn header(title: &str) {
-    println!("\n{}", title.bold());
-    println!("{}", "=".repeat(title.len()).dimmed());
+    if prefix().is_none() {
+        println!();
+    }
+    line(title.bold());
+    line("=".repeat(title.len()).dimmed());
 }
 
-#[allow(dead_code)]
 pub fn kv(key: &str, value: &str) {
-    println!("  {}: {}", key.dimmed(), value);
+    line(format_args!("  {}: {}", key.dimmed(), value));
+}
+
+/// Ask a yes/no question; anything but `y` (including end of input) is no.
+pub fn confirm(question: &str) -> io::Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().eq_ignore_ascii_case("y"))
+}
+
+/// Like [`confirm`], but give up after `timeout`; `None` means no answer
+/// came in time.
+pub fn confirm_within(question: &str, timeout: Duration) -> io::Result<Option<bool>> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+
+    // stdin can't be read with a timeout, so read it on a thread that is
+    // left behind if the answer doesn't come.
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = String::new();
+        let answer = io::stdin()
+            .read_line(&mut input)
+            .map(|_| input.trim().eq_ignore_ascii_case("y"));
+        let _ = tx.send(answer);
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(answer) => answer.map(Some),
+        Err(_) => {
+            println!();
+            Ok(None)
+        }
+    }
+}
+
+/// Points stdout at stderr until dropped.
+///
+/// Used while producing machine-readable output, so that everything printed
+/// along the way goes to stderr and stdout carries only the document.
+pub struct StdoutToStderr {
+    saved: RawFd,
+}
+
+/// Redirect stdout to stderr until the returned guard is dropped.
+pub fn stdout_to_stderr() -> io::Result<StdoutToStderr> {
+    io::stdout().flush()?;
+
+    // SAFETY: dup/dup2 only operate on the process's own standard descriptors.
+    let saved = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if saved < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(saved) };
+        return Err(err);
+    }
+
+    Ok(StdoutToStderr { saved })
+}
+
+impl Drop for StdoutToStderr {
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        // SAFETY: `saved` is a descriptor we duplicated and still own.
+        unsafe {
+            libc::dup2(self.saved, libc::STDOUT_FILENO);
+            libc::close(self.saved);
+        }
+    }
 }
@@ -0,0 +1,288 @@
+//! Child process control: process groups, signal forwarding and timeouts.
+//!
+//! Children that don't read from the terminal run in their own process
+//! group, so a timeout can stop everything they started (nh → nix → builders)
+//! and not just the direct child. Because they no longer receive the
+//! terminal's Ctrl-C, bonk's SIGINT/SIGTERM handler forwards the signal to
+//! every running group. Interactive children stay in bonk's group and get
+//! Ctrl-C from the terminal directly, unless they have a timeout: then they
+//! get their own group too, made the terminal's foreground group for as long
+//! as they run, so they can still prompt and be interrupted.
+//!
+//! Either way the handler notes that bonk was interrupted, so the step that
+//! was running can be reported instead of a bare exit code. Only children
+//! running when the signal arrived count as interrupted: steps bonk runs
+//! afterwards, like post-hooks, report their own result.
+
+use std::io;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{ChildStderr, ChildStdout, Command, ExitStatus};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a timed-out child gets to exit after SIGTERM before SIGKILL.
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// How often a child with a timeout is polled.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Running children signals are forwarded to: `-pgid` for a child in its own
+/// process group, `pid` for an interactive child in bonk's group, 0 if free.
+static CHILDREN: [AtomicI32; 64] = [const { AtomicI32::new(0) }; 64];
+
+/// How many times bonk has received SIGINT or SIGTERM.
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+static INSTALL: Once = Once::new();
+
+/// Act as if bonk received Ctrl-C.
+#[cfg(test)]
+pub fn interrupt() {
+    INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+}
+
+extern "C" fn on_signal(sig: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+
+    // Signals from the terminal already reach interactive children; only
+    // forward to them what was sent to bonk alone (e.g. `kill`).
+    let sent_by_user = !info.is_null() && unsafe { (*info).si_code } <= 0;
+
+    let mut running = false;
+    for slot in &CHILDREN {
+        let target = slot.load(Ordering::SeqCst);
+        if target == 0 {
+            continue;
+        }
+        running = true;
+        if target < 0 || sent_by_user {
+            unsafe { libc::kill(target, sig) };
+        }
+    }
+
+    // Nothing to wait for: behave as if no handler was installed.
+    if !running {
+        unsafe {
+            libc::signal(sig, libc::SIG_DFL);
+            libc::raise(sig);
+        }
+    }
+}
+
+fn install_handlers() {
+    INSTALL.call_once(|| unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_signal as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        for sig in [libc::SIGINT, libc::SIGTERM] {
+            libc::sigaction(sig, &action, std::ptr::null_mut());
+        }
+    });
+}
+
+/// Make `pgrp` the foreground process group of the terminal on stdin, if
+/// there is one. SIGTTOU is blocked meanwhile, as a background process
+/// taking the terminal back would otherwise be stopped by it.
+fn set_foreground(pgrp: libc::pid_t) {
+    unsafe {
+        let mut block: libc::sigset_t = std::mem::zeroed();
+        let mut old: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut block);
+        libc::sigaddset(&mut block, libc::SIGTTOU);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &block, &mut old);
+        libc::tcsetpgrp(libc::STDIN_FILENO, pgrp);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &old, std::ptr::null_mut());
+    }
+}
+
+/// A running child process, unregistered from signal forwarding on drop.
+pub struct Child {
+    inner: std::process::Child,
+    slot: Option<usize>,
+    /// Whether the child leads its own process group.
+    group: bool,
+    /// Whether the child's group holds the terminal's foreground.
+    foreground: bool,
+    /// [`INTERRUPTS`] when the child was spawned.
+    interrupts: usize,
+}
+
+/// Spawn `command`. Unless `interactive`, or if it is `timed`, it gets its
+/// own process group.
+pub fn spawn(command: &mut Command, interactive: bool, timed: bool) -> io::Result<Child> {
+    install_handlers();
+    let interrupts = INTERRUPTS.load(Ordering::SeqCst);
+    let group = !interactive || timed;
+    let foreground = interactive && timed;
+    if group {
+        command.process_group(0);
+    }
+    if foreground {
+        // Runs after the child has moved into its own group.
+        unsafe {
+            command.pre_exec(|| {
+                set_foreground(libc::getpgrp());
+                Ok(())
+            });
+        }
+    }
+
+    let inner = command.spawn()?;
+    let pid = inner.id() as i32;
+    let target = if group { -pid } else { pid };
+    let slot = CHILDREN.iter().position(|slot| {
+        slot.compare_exchange(0, target, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    });
+
+    Ok(Child {
+        inner,
+        slot,
+        group,
+        foreground,
+        interrupts,
+    })
+}
+
+impl Child {
+    /// Whether bonk was interrupted (Ctrl-C or SIGTERM) since the child was
+    /// spawned.
+    pub fn interrupted(&self) -> bool {
+        INTERRUPTS.load(Ordering::SeqCst) != self.interrupts
+    }
+
+    pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+        self.inner.stdout.take()
+    }
+
+    pub fn take_stderr(&mut self) -> Option<ChildStderr> {
+        self.inner.stderr.take()
+    }
+
+    /// Wait for the child to exit. After `timeout`, it is sent SIGTERM, then
+    /// SIGKILL if it has not exited shortly after, and `None` is returned.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<Option<ExitStatus>> {
+        let status = self.wait_for(timeout);
+        if self.foreground {
+            set_foreground(unsafe { libc::getpgrp() });
+            // The terminal's Ctrl-C went to the child alone; count it as
+            // bonk's, as it would have been without the timeout.
+            if let Ok(Some(status)) = &status {
+                if status.signal() == Some(libc::SIGINT) || status.code() == Some(130) {
+                    INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+        status
+    }
+
+    fn wait_for(&mut self, timeout: Option<Duration>) -> io::Result<Option<ExitStatus>> {
+        let Some(timeout) = timeout else {
+            return self.inner.wait().map(Some);
+        };
+
+        if self.wait_until(Instant::now() + timeout)?.is_some() {
+            return self.inner.wait().map(Some);
+        }
+
+        self.signal(libc::SIGTERM);
+        if self.wait_until(Instant::now() + KILL_GRACE)?.is_none() {
+            self.signal(libc::SIGKILL);
+        }
+        self.inner.wait()?;
+        Ok(None)
+    }
+
+    fn wait_until(&mut self, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+        loop {
+            if let Some(status) = self.inner.try_wait()? {
+                return Ok(Some(status));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn signal(&self, sig: libc::c_int) {
+        let pid = self.inner.id() as i32;
+        let target = if self.group { -pid } else { pid };
+        unsafe { libc::kill(target, sig) };
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            CHILDREN[slot].store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    fn test_wait_times_out_and_kills_group() {
+        let mut child = spawn(Command::new("sh").args(["-c", "sleep 10"]), false, false).unwrap();
+        let start = Instant::now();
+        assert!(child
+            .wait(Some(Duration::from_millis(100)))
+            .unwrap()
+            .is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    #[serial]
+    fn test_interrupt_before_spawn_is_not_the_childs() {
+        let mut before = spawn(Command::new("sh").args(["-c", "sleep 0.2"]), false, false).unwrap();
+        interrupt();
+        let mut after = spawn(&mut Command::new("true"), false, false).unwrap();
+        after.wait(None).unwrap();
+        before.wait(None).unwrap();
+        assert!(before.interrupted());
+        assert!(!after.interrupted());
+    }
+
+    #[test]
+    fn test_timeout_kills_interactive_grandchildren() {
+        let dir = tempfile::tempdir().unwrap();
+        let pidfile = dir.path().join("pid");
+        let script = format!("sleep 30 & echo $! > {}; wait", pidfile.display());
+        let mut child = spawn(Command::new("sh").args(["-c", &script]), true, true).unwrap();
+        assert!(child
+            .wait(Some(Duration::from_millis(300)))
+            .unwrap()
+            .is_none());
+
+        let pid: i32 = std::fs::read_to_string(&pidfile)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        // The orphaned sleep may linger briefly as a zombie of init.
+        let gone = || {
+            std::fs::read_to_string(format!("/proc/{}/stat", pid))
+                .map_or(true, |stat| stat.contains(") Z "))
+        };
+        let start = Instant::now();
+        while !gone() && start.elapsed() < Duration::from_secs(2) {
+            thread::sleep(POLL_INTERVAL);
+        }
+        assert!(gone());
+    }
+
+    #[test]
+    fn test_wait_returns_status() {
+        let mut child = spawn(Command::new("sh").args(["-c", "exit 3"]), false, false).unwrap();
+        let status = child.wait(Some(Duration::from_secs(10))).unwrap().unwrap();
+        assert_eq!(status.code(), Some(3));
+    }
+}
@@ -0,0 +1,627 @@
+//! Progress display for nix's `--log-format internal-json` output.
+//!
+//! With `--log-format internal-json`, nix writes one `@nix {...}` JSON event
+//! per line to stderr instead of its own progress bar. [`State`] folds those
+//! events into counts of builds and downloads, and [`Display`] redraws a
+//! single status line from them. Log messages are printed above it, and any
+//! line that is not an event is passed through unchanged. When the status
+//! line can't be drawn (only `--timings` is on, or stderr is not a terminal),
+//! build logs are printed too, as `name> line` like `nix -L`.
+//!
+//! The same events give the start and stop of every build and substitution,
+//! collected as [`Timings`] for the `--timings` report and the run history.
+
+use std::collections::HashMap;
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+
+use crate::output;
+
+/// Minimum time between redraws of the status line.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Messages above this level (`info`) are too chatty to print.
+const MAX_MESSAGE_LEVEL: u64 = 3;
+
+// Activity and result types from nix's logging.hh.
+const ACT_FILE_TRANSFER: u64 = 101;
+const ACT_COPY_PATHS: u64 = 103;
+const ACT_BUILDS: u64 = 104;
+const ACT_BUILD: u64 = 105;
+const ACT_SUBSTITUTE: u64 = 108;
+const RES_BUILD_LOG_LINE: u64 = 101;
+const RES_PROGRESS: u64 = 105;
+
+/// Whether the progress display can be drawn (stderr is a terminal).
+pub fn supported() -> bool {
+    std::io::stderr().is_terminal()
+}
+
+/// One `@nix` event. Fields not needed for the display are ignored.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum Event {
+    Start {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        #[serde(default)]
+        text: String,
+        #[serde(default)]
+        fields: Vec<serde_json::Value>,
+    },
+    Stop {
+        id: u64,
+    },
+    Result {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        #[serde(default)]
+        fields: Vec<serde_json::Value>,
+    },
+    Msg {
+        level: u64,
+        msg: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug)]
+struct Activity {
+    kind: u64,
+    /// Derivation or file name, for builds and transfers.
+    name: String,
+    /// Bytes done, for file transfers.
+    done: u64,
+    started: Instant,
+}
+
+/// Counts folded from the event stream.
+#[derive(Debug, Default)]
+pub struct State {
+    activities: HashMap<u64, Activity>,
+    builds_done: u64,
+    builds_expected: u64,
+    paths_done: u64,
+    paths_expected: u64,
+    /// Bytes from finished transfers; running ones are in `activities`.
+    bytes_finished: u64,
+    last_log: Option<String>,
+    /// Return build log lines for printing, as `name> line` like `nix -L`,
+    /// instead of only showing the latest in the status line.
+    print_build_logs: bool,
+    timings: Timings,
+}
+
+impl State {
+    /// Process one line of nix's stderr.
+    ///
+    /// Returns text to print above the status line: log messages, and lines
+    /// that are not `@nix` events.
+    pub fn handle(&mut self, line: &str) -> Option<String> {
+        let Some(json) = line.strip_prefix("@nix ") else {
+            return Some(line.to_string());
+        };
+        let Ok(event) = serde_json::from_str::<Event>(json) else {
+            return Some(line.to_string());
+        };
+
+        match event {
+            Event::Start {
+                id,
+                kind,
+                text,
+                fields,
+            } => {
+                let name = match kind {
+                    ACT_BUILD | ACT_SUBSTITUTE => fields
+                        .first()
+                        .and_then(|f| f.as_str())
+                        .map_or(text, derivation_name),
+                    _ => text,
+                };
+                self.activities.insert(
+                    id,
+                    Activity {
+                        kind,
+                        name,
+                        done: 0,
+                        started: Instant::now(),
+                    },
+                );
+            }
+            Event::Stop { id } => {
+                if let Some(activity) = self.activities.remove(&id) {
+                    let timed = || Timed {
+                        name: activity.name.clone(),
+                        duration_ms: activity.started.elapsed().as_millis() as u64,
+                    };
+                    match activity.kind {
+                        ACT_FILE_TRANSFER => self.bytes_finished += activity.done,
+                        ACT_BUILD => self.timings.builds.push(timed()),
+                        ACT_SUBSTITUTE => self.timings.downloads.push(timed()),
+                        _ => {}
+                    }
+                }
+            }
+            Event::Result { id, kind, fields } => return self.result(id, kind, &fields),
+            Event::Msg { level, msg } if level <= MAX_MESSAGE_LEVEL => return Some(msg),
+            Event::Msg { .. } | Event::Other => {}
+        }
+
+        None
+    }
+
+    fn result(&mut self, id: u64, kind: u64, fields: &[serde_json::Value]) -> Option<String> {
+        let activity = self.activities.get_mut(&id)?;
+        let number = |i: usize| fields.get(i).and_then(serde_json::Value::as_u64);
+
+        match (kind, activity.kind) {
+            (RES_PROGRESS, ACT_BUILDS) => {
+                self.builds_done = number(0).unwrap_or(0);
+                self.builds_expected = number(1).unwrap_or(0);
+            }
+            (RES_PROGRESS, ACT_COPY_PATHS) => {
+                self.paths_done = number(0).unwrap_or(0);
+                self.paths_expected = number(1).unwrap_or(0);
+            }
+            (RES_PROGRESS, ACT_FILE_TRANSFER) => {
+                activity.done = number(0).unwrap_or(activity.done);
+            }
+            (RES_BUILD_LOG_LINE, _) => {
+                let line = fields.first().and_then(|f| f.as_str()).unwrap_or_default();
+                if self.print_build_logs {
+                    return Some(format!("{}> {}", activity.name, line));
+                }
+                self.last_log = Some(line.trim().to_string()).filter(|line| !line.is_empty());
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn running(&self, kind: u64) -> impl Iterator<Item = &Activity> {
+        self.activities.values().filter(move |a| a.kind == kind)
+    }
+
+    /// Bytes downloaded so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes_finished + self.running(ACT_FILE_TRANSFER).map(|a| a.done).sum::<u64>()
+    }
+
+    /// The status line, given how long the command has been running.
+    pub fn status_line(&self, elapsed: Duration) -> String {
+        let mut parts = Vec::new();
+
+        let running = self.running(ACT_BUILD).count() as u64;
+        if self.builds_expected > 0 || running > 0 {
+            let queued = self
+                .builds_expected
+                .saturating_sub(self.builds_done + running);
+            parts.push(format!(
+                "builds {}/{} ({} running, {} queued)",
+                self.builds_done,
+                self.builds_expected.max(running),
+                running,
+                queued
+            ));
+        }
+
+        let bytes = self.bytes();
+        if self.paths_expected > 0 || bytes > 0 {
+            let rate = bytes as f64 / elapsed.as_secs_f64().max(1.0);
+            let transfer = format!("{} @ {}/s", format_bytes(bytes as f64), format_bytes(rate));
+            parts.push(if self.paths_expected > 0 {
+                format!(
+                    "downloads {}/{} paths, {}",
+                    self.paths_done, self.paths_expected, transfer
+                )
+            } else {
+                format!("downloads {}", transfer)
+            });
+        }
+
+        // The most recently started build is the one worth naming.
+        if let Some(build) = self.running(ACT_BUILD).max_by_key(|a| a.started) {
+            let mut current = format!("building {}", build.name);
+            if let Some(ref log) = self.last_log {
+                current.push_str(&format!(": {}", log));
+            }
+            parts.push(current);
+        }
+
+        parts.join(" | ")
+    }
+}
+
+/// A build or substitution and how long it took.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Timed {
+    pub name: String,
+    pub duration_ms: u64,
+}
+
+/// Time spent on each derivation during a run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Timings {
+    /// Derivations built (locally or on a build host).
+    #[serde(default)]
+    pub builds: Vec<Timed>,
+    /// Store paths fetched from a binary cache.
+    #[serde(default)]
+    pub downloads: Vec<Timed>,
+}
+
+impl Timings {
+    pub fn is_empty(&self) -> bool {
+        self.builds.is_empty() && self.downloads.is_empty()
+    }
+
+    /// Add the timings of another command in the same run.
+    pub fn extend(&mut self, other: Timings) {
+        self.builds.extend(other.builds);
+        self.downloads.extend(other.downloads);
+    }
+
+    /// The `top` slowest builds and downloads, slowest first.
+    pub fn slowest(&self, top: usize) -> Vec<(&'static str, &Timed)> {
+        let mut all: Vec<(&'static str, &Timed)> = self
+            .builds
+            .iter()
+            .map(|t| ("built", t))
+            .chain(self.downloads.iter().map(|t| ("cached", t)))
+            .collect();
+        all.sort_by_key(|(_, t)| std::cmp::Reverse(t.duration_ms));
+        all.truncate(top);
+        all
+    }
+
+    /// Print the report: totals, then the `top` slowest derivations.
+    pub fn print(&self, top: usize) {
+        let total = |items: &[Timed]| items.iter().map(|t| t.duration_ms).sum::<u64>();
+
+        output::header("Build Timings");
+        if self.is_empty() {
+            output::status("Nothing was built or downloaded");
+            return;
+        }
+
+        output::kv(
+            "built",
+            &format!(
+                "{} derivations, {} total",
+                self.builds.len(),
+                format_ms(total(&self.builds))
+            ),
+        );
+        output::kv(
+            "from cache",
+            &format!(
+                "{} paths, {} total",
+                self.downloads.len(),
+                format_ms(total(&self.downloads))
+            ),
+        );
+
+        println!();
+        for (kind, timed) in self.slowest(top) {
+            println!(
+                "  {:>7}  {:<6}  {}",
+                format_ms(timed.duration_ms),
+                kind.dimmed(),
+                timed.name
+            );
+        }
+    }
+}
+
+/// Format milliseconds as `1m02s`, `12s` or `0.4s`.
+pub fn format_ms(ms: u64) -> String {
+    let secs = ms / 1_000;
+    if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else if secs >= 10 {
+        format!("{}s", secs)
+    } else {
+        format!("{:.1}s", ms as f64 / 1_000.0)
+    }
+}
+
+/// Name of a derivation from its store path, e.g. `hello-2.12`.
+fn derivation_name(path: &str) -> String {
+    let base = path.rsplit('/').next().unwrap_or(path);
+    let base = base.strip_suffix(".drv").unwrap_or(base);
+    match base.split_once('-') {
+        Some((hash, name)) if hash.len() == 32 => name.to_string(),
+        _ => base.to_string(),
+    }
+}
+
+/// Format a byte count (or rate) as `12.3 MiB`.
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", value as u64, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Draws [`State`] as a status line on stderr.
+pub struct Display {
+    state: State,
+    /// Draw the status line; otherwise print messages and build logs as
+    /// plain text.
+    enabled: bool,
+    start: Instant,
+    last_draw: Option<Instant>,
+    /// Whether a status line is currently on screen.
+    drawn: bool,
+}
+
+impl Display {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            state: State {
+                print_build_logs: !enabled,
+                ..State::default()
+            },
+            enabled,
+            start: Instant::now(),
+            last_draw: None,
+            drawn: false,
+        }
+    }
+
+    /// Feed one line of nix's stderr.
+    pub fn line(&mut self, line: &str) {
+        match self.state.handle(line) {
+            Some(text) => {
+                self.clear();
+                eprintln!("{}", text);
+                self.draw();
+            }
+            None => {
+                let due = self
+                    .last_draw
+                    .is_none_or(|at| at.elapsed() >= REDRAW_INTERVAL);
+                if due {
+                    self.draw();
+                }
+            }
+        }
+    }
+
+    /// Remove the status line once the command is done, and return the
+    /// timings collected.
+    pub fn finish(mut self) -> Timings {
+        self.clear();
+        self.state.timings
+    }
+
+    fn draw(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        let status = self.state.status_line(self.start.elapsed());
+        let mut stderr = std::io::stderr().lock();
+        if status.is_empty() {
+            if self.drawn {
+                let _ = write!(stderr, "\r\x1b[K");
+            }
+            self.drawn = false;
+        } else {
+            let width = terminal_width().saturating_sub(4);
+            let status: String = status.chars().take(width).collect();
+            let _ = write!(stderr, "\r\x1b[K{} {}", "::".blue().bold(), status.dimmed());
+            self.drawn = true;
+        }
+        let _ = stderr.flush();
+        self.last_draw = Some(Instant::now());
+    }
+
+    fn clear(&mut self) {
+        if self.drawn {
+            let mut stderr = std::io::stderr().lock();
+            let _ = write!(stderr, "\r\x1b[K");
+            let _ = stderr.flush();
+            self.drawn = false;
+        }
+    }
+}
+
+/// Width of the terminal on stderr, or 80 if unknown.
+fn terminal_width() -> usize {
+    // SAFETY: TIOCGWINSZ only writes into the winsize struct we pass.
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    if ok && size.ws_col > 0 {
+        usize::from(size.ws_col)
+    } else {
+        80
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DRV: &str = "/nix/store/0123456789abcdfghijklmnpqrsvwxyz-hello-2.12.drv";
+
+    fn feed(state: &mut State, lines: &[&str]) -> Vec<String> {
+        lines.iter().filter_map(|l| state.handle(l)).collect()
+    }
+
+    #[test]
+    fn test_builds_running_and_queued() {
+        let mut state = State::default();
+        let printed = feed(
+            &mut state,
+            &[
+                r#"@nix {"action":"start","id":1,"level":0,"type":104,"text":"","parent":0,"fields":[]}"#,
+                r#"@nix {"action":"result","id":1,"type":105,"fields":[1,5,0,0]}"#,
+                &format!(
+                    r#"@nix {{"action":"start","id":2,"level":3,"type":105,"text":"building","parent":1,"fields":["{}","",1,1]}}"#,
+                    DRV
+                ),
+                r#"@nix {"action":"result","id":2,"type":101,"fields":["checking for gcc... yes"]}"#,
+            ],
+        );
+
+        assert!(printed.is_empty());
+        assert_eq!(
+            state.status_line(Duration::from_secs(1)),
+            "builds 1/5 (1 running, 3 queued) | building hello-2.12: checking for gcc... yes"
+        );
+    }
+
+    #[test]
+    fn test_download_bytes_and_rate() {
+        let mut state = State::default();
+        feed(
+            &mut state,
+            &[
+                r#"@nix {"action":"start","id":1,"level":0,"type":103,"text":"","fields":[]}"#,
+                r#"@nix {"action":"result","id":1,"type":105,"fields":[2,10,0,0]}"#,
+                r#"@nix {"action":"start","id":7,"level":4,"type":101,"text":"downloading 'https://cache.nixos.org/nar/x.nar.xz'","fields":[]}"#,
+                r#"@nix {"action":"result","id":7,"type":105,"fields":[1048576,2097152,0,0]}"#,
+                r#"@nix {"action":"stop","id":7}"#,
+                r#"@nix {"action":"start","id":8,"level":4,"type":101,"text":"","fields":[]}"#,
+                r#"@nix {"action":"result","id":8,"type":105,"fields":[1048576,2097152,0,0]}"#,
+            ],
+        );
+
+        assert_eq!(state.bytes(), 2 * 1_048_576);
+        assert_eq!(
+            state.status_line(Duration::from_secs(2)),
+            "downloads 2/10 paths, 2.0 MiB @ 1.0 MiB/s"
+        );
+    }
+
+    #[test]
+    fn test_messages_and_raw_lines_are_printed() {
+        let mut state = State::default();
+        let printed = feed(
+            &mut state,
+            &[
+                r#"@nix {"action":"msg","level":0,"msg":"error: builder failed"}"#,
+                r#"@nix {"action":"msg","level":5,"msg":"evaluating file"}"#,
+                "warning: Git tree is dirty",
+            ],
+        );
+        assert_eq!(
+            printed,
+            vec!["error: builder failed", "warning: Git tree is dirty"]
+        );
+    }
+
+    #[test]
+    fn test_build_logs_printed_without_display() {
+        let lines = [
+            r#"@nix {"action":"start","id":7,"type":105,"text":"building","fields":["/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.12.drv"]}"#,
+            r#"@nix {"action":"result","id":7,"type":101,"fields":["checking for gcc... gcc"]}"#,
+        ];
+
+        let mut drawn = State::default();
+        assert!(feed(&mut drawn, &lines).is_empty());
+        assert_eq!(drawn.last_log.as_deref(), Some("checking for gcc... gcc"));
+
+        let mut plain = State {
+            print_build_logs: true,
+            ..State::default()
+        };
+        assert_eq!(
+            feed(&mut plain, &lines),
+            ["hello-2.12> checking for gcc... gcc"]
+        );
+    }
+
+    #[test]
+    fn test_stop_removes_build() {
+        let mut state = State::default();
+        feed(
+            &mut state,
+            &[
+                &format!(
+                    r#"@nix {{"action":"start","id":2,"type":105,"text":"","fields":["{}"]}}"#,
+                    DRV
+                ),
+                r#"@nix {"action":"stop","id":2}"#,
+            ],
+        );
+        assert_eq!(state.status_line(Duration::from_secs(1)), "");
+    }
+
+    #[test]
+    fn test_timings_collected_on_stop() {
+        let mut state = State::default();
+        feed(
+            &mut state,
+            &[
+                &format!(
+                    r#"@nix {{"action":"start","id":2,"type":105,"text":"","fields":["{}"]}}"#,
+                    DRV
+                ),
+                r#"@nix {"action":"start","id":3,"type":108,"text":"","fields":["/nix/store/0123456789abcdfghijklmnpqrsvwxyz-glibc-2.39","https://cache.nixos.org"]}"#,
+                r#"@nix {"action":"stop","id":2}"#,
+                r#"@nix {"action":"stop","id":3}"#,
+            ],
+        );
+
+        let names = |items: &[Timed]| items.iter().map(|t| t.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&state.timings.builds), vec!["hello-2.12"]);
+        assert_eq!(names(&state.timings.downloads), vec!["glibc-2.39"]);
+    }
+
+    #[test]
+    fn test_slowest_across_kinds() {
+        let timed = |name: &str, ms| Timed {
+            name: name.to_string(),
+            duration_ms: ms,
+        };
+        let timings = Timings {
+            builds: vec![timed("a", 10), timed("b", 300)],
+            downloads: vec![timed("c", 200)],
+        };
+
+        let slowest: Vec<(&str, &str)> = timings
+            .slowest(2)
+            .into_iter()
+            .map(|(kind, t)| (kind, t.name.as_str()))
+            .collect();
+        assert_eq!(slowest, vec![("built", "b"), ("cached", "c")]);
+    }
+
+    #[test]
+    fn test_derivation_name() {
+        assert_eq!(derivation_name(DRV), "hello-2.12");
+        assert_eq!(derivation_name("hello"), "hello");
+    }
+
+    #[test]
+    fn test_format_ms() {
+        assert_eq!(format_ms(400), "0.4s");
+        assert_eq!(format_ms(12_300), "12s");
+        assert_eq!(format_ms(62_000), "1m02s");
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512.0), "512 B");
+        assert_eq!(format_bytes(1536.0), "1.5 KiB");
+        assert_eq!(format_bytes((120u64 << 20) as f64), "120.0 MiB");
+        assert_eq!(format_bytes((7u64 << 29) as f64), "3.5 GiB");
+    }
+}